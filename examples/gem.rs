@@ -1,20 +1,22 @@
+#![allow(dead_code)]
+
 use std::path::Path;
 
 use futures::stream::StreamExt;
 use gem_rs::api::Models;
-use gem_rs::client::{GemSession, GemSessionBuilder};
+use gem_rs::client::GemSession;
 use gem_rs::init_log;
 use gem_rs::types::{Blob, Context, FileManager, HarmBlockThreshold, Role, Settings};
 
 #[tokio::main]
 async fn main() {
     init_log();
+    dotenv::dotenv().ok();
     test_clear_files().await;
 }
 
-//TODO: Something with the API cause the cached files in cloud to change uri every time they are deleted
 async fn test_clear_files() {
-    let mut file_manager = FileManager::new();
+    let file_manager = FileManager::new().unwrap();
     file_manager.fetch_list().await.unwrap();
     file_manager.clear_files().await;
 }
@@ -35,7 +37,7 @@ async fn test_blob() {
 
     let blob = Blob::new(
         "image/png",
-        include_bytes!("C:/Users/0xhades/Downloads/x.png"),
+        &std::fs::read("C:/Users/0xhades/Downloads/x.png").unwrap(),
     );
 
     let response = session.send_blob(blob, Role::User, &settings).await;
@@ -66,7 +68,7 @@ async fn test_blob_stream() {
 
     let blob = Blob::new(
         "image/png",
-        include_bytes!("C:/Users/0xhades/Downloads/x.png"),
+        &std::fs::read("C:/Users/0xhades/Downloads/x.png").unwrap(),
     );
 
     let stream_result = session.send_blob_stream(blob, Role::User, &settings).await;
@@ -78,7 +80,7 @@ async fn test_blob_stream() {
                     Ok(response) => {
                         println!(
                             "{}",
-                            response.get_results().get(0).unwrap_or(&"".to_string())
+                            response.get_results().first().unwrap_or(&"".to_string())
                         );
                     }
                     Err(e) => {
@@ -107,7 +109,7 @@ async fn test_file() {
     settings.set_max_output_tokens(8192);
     settings.set_temperature(1.5);
 
-    let mut file_manager = FileManager::new();
+    let file_manager = FileManager::new().unwrap();
     file_manager.fetch_list().await.unwrap();
     let data = file_manager
         .add_file(Path::new("C:/Users/0xhades/Downloads/9.pdf"))
@@ -140,7 +142,7 @@ async fn test_file_stream() {
     settings.set_max_output_tokens(8192);
     settings.set_temperature(1.5);
 
-    let mut file_manager = FileManager::new();
+    let file_manager = FileManager::new().unwrap();
     file_manager.fetch_list().await.unwrap();
     let data = file_manager
         .add_file(Path::new("C:/Users/0xhades/Downloads/9.pdf"))
@@ -156,7 +158,7 @@ async fn test_file_stream() {
                     Ok(response) => {
                         println!(
                             "{}",
-                            response.get_results().get(0).unwrap_or(&"".to_string())
+                            response.get_results().first().unwrap_or(&"".to_string())
                         );
                     }
                     Err(e) => {
@@ -194,7 +196,7 @@ async fn test_stream() {
                     Ok(response) => {
                         println!(
                             "{}",
-                            response.get_results().get(0).unwrap_or(&"".to_string())
+                            response.get_results().first().unwrap_or(&"".to_string())
                         );
                     }
                     Err(e) => {
@@ -4,8 +4,20 @@
 /// providing support for streaming. This library is designed to facilitate
 /// interaction with the Gemini API, making it easier to integrate its
 /// functionalities into Rust projects.
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
 use serde::{Deserialize, Serialize};
 
+use crate::errors::GemError;
+use crate::types::Modality;
+
+/// Environment variable read by `GemSessionBuilder::new` to select the default model.
+pub const MODEL_ENV_VAR: &str = "GEMINI_MODEL";
+
+/// Base URL of the Gemini API, used for the files API.
+pub const API_BASE_URL: &str = "https://generativelanguage.googleapis.com/";
+
 /// Base URL for generating content using the Gemini API.
 pub const GENERATE_CONTENT: &str = "https://generativelanguage.googleapis.com/v1beta/models/";
 
@@ -13,11 +25,16 @@ pub const GENERATE_CONTENT: &str = "https://generativelanguage.googleapis.com/v1
 pub const STREAM_GENERATE_CONTENT: &str =
     "https://generativelanguage.googleapis.com/v1beta/models/";
 
+/// Maximum size in bytes of the inline (base64 encoded) data in a single request.
+///
+/// Larger payloads must be uploaded with the files API and referenced by URI.
+pub const MAX_INLINE_DATA_SIZE: usize = 20 * 1024 * 1024;
+
 /// Enum representing different Gemini API models.
 ///
 /// This enum includes various versions of Gemini models, including experimental
 /// and stable versions. The default model is set to `Gemini15Pro`.
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
 pub enum Models {
     /// Experimental Gemini 1.5 Pro model (version 0827)
     #[serde(rename = "gemini-1.5-pro-exp-0827")]
@@ -84,15 +101,131 @@ pub enum Models {
     #[serde(rename = "gemma-2-27b-it")]
     Gemma2_27bIt,
 
+    /// Alias that always points to the latest Gemini Flash model
+    #[serde(rename = "gemini-flash-latest")]
+    GeminiFlashLatest,
+
+    /// Alias that always points to the latest Gemini Flash Lite model
+    #[serde(rename = "gemini-flash-lite-latest")]
+    GeminiFlashLiteLatest,
+
+    /// Alias that always points to the latest Gemini Pro model
+    #[serde(rename = "gemini-pro-latest")]
+    GeminiProLatest,
+
+    /// Text Embedding 004 model
+    #[serde(rename = "text-embedding-004")]
+    TextEmbedding004,
+
     /// Custom model
     Custom(String),
 }
 
-impl ToString for Models {
-    fn to_string(&self) -> String {
+impl Models {
+    /// Returns the response modalities the model can produce.
+    ///
+    /// Custom models are not validated, so every modality is reported as supported.
+    pub fn supported_response_modalities(&self) -> Vec<Modality> {
+        match self {
+            Models::Gemini2FlashExp => vec![Modality::Text, Modality::Image],
+            Models::Custom(_) => vec![Modality::Text, Modality::Image, Modality::Audio],
+            _ => vec![Modality::Text],
+        }
+    }
+
+    /// Returns the range of fixed thinking budgets the model accepts, if known.
+    ///
+    /// A range that doesn't contain `0` means thinking can't be turned off for the model.
+    pub fn thinking_budget_range(&self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Models::Gemini25ProExp => Some(128..=32768),
+            _ => None,
+        }
+    }
+
+    /// Returns the input and output token limits of the model, if known.
+    ///
+    /// The input limit is the model's context window, shared by the prompt and the response.
+    pub fn token_limits(&self) -> Option<(u32, u32)> {
+        match self {
+            Models::Gemini15Pro => Some((2_097_152, 8192)),
+            Models::Gemini15Flash | Models::Gemini2Flash | Models::Gemini2FlashLite => {
+                Some((1_048_576, 8192))
+            }
+            Models::Gemini25ProExp => Some((1_048_576, 65_536)),
+            Models::Gemini10Pro => Some((30_720, 2048)),
+            Models::Gemma2_2bIt | Models::Gemma2_9bIt | Models::Gemma2_27bIt => Some((8192, 8192)),
+            _ => None,
+        }
+    }
+
+    /// Returns the largest `max_output_tokens` the model can honor after a prompt of
+    /// `input_tokens` tokens, or `None` if the limits are unknown or the prompt fills
+    /// the context window.
+    pub fn max_output_tokens_for(&self, input_tokens: u32) -> Option<u32> {
+        let (input_limit, output_limit) = self.token_limits()?;
+        match input_limit.saturating_sub(input_tokens) {
+            0 => None,
+            remaining => Some(remaining.min(output_limit)),
+        }
+    }
+
+    /// Returns `true` if the model is an alias that resolves to the current version server side.
+    ///
+    /// The concrete version that served a request is reported by
+    /// `GenerateContentResponse::get_model_version`.
+    pub fn is_alias(&self) -> bool {
+        matches!(
+            self,
+            Models::GeminiFlashLatest | Models::GeminiFlashLiteLatest | Models::GeminiProLatest
+        )
+    }
+
+    /// Returns `true` if the model can produce the given response modality.
+    pub fn supports_response_modality(&self, modality: &Modality) -> bool {
+        self.supported_response_modalities().contains(modality)
+    }
+}
+
+impl FromStr for Models {
+    type Err = GemError;
+
+    /// Parses a model name, with or without the `models/` prefix.
+    ///
+    /// Known names map to their variant and any other name to `Models::Custom`.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.trim();
+        let name = name.strip_prefix("models/").unwrap_or(name);
+        if name.is_empty() {
+            return Err(GemError::InvalidConfig(
+                "the model name is empty".to_string(),
+            ));
+        }
+        Ok(
+            serde_json::from_value(serde_json::Value::String(name.to_string()))
+                .unwrap_or_else(|_| Models::Custom(name.to_string())),
+        )
+    }
+}
+
+impl TryFrom<&str> for Models {
+    type Error = GemError;
+
+    /// Parses a model name the same way as `str::parse`.
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+impl std::fmt::Display for Models {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Models::Custom(model) => model.to_string().replace("\"", ""),
-            _ => serde_json::to_string(self).unwrap().replace("\"", ""),
+            Models::Custom(model) => write!(f, "{}", model.replace('"', "")),
+            _ => write!(
+                f,
+                "{}",
+                serde_json::to_string(self).unwrap().replace('"', "")
+            ),
         }
     }
 }
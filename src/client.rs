@@ -4,28 +4,30 @@
 //! sessions with the Gemini API, including support for sending messages, files, and blobs,
 //! as well as streaming responses.
 
+use std::time::Instant;
+
 use super::types::Context;
 use dotenv::dotenv;
-use error::StreamBodyError;
-use futures::Stream;
 use reqwest::{Client as webClient, StatusCode};
 use reqwest_streams::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::api::{Models, GENERATE_CONTENT, STREAM_GENERATE_CONTENT};
+use crate::api::{Models, GENERATE_CONTENT, MODEL_ENV_VAR};
 use crate::errors::GemError;
-use crate::types::{Blob, Error, FileData, GenerateContentResponse, Role, Settings};
-
-pub type StreamResponseResult = Result<
-    Box<dyn Stream<Item = Result<GenerateContentResponse, StreamBodyError>> + Unpin>,
-    GemError,
->;
+use crate::hooks::{Hooks, RequestInfo, ResponseInfo};
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::stream::{sse_stream, ChunkStream, ResponseStream, TextStream};
+use crate::types::{
+    Blob, Content, Error, FileData, GenerateContentRequest, GenerateContentResponse,
+    GenerationConfig, JsonSchema, ModelInfo, Part, RateLimitInfo, RequestPriority, Role, Settings,
+    TaskType, RESPONSE_MIME_TYPE_JSON,
+};
+
+pub type StreamResponseResult<'a> = Result<ResponseStream<'a>, GemError>;
 pub type ResponseResult = Result<GenerateContentResponse, GemError>;
 
-pub type StreamResponse = Box<
-    dyn futures::Stream<
-            Item = Result<GenerateContentResponse, reqwest_streams::error::StreamBodyError>,
-        > + Unpin,
->;
+pub type StreamResponse<'a> = ResponseStream<'a>;
 
 pub type Response = GenerateContentResponse;
 
@@ -33,6 +35,195 @@ pub type Response = GenerateContentResponse;
 pub struct GemSession {
     client: Client,
     context: Context,
+    last_exchange: Option<(usize, serde_json::Value, GenerateContentResponse)>, // Context length, settings and response of the last turn
+}
+
+/// Error body returned by the Gemini API, which wraps the error in an `error` object.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: Error,
+}
+
+/// Builds an HTTP client. `timeout` bounds whole requests and is left unset for streaming,
+/// where `read_timeout` bounds each wait for more of the body instead.
+fn http_client(
+    timeout: Option<std::time::Duration>,
+    connect_timeout: std::time::Duration,
+    read_timeout: Option<std::time::Duration>,
+    http2_keep_alive_interval: Option<std::time::Duration>,
+    http2_adaptive_window: bool,
+) -> webClient {
+    let mut builder = webClient::builder()
+        .connect_timeout(connect_timeout)
+        .http2_keep_alive_interval(http2_keep_alive_interval)
+        .http2_adaptive_window(http2_adaptive_window);
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(read_timeout) = read_timeout {
+        builder = builder.read_timeout(read_timeout);
+    }
+    builder.build().unwrap_or_default()
+}
+
+/// Builds the error for a response body that doesn't match the expected JSON.
+fn unexpected_response(
+    status_code: StatusCode,
+    response_text: &str,
+    source: serde_json::Error,
+) -> GemError {
+    GemError::UnexpectedResponse {
+        status: status_code,
+        body: response_text.to_string(),
+        source,
+    }
+}
+
+/// Builds the error for a failed API response, keeping the HTTP status even if the body
+/// can't be parsed.
+fn api_error(
+    status_code: StatusCode,
+    retry_after: Option<std::time::Duration>,
+    response_text: &str,
+) -> GemError {
+    let error = match serde_json::from_str::<ErrorResponse>(response_text) {
+        Ok(response) => response.error,
+        Err(_) => match serde_json::from_str::<Error>(response_text) {
+            Ok(error) => error,
+            Err(e) => return unexpected_response(status_code, response_text, e),
+        },
+    };
+    GemError::GeminiAPIError((error.with_retry_after(retry_after), status_code))
+}
+
+/// Builds the error for a failed request to `model`, reporting the model as not available
+/// only when the API says the model itself was not found, rather than e.g. a file.
+fn model_error(
+    model: &Models,
+    status_code: StatusCode,
+    retry_after: Option<std::time::Duration>,
+    response_text: &str,
+) -> GemError {
+    match api_error(status_code, retry_after, response_text) {
+        GemError::GeminiAPIError((error, StatusCode::NOT_FOUND))
+            if error.get_status() == "NOT_FOUND" && error.get_message().contains("models/") =>
+        {
+            GemError::ModelNotAvailable {
+                model: model.to_string(),
+            }
+        }
+        error => error,
+    }
+}
+
+/// Reads the model set in the `GEMINI_MODEL` environment variable, if any.
+fn env_model() -> Option<Models> {
+    parse_env_model(std::env::var(MODEL_ENV_VAR).ok())
+}
+
+/// Parses the value of the `GEMINI_MODEL` environment variable, ignoring invalid names.
+fn parse_env_model(name: Option<String>) -> Option<Models> {
+    match name?.parse() {
+        Ok(model) => Some(model),
+        Err(e) => {
+            log::warn!("Ignoring {}: {}", MODEL_ENV_VAR, e);
+            None
+        }
+    }
+}
+
+/// Reads the `Retry-After` header, given either in seconds or as an HTTP date.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<std::time::Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(std::time::Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// Response of the countTokens endpoint.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CountTokensResponse {
+    total_tokens: i32,
+}
+
+/// Builds the body of an embedContent request, also used for each batch entry.
+fn embed_request(model: &Models, text: &str, task_type: Option<&TaskType>) -> serde_json::Value {
+    let mut request = serde_json::json!({
+        "model": format!("models/{}", model),
+        "content": { "parts": [{ "text": text }] },
+    });
+    if let Some(task_type) = task_type {
+        request["taskType"] = serde_json::json!(task_type);
+    }
+    request
+}
+
+/// Embedding values returned by the embedding endpoints.
+#[derive(Deserialize)]
+struct ContentEmbedding {
+    values: Vec<f32>,
+}
+
+/// Response of the embedContent endpoint.
+#[derive(Deserialize)]
+struct EmbedContentResponse {
+    embedding: ContentEmbedding,
+}
+
+/// Response of the batchEmbedContents endpoint.
+#[derive(Deserialize)]
+struct BatchEmbedContentsResponse {
+    #[serde(default)]
+    embeddings: Vec<ContentEmbedding>,
+}
+
+/// Response of the models list endpoint.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListModelsResponse {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+    next_page_token: Option<String>,
+}
+
+/// Serializable snapshot of a `GemSession`, excluding the API key and HTTP client.
+#[derive(Serialize, Deserialize)]
+struct SessionState {
+    model: Models,
+    context: Context,
+    timeout: std::time::Duration,
+    connect_timeout: std::time::Duration,
+    default_generation_config: Option<GenerationConfig>,
+    fallback_model: Option<Models>,
+    #[serde(default)]
+    base_url: Option<String>,
+    #[serde(default)]
+    read_timeout: Option<std::time::Duration>,
+    #[serde(default)]
+    embedding_model: Option<Models>,
+    #[serde(default)]
+    retry_policy: RetryPolicy,
+    #[serde(default)]
+    requests_per_minute: Option<u32>,
+    #[serde(default)]
+    auto_max_output_tokens: bool,
+    #[serde(default)]
+    dedupe_identical_requests: bool,
+    #[serde(default)]
+    sse_streaming: bool,
+    #[serde(default)]
+    http2_keep_alive_interval: Option<std::time::Duration>,
+    #[serde(default)]
+    http2_adaptive_window: bool,
 }
 
 /// Builder for creating a `GemSession` with custom configurations.
@@ -44,18 +235,52 @@ pub struct Config {
     pub connect_timeout: std::time::Duration,
     pub model: Models,
     pub context: Context,
-    pub api_key: Option<String>
+    pub api_key: Option<String>,
+    pub default_generation_config: Option<GenerationConfig>,
+    pub fallback_model: Option<Models>,
+    pub load_dotenv: bool,
+    pub embedding_model: Models,
+    pub http2_keep_alive_interval: Option<std::time::Duration>,
+    pub http2_adaptive_window: bool,
+    pub retry_policy: RetryPolicy,
+    pub base_url: Option<String>,
+    pub bearer_token: Option<String>,
+    pub(crate) hooks: Hooks,
+    pub requests_per_minute: Option<u32>,
+    pub auto_max_output_tokens: bool,
+    pub dedupe_identical_requests: bool,
+    pub sse_streaming: bool,
+    pub read_timeout: Option<std::time::Duration>,
 }
 
 impl GemSessionBuilder {
     /// Creates a new `GemSessionBuilder` with default settings.
+    ///
+    /// The model is read from the `GEMINI_MODEL` environment variable when it is set,
+    /// and can still be overridden with `model`.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> GemSessionBuilder {
         GemSessionBuilder(Config {
             timeout: std::time::Duration::from_secs(30),
             connect_timeout: std::time::Duration::from_secs(30),
-            model: Models::default(),
+            model: env_model().unwrap_or_default(),
             context: Context::new(),
             api_key: None,
+            default_generation_config: None,
+            fallback_model: None,
+            load_dotenv: false,
+            embedding_model: Models::TextEmbedding004,
+            http2_keep_alive_interval: None,
+            http2_adaptive_window: false,
+            retry_policy: RetryPolicy::default(),
+            base_url: None,
+            bearer_token: None,
+            hooks: Hooks::default(),
+            requests_per_minute: None,
+            auto_max_output_tokens: false,
+            dedupe_identical_requests: false,
+            sse_streaming: false,
+            read_timeout: None,
         })
     }
 
@@ -69,10 +294,14 @@ impl GemSessionBuilder {
                 std::time::Duration::from_secs(30),
             ),
             context: Context::new(),
+            last_exchange: None,
         }
     }
 
     /// Sets the timeout for API requests.
+    ///
+    /// Streaming requests are not bounded as a whole, since long generations can take
+    /// any time; see `read_timeout` for them.
     pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
         self.0.timeout = timeout;
         self
@@ -96,6 +325,21 @@ impl GemSessionBuilder {
         self
     }
 
+    /// Sets the connection and request timeouts in one call. See `connect_timeout` and `timeout`.
+    pub fn timeouts(mut self, connect: std::time::Duration, request: std::time::Duration) -> Self {
+        self.0.connect_timeout = connect;
+        self.0.timeout = request;
+        self
+    }
+
+    /// Sets how long to wait for more of a response body before giving up, which keeps
+    /// stalled streams from hanging while slow but progressing ones continue. Defaults to
+    /// the request `timeout`.
+    pub fn read_timeout(mut self, read_timeout: std::time::Duration) -> Self {
+        self.0.read_timeout = Some(read_timeout);
+        self
+    }
+
     /// Sets the initial context for the session.
     pub fn context(mut self, context: Context) -> Self {
         self.0.context = context;
@@ -108,15 +352,188 @@ impl GemSessionBuilder {
         self
     }
 
+    /// Sets the generation config used when `Settings` doesn't provide one.
+    pub fn default_generation_config(mut self, config: GenerationConfig) -> Self {
+        self.0.default_generation_config = Some(config);
+        self
+    }
+
+    /// Sets a model to retry with when the configured model is not available.
+    ///
+    /// Only content generation requests, streamed or not, fall back. Token counting and
+    /// embedding requests are sent to the configured models only.
+    pub fn fallback_model(mut self, model: Models) -> Self {
+        self.0.fallback_model = Some(model);
+        self
+    }
+
+    /// Sets the model used for embeddings.
+    pub fn embedding_model(mut self, model: Models) -> Self {
+        self.0.embedding_model = model;
+        self
+    }
+
+    /// Sets the interval for HTTP/2 keep-alive pings. Pings are disabled by default.
+    pub fn http2_keep_alive_interval(mut self, interval: std::time::Duration) -> Self {
+        self.0.http2_keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// Enables HTTP/2 adaptive flow control, which helps with many concurrent long streams.
+    pub fn http2_adaptive_window(mut self, enabled: bool) -> Self {
+        self.0.http2_adaptive_window = enabled;
+        self
+    }
+
+    /// Sets the maximum number of times a failed request is retried. Retries are disabled by default.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.0.retry_policy.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the delay before the first retry, doubled for every following retry.
+    pub fn base_delay(mut self, base_delay: std::time::Duration) -> Self {
+        self.0.retry_policy.base_delay = base_delay;
+        self
+    }
+
+    /// Sets whether a random delay of up to the base delay is added to every retry.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.0.retry_policy.jitter = jitter;
+        self
+    }
+
+    /// Sets the longest delay before a retry, which also caps a `Retry-After` delay
+    /// sent by the API. Defaults to 60 seconds.
+    pub fn max_delay(mut self, max_delay: std::time::Duration) -> Self {
+        self.0.retry_policy.max_delay = max_delay;
+        self
+    }
+
+    /// Sets the predicate deciding which errors are retried, replacing the default of
+    /// retrying rate limiting, overloaded service and timeout errors.
+    pub fn retry_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&GemError) -> bool + Send + Sync + 'static,
+    {
+        self.0.retry_policy.retry_if = Some(std::sync::Arc::new(predicate));
+        self
+    }
+
+    /// Sets the URL that model names are appended to, e.g. a Vertex AI endpoint such as
+    /// `https://us-central1-aiplatform.googleapis.com/v1/projects/{project}/locations/us-central1/publishers/google/models/`
+    /// or a proxy. Defaults to the public Gemini API.
+    pub fn base_url(mut self, base_url: String) -> Self {
+        let base_url = match base_url.ends_with('/') {
+            true => base_url,
+            false => format!("{}/", base_url),
+        };
+        self.0.base_url = Some(base_url);
+        self
+    }
+
+    /// Authenticates with an OAuth bearer token instead of an API key, as required by Vertex AI.
+    ///
+    /// When set, `GEMINI_API_KEY` is not read.
+    pub fn bearer_token(mut self, token: String) -> Self {
+        self.0.bearer_token = Some(token);
+        self
+    }
+
+    /// Limits the session to `requests` requests per minute; requests over the limit wait
+    /// for their turn instead of failing with a 429. Unlimited by default.
+    pub fn requests_per_minute(mut self, requests: u32) -> Self {
+        self.0.requests_per_minute = Some(requests);
+        self
+    }
+
+    /// Sizes `max_output_tokens` to the room left in the model's context window when the
+    /// settings leave it unset. Costs an extra `countTokens` request per message.
+    pub fn auto_max_output_tokens(mut self, enabled: bool) -> Self {
+        self.0.auto_max_output_tokens = enabled;
+        self
+    }
+
+    /// Returns the previous response instead of sending a request identical to the last
+    /// successful one, e.g. when a UI fires the same submission twice. Streaming requests
+    /// are never deduplicated. Disabled by default.
+    ///
+    /// A turn sent with `send_message`, `send_parts` and the like right after the same turn
+    /// and its reply, with the same settings, is not pushed again and gets the previous
+    /// response.
+    pub fn dedupe_identical_requests(mut self, enabled: bool) -> Self {
+        self.0.dedupe_identical_requests = enabled;
+        self
+    }
+
+    /// Streams responses as Server-Sent Events (`alt=sse`) instead of a JSON array, which
+    /// parses every chunk as soon as it arrives. Disabled by default.
+    pub fn sse_streaming(mut self, enabled: bool) -> Self {
+        self.0.sse_streaming = enabled;
+        self
+    }
+
+    /// Sets a callback invoked before every request with its model and size.
+    pub fn on_request<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&RequestInfo) + Send + Sync + 'static,
+    {
+        self.0.hooks.on_request = Some(std::sync::Arc::new(callback));
+        self
+    }
+
+    /// Sets a callback invoked after every response with its status, latency and usage.
+    pub fn on_response<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ResponseInfo) + Send + Sync + 'static,
+    {
+        self.0.hooks.on_response = Some(std::sync::Arc::new(callback));
+        self
+    }
+
+    /// Loads environment variables from a `.env` file before reading `GEMINI_API_KEY`.
+    pub fn load_dotenv(mut self) -> Self {
+        self.0.load_dotenv = true;
+        self
+    }
+
     /// Builds a `GemSession` with the configured settings and provided API key.
+    ///
+    /// If neither an API key nor a bearer token was set, the key is read from the
+    /// `GEMINI_API_KEY` environment variable.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key has to be read from `GEMINI_API_KEY` and the variable is not set.
+    /// Use `try_build` to get an error instead.
     pub fn build(self) -> GemSession {
+        self.try_build().expect("GEMINI_API_KEY is not set")
+    }
+
+    /// Like `build`, but returns `GemError::InvalidConfig` instead of panicking when the
+    /// API key has to be read from `GEMINI_API_KEY` and the variable is not set.
+    pub fn try_build(self) -> Result<GemSession, GemError> {
+        self.build_with_env(|name| std::env::var(name).ok())
+    }
+
+    /// Builds the session, looking up environment variables through `env`.
+    fn build_with_env(self, env: impl Fn(&str) -> Option<String>) -> Result<GemSession, GemError> {
         if let Some(api_key) = self.0.api_key.clone() {
-            GemSession::build(api_key, self.0)
-        }
-        else {
-            dotenv().expect("Failed to load Gemini API key");
-            let api_key = std::env::var("GEMINI_API_KEY").unwrap();
-            GemSession::build(api_key, self.0)
+            Ok(GemSession::build(api_key, self.0))
+        } else if self.0.bearer_token.is_some() {
+            Ok(GemSession::build(String::new(), self.0))
+        } else {
+            if self.0.load_dotenv {
+                if let Err(e) = dotenv() {
+                    log::warn!("Failed to load .env file: {}", e);
+                }
+            }
+            match env("GEMINI_API_KEY") {
+                Some(api_key) => Ok(GemSession::build(api_key, self.0)),
+                None => Err(GemError::InvalidConfig(
+                    "GEMINI_API_KEY is not set".to_string(),
+                )),
+            }
         }
     }
 }
@@ -124,8 +541,28 @@ impl GemSessionBuilder {
 /// Internal client for making API requests to Gemini.
 pub struct Client {
     client: webClient,
+    stream_client: std::sync::OnceLock<webClient>,
+    read_timeout: Option<std::time::Duration>,
+    http2_keep_alive_interval: Option<std::time::Duration>,
+    http2_adaptive_window: bool,
     api_key: String,
     model: Models,
+    timeout: std::time::Duration,
+    connect_timeout: std::time::Duration,
+    fallback_model: Option<Models>,
+    base_url: String,
+    default_generation_config: Option<GenerationConfig>,
+    embedding_model: Models,
+    retry_policy: RetryPolicy,
+    bearer_token: Option<String>,
+    hooks: Hooks,
+    rate_limiter: Option<std::sync::Arc<RateLimiter>>,
+    auto_max_output_tokens: bool,
+    dedupe_identical_requests: bool,
+    sse_streaming: bool,
+    last_response: std::sync::Mutex<Option<(String, GenerateContentResponse)>>,
+    last_rate_limit: std::sync::Mutex<Option<RateLimitInfo>>,
+    last_retry_count: std::sync::atomic::AtomicU32,
 }
 
 impl Client {
@@ -137,39 +574,370 @@ impl Client {
         connect_timeout: std::time::Duration,
     ) -> Self {
         Client {
-            client: webClient::builder()
-                .timeout(timeout)
-                .connect_timeout(connect_timeout)
-                .build()
-                .unwrap_or(webClient::new()),
+            client: http_client(Some(timeout), connect_timeout, None, None, false),
+            stream_client: std::sync::OnceLock::new(),
+            read_timeout: None,
+            http2_keep_alive_interval: None,
+            http2_adaptive_window: false,
             api_key,
             model,
+            timeout,
+            connect_timeout,
+            fallback_model: None,
+            base_url: GENERATE_CONTENT.to_string(),
+            default_generation_config: None,
+            embedding_model: Models::TextEmbedding004,
+            retry_policy: RetryPolicy::default(),
+            bearer_token: None,
+            hooks: Hooks::default(),
+            rate_limiter: None,
+            auto_max_output_tokens: false,
+            dedupe_identical_requests: false,
+            sse_streaming: false,
+            last_response: std::sync::Mutex::new(None),
+            last_rate_limit: std::sync::Mutex::new(None),
+            last_retry_count: std::sync::atomic::AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the client used for streaming, built on first use. It has no overall
+    /// timeout, only the read timeout, so long generations aren't cut off.
+    fn stream_client(&self) -> &webClient {
+        self.stream_client.get_or_init(|| {
+            http_client(
+                None,
+                self.connect_timeout,
+                Some(self.read_timeout.unwrap_or(self.timeout)),
+                self.http2_keep_alive_interval,
+                self.http2_adaptive_window,
+            )
+        })
+    }
+
+    /// Adds the credentials to a request: the bearer token if set, otherwise the API key.
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.bearer_token {
+            Some(token) => request.bearer_auth(token),
+            None => request.query(&[("key", &self.api_key)]),
+        }
+    }
+
+    /// Returns the rate limit state reported by the most recent response that carried one.
+    pub fn last_rate_limit_info(&self) -> Option<RateLimitInfo> {
+        self.last_rate_limit.lock().unwrap().clone()
+    }
+
+    /// Returns how many times the most recent `send_context` call was retried.
+    pub fn last_retry_count(&self) -> u32 {
+        self.last_retry_count
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Remembers the rate limit headers of a response, if it has any.
+    fn record_rate_limit(&self, headers: &reqwest::header::HeaderMap) {
+        if let Some(info) = RateLimitInfo::from_headers(headers) {
+            *self.last_rate_limit.lock().unwrap() = Some(info);
+        }
+    }
+
+    /// Checks that an API key is accepted by the Gemini API.
+    ///
+    /// Lists a single model, which is cheap and doesn't consume generation quota.
+    /// Returns `GemError::InvalidApiKey` if the key is rejected and
+    /// `GemError::ConnectionError` if the API couldn't be reached.
+    pub async fn validate_key(api_key: &str) -> Result<(), GemError> {
+        Self::validate_key_at(GENERATE_CONTENT, api_key).await
+    }
+
+    /// Checks an API key against the models endpoint at `base_url`.
+    pub(crate) async fn validate_key_at(base_url: &str, api_key: &str) -> Result<(), GemError> {
+        let response = match webClient::new()
+            .get(base_url.trim_end_matches('/'))
+            .query(&[("key", api_key), ("pageSize", "1")])
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(GemError::ConnectionError(e)),
+        };
+
+        let status_code = response.status();
+        match status_code {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Err(GemError::InvalidApiKey(status_code))
+            }
+            // The API rejects a malformed or unknown key with a 400 rather than a 401.
+            StatusCode::BAD_REQUEST => {
+                let retry_after = retry_after(response.headers());
+                let response_text = response
+                    .text()
+                    .await
+                    .map_err(|e| GemError::ResponseError((e, status_code)))?;
+                match api_error(status_code, retry_after, &response_text) {
+                    GemError::GeminiAPIError((error, _)) if error.has_reason("API_KEY_INVALID") => {
+                        Err(GemError::InvalidApiKey(status_code))
+                    }
+                    error => Err(error),
+                }
+            }
+            _ => match response.error_for_status() {
+                Ok(_) => Ok(()),
+                Err(e) => Err(GemError::ResponseError((e, status_code))),
+            },
+        }
+    }
+
+    /// Lists the models available to the client's API key, following every page of results.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, GemError> {
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut request = self.authorize(self.client.get(self.base_url.trim_end_matches('/')));
+
+            if let Some(token) = &page_token {
+                request = request.query(&[("pageToken", token)]);
+            }
+
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(e) => return Err(GemError::ConnectionError(e)),
+            };
+
+            let status_code = response.status();
+            let retry_after = retry_after(response.headers());
+            let response_text = match response.text().await {
+                Ok(text) => text,
+                Err(e) => return Err(GemError::ResponseError((e, status_code))),
+            };
+
+            if status_code != StatusCode::OK {
+                return Err(api_error(status_code, retry_after, &response_text));
+            }
+
+            let mut page = match serde_json::from_str::<ListModelsResponse>(&response_text) {
+                Ok(page) => page,
+                Err(e) => return Err(unexpected_response(status_code, &response_text, e)),
+            };
+            models.append(&mut page.models);
+
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
         }
+
+        Ok(models)
+    }
+
+    /// Builds the request body for a context, applying the client's default generation config.
+    pub(crate) fn build_request(
+        &self,
+        context: &Context,
+        settings: &Settings,
+    ) -> GenerateContentRequest {
+        context.build_with_default_config(settings, self.default_generation_config.as_ref())
     }
 
     /// Sends a context to the Gemini API and returns the response.
+    ///
+    /// Failed requests are retried according to the client's retry policy.
     pub(crate) async fn send_context(
         &self,
         context: &Context,
         settings: &Settings,
     ) -> ResponseResult {
-        let url = format!(
-            "{}{}:generateContent",
-            GENERATE_CONTENT,
-            self.model.to_string()
-        );
+        let input_tokens = self.input_tokens_for_sizing(context, settings).await?;
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .send_context_with_fallback(context, settings, input_tokens)
+                .await;
+            match result {
+                Err(e) if self.retry_policy.should_retry(&e, attempt) => {
+                    let delay = self.retry_policy.delay_for(&e, attempt);
+                    log::warn!("Request failed: {}, retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => {
+                    self.last_retry_count
+                        .store(attempt, std::sync::atomic::Ordering::Relaxed);
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Returns the last response if it was sent for the request hashed as `request_hash`.
+    fn cached_response(&self, request_hash: &str) -> Option<GenerateContentResponse> {
+        match self.last_response.lock().unwrap().as_ref() {
+            Some((hash, response)) if hash == request_hash => {
+                log::info!("Request is identical to the previous one, reusing its response");
+                Some(response.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends a context to the Gemini API and returns the response.
+    ///
+    /// If the model is not available and a fallback model is configured, the request is
+    /// retried once with the fallback model.
+    async fn send_context_with_fallback(
+        &self,
+        context: &Context,
+        settings: &Settings,
+        input_tokens: Option<u32>,
+    ) -> ResponseResult {
+        let result = self
+            .send_context_with_model(&self.model, context, settings, input_tokens)
+            .await;
+
+        match (&result, &self.fallback_model) {
+            (Err(GemError::ModelNotAvailable { model }), Some(fallback)) => {
+                log::warn!(
+                    "Model {} is not available, retrying with {}",
+                    model,
+                    fallback
+                );
+                self.send_context_with_model(fallback, context, settings, input_tokens)
+                    .await
+            }
+            _ => result,
+        }
+    }
+
+    /// Counts the tokens of the context when `max_output_tokens` is to be sized to the room
+    /// it leaves in the model's context window, i.e. auto-sizing is enabled and neither the
+    /// settings nor the default generation config set it.
+    ///
+    /// Only the contents are counted, so the system instruction and tools are not.
+    async fn input_tokens_for_sizing(
+        &self,
+        context: &Context,
+        settings: &Settings,
+    ) -> Result<Option<u32>, GemError> {
+        if !self.auto_max_output_tokens
+            || self.model.token_limits().is_none()
+            || settings
+                .effective_max_output_tokens(self.default_generation_config.as_ref())
+                .is_some()
+        {
+            return Ok(None);
+        }
+        Ok(Some(self.count_tokens(context).await?.max(0) as u32))
+    }
+
+    /// Returns the settings a request to `model` is sent with: the default generation
+    /// config applies when the settings have none, and `max_output_tokens` is sized for
+    /// `model` when `input_tokens` were counted for auto-sizing.
+    fn effective_settings(
+        &self,
+        model: &Models,
+        settings: &Settings,
+        input_tokens: Option<u32>,
+    ) -> Settings {
+        let settings =
+            settings.with_default_generation_config(self.default_generation_config.as_ref());
+        match input_tokens.and_then(|tokens| model.max_output_tokens_for(tokens)) {
+            Some(max_output_tokens) => {
+                log::info!(
+                    "Sized max_output_tokens to {} for {} input tokens on {}",
+                    max_output_tokens,
+                    input_tokens.unwrap_or_default(),
+                    model
+                );
+                let config = GenerationConfig {
+                    max_output_tokens: Some(max_output_tokens),
+                    ..Default::default()
+                };
+                settings.with_generation_config_override(&config, None)
+            }
+            None => settings,
+        }
+    }
+
+    /// Waits for the rate limiter, if any, to allow another request.
+    async fn wait_for_rate_limit(&self) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.acquire().await;
+        }
+    }
+
+    /// Describes a request for the `on_request` hook.
+    fn request_info(
+        &self,
+        model: &Models,
+        context: &Context,
+        request_size: usize,
+        streaming: bool,
+    ) -> RequestInfo {
+        RequestInfo {
+            model: model.clone(),
+            request_size,
+            estimated_tokens: context.content_breakdown().text_length.div_ceil(4),
+            streaming,
+        }
+    }
+
+    /// Returns the headers carrying the request priority set in `settings`, if `url` supports it.
+    fn priority_headers(&self, url: &str, settings: &Settings) -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(priority) = settings.get_request_priority() {
+            if RequestPriority::is_supported_by(url) {
+                headers.insert(
+                    RequestPriority::HEADER,
+                    reqwest::header::HeaderValue::from_static(priority.header_value()),
+                );
+            } else {
+                log::warn!(
+                    "Request priority {:?} is not supported by this backend and was ignored",
+                    priority
+                );
+            }
+        }
+        headers
+    }
+
+    /// Sends a context to the given model and returns the response.
+    async fn send_context_with_model(
+        &self,
+        model: &Models,
+        context: &Context,
+        settings: &Settings,
+        input_tokens: Option<u32>,
+    ) -> ResponseResult {
+        let settings = &self.effective_settings(model, settings, input_tokens);
+        settings.validate_for_model(model)?;
+        context.validate_inline_data_size()?;
+        let url = format!("{}{}:generateContent", self.base_url, model);
 
         log::info!("URL: {}", url);
 
-        let context = context.build(settings);
-        log::info!("Request: {:#?}", serde_json::to_string(&context).unwrap());
+        let body = serde_json::to_string(&self.build_request(context, settings)).unwrap();
+        log::info!("Request ({} bytes): {:#?}", body.len(), body);
+
+        let request_hash = self
+            .dedupe_identical_requests
+            .then(|| sha256::digest(format!("{}\n{}", model, body)));
+        if let Some(response) = request_hash
+            .as_deref()
+            .and_then(|hash| self.cached_response(hash))
+        {
+            return Ok(response);
+        }
 
+        let headers = self.priority_headers(&url, settings);
+        self.wait_for_rate_limit().await;
+        self.hooks
+            .request(self.request_info(model, context, body.len(), false));
+        let started = Instant::now();
         let response = match self
-            .client
-            .post(url)
-            .query(&[("key", &self.api_key)])
+            .authorize(self.client.post(url))
             .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&context)
+            .headers(headers)
+            .body(body)
             .send()
             .await
         {
@@ -178,30 +946,40 @@ impl Client {
         };
 
         let status_code = response.status();
+        let retry_after = retry_after(response.headers());
+        self.record_rate_limit(response.headers());
         let response_text = match response.text().await {
             Ok(text) => text,
             Err(e) => return Err(GemError::ResponseError((e, status_code))),
         };
+        let latency = started.elapsed();
 
-        log::info!("Response: {}", response_text);
+        log::info!(
+            "Response ({} bytes): {}",
+            response_text.len(),
+            response_text
+        );
 
-        let response = match status_code {
-            StatusCode::OK => match serde_json::from_str::<GenerateContentResponse>(&response_text)
-            {
-                Ok(response) => response,
-                Err(e) => {
-                    return Err(GemError::ParsingError(e));
-                }
-            },
-            _ => match serde_json::from_str::<Error>(&response_text) {
-                Ok(error) => {
-                    return Err(GemError::GeminiAPIError(error));
-                }
-                Err(e) => return Err(GemError::ParsingError(e)),
-            },
+        let parsed = match status_code {
+            StatusCode::OK => Some(serde_json::from_str::<GenerateContentResponse>(
+                &response_text,
+            )),
+            _ => None,
+        };
+        self.hooks.response(ResponseInfo::new(
+            model,
+            status_code,
+            latency,
+            parsed.as_ref().and_then(|parsed| parsed.as_ref().ok()),
+        ));
+
+        let response = match parsed {
+            Some(Ok(response)) => response,
+            Some(Err(e)) => return Err(unexpected_response(status_code, &response_text, e)),
+            None => return Err(model_error(model, status_code, retry_after, &response_text)),
         };
 
-        if response.get_candidates().len() == 0 {
+        if response.get_candidates().is_empty() {
             return Err(GemError::EmptyApiResponse);
         }
 
@@ -219,56 +997,210 @@ impl Client {
             if let Some(reason) = response.feedback() {
                 return Err(GemError::FeedbackError(reason.to_string()));
             }
+            if response.was_spii_blocked() {
+                return Err(GemError::SpiiBlocked);
+            }
             return Err(GemError::AllCandidatesBlocked);
         }
 
+        if response.is_effectively_empty() {
+            return Err(GemError::EmptyApiResponse);
+        }
+
+        if let Some(hash) = request_hash {
+            *self.last_response.lock().unwrap() = Some((hash, response.clone()));
+        }
         Ok(response)
     }
 
-    /// Sends a context to the Gemini API and returns a stream of responses.
-    pub(crate) async fn send_context_stream(
+    /// Counts the tokens the contents of a context would use with the client's model.
+    ///
+    /// File data parts are sent by URI, so previously uploaded files are not uploaded again.
+    pub async fn count_tokens(&self, context: &Context) -> Result<i32, GemError> {
+        let body = serde_json::json!({ "contents": context.get_contents() });
+        let response: CountTokensResponse =
+            self.post_model(&self.model, "countTokens", &body).await?;
+        Ok(response.total_tokens)
+    }
+
+    /// Generates an embedding for `text` with the client's embedding model.
+    pub async fn embed_content(
         &self,
-        context: &Context,
-        settings: &Settings,
-    ) -> StreamResponseResult {
-        let url = format!(
-            "{}{}:streamGenerateContent",
-            STREAM_GENERATE_CONTENT,
-            self.model.to_string()
-        );
+        text: &str,
+        task_type: Option<TaskType>,
+    ) -> Result<Vec<f32>, GemError> {
+        let body = embed_request(&self.embedding_model, text, task_type.as_ref());
+        let response: EmbedContentResponse = self
+            .post_model(&self.embedding_model, "embedContent", &body)
+            .await?;
+        Ok(response.embedding.values)
+    }
 
-        let response = self
-            .client
-            .post(url)
-            .query(&[("key", &self.api_key)])
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&context.build(settings))
-            .send()
-            .await;
+    /// Generates one embedding per text in a single request, in the same order as `texts`.
+    pub async fn batch_embed_contents(
+        &self,
+        texts: &[&str],
+        task_type: Option<TaskType>,
+    ) -> Result<Vec<Vec<f32>>, GemError> {
+        let requests: Vec<serde_json::Value> = texts
+            .iter()
+            .map(|text| embed_request(&self.embedding_model, text, task_type.as_ref()))
+            .collect();
+        let body = serde_json::json!({ "requests": requests });
+        let response: BatchEmbedContentsResponse = self
+            .post_model(&self.embedding_model, "batchEmbedContents", &body)
+            .await?;
+        Ok(response
+            .embeddings
+            .into_iter()
+            .map(|embedding| embedding.values)
+            .collect())
+    }
 
-        match response {
-            Ok(response) => {
+    /// Sets the model used for embeddings.
+    pub fn set_embedding_model(&mut self, model: Models) {
+        self.embedding_model = model;
+    }
+
+    /// Posts `body` to `method` of `model` and parses the successful response.
+    async fn post_model<T: DeserializeOwned>(
+        &self,
+        model: &Models,
+        method: &str,
+        body: &serde_json::Value,
+    ) -> Result<T, GemError> {
+        let url = format!("{}{}:{}", self.base_url, model, method);
+
+        log::info!("URL: {}", url);
+
+        let body = body.to_string();
+        log::info!("Request ({} bytes): {:#?}", body.len(), body);
+
+        self.wait_for_rate_limit().await;
+        let response = match self
+            .authorize(self.client.post(url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(GemError::ConnectionError(e)),
+        };
+
+        let status_code = response.status();
+        let retry_after = retry_after(response.headers());
+        self.record_rate_limit(response.headers());
+        let response_text = match response.text().await {
+            Ok(text) => text,
+            Err(e) => return Err(GemError::ResponseError((e, status_code))),
+        };
+
+        log::info!(
+            "Response ({} bytes): {}",
+            response_text.len(),
+            response_text
+        );
+
+        match status_code {
+            StatusCode::OK => serde_json::from_str::<T>(&response_text)
+                .map_err(|e| unexpected_response(status_code, &response_text, e)),
+            _ => Err(model_error(model, status_code, retry_after, &response_text)),
+        }
+    }
+
+    /// Sends a context to the Gemini API and returns a stream of responses.
+    pub(crate) async fn send_context_stream(
+        &self,
+        context: &Context,
+        settings: &Settings,
+    ) -> StreamResponseResult<'static> {
+        let input_tokens = self.input_tokens_for_sizing(context, settings).await?;
+        let result = self
+            .send_context_stream_with_model(&self.model, context, settings, input_tokens)
+            .await;
+
+        match (&result, &self.fallback_model) {
+            (Err(GemError::ModelNotAvailable { model }), Some(fallback)) => {
+                log::warn!(
+                    "Model {} is not available, retrying with {}",
+                    model,
+                    fallback
+                );
+                self.send_context_stream_with_model(fallback, context, settings, input_tokens)
+                    .await
+            }
+            _ => result,
+        }
+    }
+
+    /// Sends a context to `model` and returns a stream of responses.
+    async fn send_context_stream_with_model(
+        &self,
+        model: &Models,
+        context: &Context,
+        settings: &Settings,
+        input_tokens: Option<u32>,
+    ) -> StreamResponseResult<'static> {
+        let url = format!("{}{}:streamGenerateContent", self.base_url, model);
+
+        let settings = &self.effective_settings(model, settings, input_tokens);
+        settings.validate_for_model(model)?;
+        context.validate_inline_data_size()?;
+        let body = serde_json::to_string(&self.build_request(context, settings)).unwrap();
+        log::info!("Stream request size: {} bytes", body.len());
+
+        let headers = self.priority_headers(&url, settings);
+        self.wait_for_rate_limit().await;
+        self.hooks
+            .request(self.request_info(model, context, body.len(), true));
+        let started = Instant::now();
+        let mut request = self.authorize(self.stream_client().post(url));
+        if self.sse_streaming {
+            request = request.query(&[("alt", "sse")]);
+        }
+        let response = request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .headers(headers)
+            .body(body)
+            .send()
+            .await;
+
+        match response {
+            Ok(response) => {
                 let status_code = response.status();
+                let retry_after = retry_after(response.headers());
+                self.record_rate_limit(response.headers());
+                self.hooks.response(ResponseInfo::new(
+                    model,
+                    status_code,
+                    started.elapsed(),
+                    None,
+                ));
                 match status_code {
                     StatusCode::OK => {
-                        let json_stream = response.json_array_stream::<GenerateContentResponse>(
-                            settings.get_stream_max_json_size() as usize,
-                        );
-                        Ok(Box::new(json_stream))
+                        let max_size = settings.get_stream_max_json_size() as usize;
+                        let chunks: ChunkStream = match self.sse_streaming {
+                            true => sse_stream(response.bytes_stream(), max_size),
+                            false => Box::new(
+                                response.json_array_stream::<GenerateContentResponse>(max_size),
+                            ),
+                        };
+                        Ok(ResponseStream::new(chunks, started)
+                            .stop_when(settings.get_stream_stop_when())
+                            .chunk_timeout(settings.get_stream_chunk_timeout()))
                     }
                     _ => {
-                        return Err(GemError::StreamError(format!(
-                            "Response error: {} (status code: {})",
-                            response.text().await.unwrap(),
-                            status_code
-                        )));
+                        let response_text = response
+                            .text()
+                            .await
+                            .map_err(|e| GemError::ResponseError((e, status_code)))?;
+                        Err(model_error(model, status_code, retry_after, &response_text))
                     }
                 }
             }
 
-            Err(e) => {
-                return Err(GemError::ConnectionError(e));
-            }
+            Err(e) => Err(GemError::ConnectionError(e)),
         }
     }
 }
@@ -276,14 +1208,47 @@ impl Client {
 impl GemSession {
     /// Builds a new `GemSession` with the provided API key and configuration.
     pub(crate) fn build(api_key: String, config: Config) -> Self {
-        GemSession {
-            client: Client::new(
-                api_key,
-                config.model,
-                config.timeout,
+        let mut client = Client::new(
+            api_key,
+            config.model,
+            config.timeout,
+            config.connect_timeout,
+        );
+        client.default_generation_config = config.default_generation_config;
+        client.fallback_model = config.fallback_model;
+        client.embedding_model = config.embedding_model;
+        client.retry_policy = config.retry_policy;
+        client.bearer_token = config.bearer_token;
+        client.hooks = config.hooks;
+        client.rate_limiter = config
+            .requests_per_minute
+            .map(|requests| std::sync::Arc::new(RateLimiter::per_minute(requests)));
+        client.auto_max_output_tokens = config.auto_max_output_tokens;
+        client.dedupe_identical_requests = config.dedupe_identical_requests;
+        client.sse_streaming = config.sse_streaming;
+        if let Some(base_url) = config.base_url {
+            client.base_url = base_url;
+        }
+        client.read_timeout = config.read_timeout;
+        client.http2_keep_alive_interval = config.http2_keep_alive_interval;
+        client.http2_adaptive_window = config.http2_adaptive_window;
+        if config.read_timeout.is_some()
+            || config.http2_keep_alive_interval.is_some()
+            || config.http2_adaptive_window
+        {
+            client.client = http_client(
+                Some(config.timeout),
                 config.connect_timeout,
-            ),
+                config.read_timeout,
+                config.http2_keep_alive_interval,
+                config.http2_adaptive_window,
+            );
+        }
+
+        GemSession {
+            client,
             context: config.context,
+            last_exchange: None,
         }
     }
 
@@ -293,10 +1258,103 @@ impl GemSession {
     }
 
     /// Returns a new `GemSessionBuilder` for creating a customized `GemSession`.
+    #[allow(non_snake_case)]
     pub fn Builder() -> GemSessionBuilder {
         GemSessionBuilder::new()
     }
 
+    /// Serializes the session's model, context, and session-level settings to JSON.
+    ///
+    /// The API key, bearer token, hooks and `retry_if` predicate are not included in the
+    /// saved state.
+    pub fn save_state(&self) -> Result<String, GemError> {
+        let state = SessionState {
+            model: self.client.model.clone(),
+            context: self.context.clone(),
+            timeout: self.client.timeout,
+            connect_timeout: self.client.connect_timeout,
+            default_generation_config: self.client.default_generation_config.clone(),
+            fallback_model: self.client.fallback_model.clone(),
+            base_url: Some(self.client.base_url.clone()),
+            read_timeout: self.client.read_timeout,
+            embedding_model: Some(self.client.embedding_model.clone()),
+            retry_policy: self.client.retry_policy.clone(),
+            requests_per_minute: self
+                .client
+                .rate_limiter
+                .as_ref()
+                .map(|limiter| limiter.requests_per_minute()),
+            auto_max_output_tokens: self.client.auto_max_output_tokens,
+            dedupe_identical_requests: self.client.dedupe_identical_requests,
+            sse_streaming: self.client.sse_streaming,
+            http2_keep_alive_interval: self.client.http2_keep_alive_interval,
+            http2_adaptive_window: self.client.http2_adaptive_window,
+        };
+        serde_json::to_string(&state).map_err(GemError::ParsingError)
+    }
+
+    /// Restores a session saved with `save_state`, rebuilding the HTTP client with `api_key`.
+    pub fn restore_state(api_key: String, json: &str) -> Result<GemSession, GemError> {
+        let state: SessionState = serde_json::from_str(json).map_err(GemError::ParsingError)?;
+
+        let mut builder = GemSessionBuilder::new();
+        builder.0.model = state.model;
+        builder.0.context = state.context;
+        builder.0.timeout = state.timeout;
+        builder.0.connect_timeout = state.connect_timeout;
+        builder.0.default_generation_config = state.default_generation_config;
+        builder.0.fallback_model = state.fallback_model;
+        builder.0.base_url = state.base_url;
+        builder.0.read_timeout = state.read_timeout;
+        if let Some(embedding_model) = state.embedding_model {
+            builder.0.embedding_model = embedding_model;
+        }
+        builder.0.retry_policy = state.retry_policy;
+        builder.0.requests_per_minute = state.requests_per_minute;
+        builder.0.auto_max_output_tokens = state.auto_max_output_tokens;
+        builder.0.dedupe_identical_requests = state.dedupe_identical_requests;
+        builder.0.sse_streaming = state.sse_streaming;
+        builder.0.http2_keep_alive_interval = state.http2_keep_alive_interval;
+        builder.0.http2_adaptive_window = state.http2_adaptive_window;
+        Ok(GemSession::build(api_key, builder.0))
+    }
+
+    /// Generates an embedding for `text` with the session's embedding model.
+    pub async fn embed_content(
+        &self,
+        text: &str,
+        task_type: Option<TaskType>,
+    ) -> Result<Vec<f32>, GemError> {
+        self.client.embed_content(text, task_type).await
+    }
+
+    /// Generates one embedding per text with the session's embedding model.
+    pub async fn batch_embed_contents(
+        &self,
+        texts: &[&str],
+        task_type: Option<TaskType>,
+    ) -> Result<Vec<Vec<f32>>, GemError> {
+        self.client.batch_embed_contents(texts, task_type).await
+    }
+
+    /// Returns the rate limit state reported by the most recent response that carried
+    /// `X-RateLimit-*` headers, so callers can throttle before hitting 429s.
+    pub fn last_rate_limit_info(&self) -> Option<RateLimitInfo> {
+        self.client.last_rate_limit_info()
+    }
+
+    /// Returns how many times the most recent request was retried before it succeeded or
+    /// gave up, to monitor how flaky the API is. Streaming requests are not retried and
+    /// don't change it.
+    pub fn last_retry_count(&self) -> u32 {
+        self.client.last_retry_count()
+    }
+
+    /// Counts the tokens the session's context would use with the session's model.
+    pub async fn count_tokens(&self) -> Result<i32, GemError> {
+        self.client.count_tokens(&self.context).await
+    }
+
     /// Sends a message to the Gemini API and returns the response.
     pub async fn send_message(
         &mut self,
@@ -305,19 +1363,29 @@ impl GemSession {
         settings: &Settings,
     ) -> ResponseResult {
         self.context.push_message(role, message.to_string());
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Role::Model,
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
-        Ok(response)
+        self.send_turn(settings).await
+    }
+
+    /// Sends a prompt asking for a JSON response following the schema of `T`, and parses it.
+    ///
+    /// The session's default generation config is kept; only the response schema and MIME
+    /// type are overridden. The prompt and response are recorded in the session context.
+    pub async fn extract<T: JsonSchema + DeserializeOwned>(
+        &mut self,
+        prompt: &str,
+    ) -> Result<T, GemError> {
+        let config = GenerationConfig {
+            response_mime_type: Some(RESPONSE_MIME_TYPE_JSON.to_string()),
+            response_schema: Some(T::json_schema()),
+            ..Default::default()
+        };
+        let settings = Settings::new().with_generation_config_override(
+            &config,
+            self.client.default_generation_config.as_ref(),
+        );
+        self.send_message(prompt, Role::User, &settings)
+            .await?
+            .parse_json()
     }
 
     /// Sends a file to the Gemini API and returns the response.
@@ -328,20 +1396,7 @@ impl GemSession {
         settings: &Settings,
     ) -> ResponseResult {
         self.context.push_file(role, file_data);
-
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Role::Model,
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
-        Ok(response)
+        self.send_turn(settings).await
     }
 
     /// Sends a blob to the Gemini API and returns the response.
@@ -352,19 +1407,7 @@ impl GemSession {
         settings: &Settings,
     ) -> ResponseResult {
         self.context.push_blob(role, blob);
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Role::Model,
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
-        Ok(response)
+        self.send_turn(settings).await
     }
 
     /// Sends a message with an attached file to the Gemini API and returns the response.
@@ -377,19 +1420,7 @@ impl GemSession {
     ) -> ResponseResult {
         self.context
             .push_message_with_file(role, message, file_data);
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Role::Model,
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
-        Ok(response)
+        self.send_turn(settings).await
     }
 
     /// Sends a message with an attached blob to the Gemini API and returns the response.
@@ -401,19 +1432,30 @@ impl GemSession {
         settings: &Settings,
     ) -> ResponseResult {
         self.context.push_message_with_blob(role, message, blob);
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Role::Model,
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
-        Ok(response)
+        self.send_turn(settings).await
+    }
+
+    /// Sends a turn made of arbitrary parts, e.g. two images and a question, and returns
+    /// the response.
+    pub async fn send_parts(
+        &mut self,
+        parts: Vec<Part>,
+        role: Role,
+        settings: &Settings,
+    ) -> ResponseResult {
+        self.context.push_parts(Some(role), parts);
+        self.send_turn(settings).await
+    }
+
+    /// Sends a turn made of arbitrary parts and returns a stream of responses.
+    pub async fn send_parts_stream(
+        &mut self,
+        parts: Vec<Part>,
+        role: Role,
+        settings: &Settings,
+    ) -> StreamResponseResult<'_> {
+        self.context.push_parts(Some(role), parts);
+        self.send_context_stream(settings).await
     }
 
     /// Sends a message to the Gemini API and returns a stream of responses.
@@ -422,9 +1464,39 @@ impl GemSession {
         message: &str,
         role: Role,
         settings: &Settings,
-    ) -> StreamResponseResult {
+    ) -> StreamResponseResult<'_> {
+        self.context.push_message(role, message.to_string());
+        self.send_context_stream(settings).await
+    }
+
+    /// Sends a message to the Gemini API and returns a stream of responses, with `config`
+    /// overriding the generation config of `settings` for this request only.
+    pub async fn send_message_stream_with(
+        &mut self,
+        message: &str,
+        role: Role,
+        settings: &Settings,
+        config: &GenerationConfig,
+    ) -> StreamResponseResult<'_> {
+        let settings = settings.with_generation_config_override(
+            config,
+            self.client.default_generation_config.as_ref(),
+        );
+        self.context.push_message(role, message.to_string());
+        self.send_context_stream(&settings).await
+    }
+
+    /// Sends a message to the Gemini API and returns a stream of the text deltas of the response.
+    ///
+    /// The full response is recorded in the session context once the stream completes.
+    pub async fn send_message_text_stream(
+        &mut self,
+        message: &str,
+        role: Role,
+        settings: &Settings,
+    ) -> Result<TextStream<'_>, GemError> {
         self.context.push_message(role, message.to_string());
-        Ok(Box::new(self.send_context_stream(settings).await?))
+        Ok(TextStream::new(self.send_context_stream(settings).await?))
     }
 
     /// Sends a file to the Gemini API and returns a stream of responses.
@@ -433,9 +1505,9 @@ impl GemSession {
         file_data: FileData,
         role: Role,
         settings: &Settings,
-    ) -> StreamResponseResult {
+    ) -> StreamResponseResult<'_> {
         self.context.push_file(role, file_data);
-        Ok(Box::new(self.send_context_stream(settings).await?))
+        self.send_context_stream(settings).await
     }
 
     /// Sends a blob to the Gemini API and returns a stream of responses.
@@ -444,9 +1516,9 @@ impl GemSession {
         blob: Blob,
         role: Role,
         settings: &Settings,
-    ) -> StreamResponseResult {
+    ) -> StreamResponseResult<'_> {
         self.context.push_blob(role, blob);
-        Ok(Box::new(self.send_context_stream(settings).await?))
+        self.send_context_stream(settings).await
     }
 
     /// Sends a message with an attached file to the Gemini API and returns a stream of responses.
@@ -456,10 +1528,10 @@ impl GemSession {
         file_data: FileData,
         role: Role,
         settings: &Settings,
-    ) -> StreamResponseResult {
+    ) -> StreamResponseResult<'_> {
         self.context
             .push_message_with_file(role, message, file_data);
-        Ok(Box::new(self.send_context_stream(settings).await?))
+        self.send_context_stream(settings).await
     }
 
     /// Sends a message with an attached blob to the Gemini API and returns a stream of responses.
@@ -469,9 +1541,65 @@ impl GemSession {
         blob: Blob,
         role: Role,
         settings: &Settings,
-    ) -> StreamResponseResult {
+    ) -> StreamResponseResult<'_> {
         self.context.push_message_with_blob(role, message, blob);
-        Ok(Box::new(self.send_context_stream(settings).await?))
+        self.send_context_stream(settings).await
+    }
+
+    /// Sends `parts` as a single user turn and returns the response, without reading or
+    /// changing the session context.
+    ///
+    /// Since it only borrows the session, several calls can run in parallel, e.g. for
+    /// stateless requests rebuilt from a tool loop.
+    pub async fn generate_once(&self, parts: Vec<Part>, settings: &Settings) -> ResponseResult {
+        let mut context = Context::new();
+        context.push_parts(Some(Role::User), parts);
+        self.client.send_context(&context, settings).await
+    }
+
+    /// Sends the context after a turn was pushed and records the model's reply.
+    ///
+    /// With `dedupe_identical_requests`, a turn repeating the previous one is popped again
+    /// and answered with the previous response.
+    async fn send_turn(&mut self, settings: &Settings) -> ResponseResult {
+        if let Some(response) = self.repeated_turn(settings) {
+            log::info!("Turn is identical to the previous one, reusing its response");
+            self.context.pop();
+            return Ok(response);
+        }
+
+        let response = self.send_context(settings).await?;
+        // Every part is kept, so function calls can be answered with a function response.
+        if let Some(content) = response
+            .get_candidates()
+            .first()
+            .and_then(|candidate| candidate.get_content())
+        {
+            self.context.push_content(Content {
+                role: Some(Role::Model),
+                parts: content.parts.clone(),
+            });
+        }
+        if self.client.dedupe_identical_requests {
+            self.last_exchange = serde_json::to_value(settings)
+                .ok()
+                .map(|settings| (self.context.len(), settings, response.clone()));
+        }
+        Ok(response)
+    }
+
+    /// Returns the previous response if the last pushed turn repeats the turn before the
+    /// previous reply, with nothing else pushed since and the same settings.
+    fn repeated_turn(&self, settings: &Settings) -> Option<GenerateContentResponse> {
+        let (length, last_settings, response) = self.last_exchange.as_ref()?;
+        let contents = self.context.get_contents();
+        if !self.client.dedupe_identical_requests || contents.len() != length + 1 || *length < 2 {
+            return None;
+        }
+        let turn = serde_json::to_value(&contents[*length]).ok()?;
+        let previous = serde_json::to_value(&contents[*length - 2]).ok()?;
+        let settings = serde_json::to_value(settings).ok()?;
+        (turn == previous && settings == *last_settings).then(|| response.clone())
     }
 
     /// Internal method to send a context to the Gemini API.
@@ -480,20 +1608,27 @@ impl GemSession {
     }
 
     /// Internal method to send a context to the Gemini API and return a stream of responses.
-    pub async fn send_context_stream(&mut self, settings: &Settings) -> StreamResponseResult {
-        self.client
+    ///
+    /// The response is recorded in the session context once the stream completes.
+    pub async fn send_context_stream(&mut self, settings: &Settings) -> StreamResponseResult<'_> {
+        let stream = self
+            .client
             .send_context_stream(&self.context, settings)
-            .await
+            .await?;
+        Ok(stream.record_to(&mut self.context))
     }
 }
 
+#[cfg(test)]
 mod tests {
 
+    use crate::mock::{MockResponse, MockServer};
     use crate::types::HarmBlockThreshold;
 
     use super::*;
 
     #[tokio::test]
+    #[ignore = "requires GEMINI_API_KEY and network access"]
     async fn test_gem_session_send_context() {
         dotenv().expect("Failed to load Gemini API key");
         let api_key = std::env::var("GEMINI_API_KEY").unwrap();
@@ -503,6 +1638,7 @@ mod tests {
             .timeout(std::time::Duration::from_secs(30))
             .model(Models::Gemini15FlashExp0827)
             .context(Context::new())
+            .api_key(api_key)
             .build();
 
         let mut settings = Settings::new();
@@ -511,6 +1647,211 @@ mod tests {
         let response = session
             .send_message("Hello! What is your name?", Role::User, &settings)
             .await;
+        assert!(response.is_ok());
+    }
+
+    #[test]
+    fn test_default_generation_config() {
+        let session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .default_generation_config(GenerationConfig {
+                max_output_tokens: Some(256),
+                temperature: Some(0.5),
+                ..Default::default()
+            })
+            .build();
+
+        let settings = Settings::new();
+        let request =
+            serde_json::to_value(session.client.build_request(&session.context, &settings))
+                .unwrap();
+        assert_eq!(request["generationConfig"]["maxOutputTokens"], 256);
+        assert_eq!(request["generationConfig"]["temperature"], 0.5);
+
+        let mut settings = Settings::new();
+        settings.set_temperature(1.5);
+        let request =
+            serde_json::to_value(session.client.build_request(&session.context, &settings))
+                .unwrap();
+        assert_eq!(request["generationConfig"]["temperature"], 1.5);
+        assert!(request["generationConfig"]["maxOutputTokens"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_model_not_available_fallback() {
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                404,
+                r#"{"code": 404, "message": "models/gemini-0.1 is not found", "status": "NOT_FOUND"}"#,
+            ),
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .custom_model("gemini-0.1".to_string())
+            .fallback_model(Models::Gemini2Flash)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let response = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(response.get_results(), vec!["Hi".to_string()]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].path.starts_with("/gemini-0.1:generateContent"));
+        assert!(requests[1]
+            .path
+            .starts_with("/gemini-2.0-flash:generateContent"));
+    }
+
+    #[tokio::test]
+    async fn test_model_not_available_error() {
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                404,
+                r#"{"error": {"code": 404, "message": "models/gemini-0.1 is not found", "status": "NOT_FOUND"}}"#,
+            ),
+            MockResponse::new(
+                404,
+                r#"{"error": {"code": 404, "message": "File abc123 was not found", "status": "NOT_FOUND"}}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .custom_model("gemini-0.1".to_string())
+            .fallback_model(Models::Gemini2Flash)
+            .build();
+        session.client.fallback_model = None;
+        session.client.base_url = server.url.clone();
+
+        match session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+        {
+            Err(GemError::ModelNotAvailable { model }) => assert_eq!(model, "gemini-0.1"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // A 404 about something other than the model doesn't trigger the fallback.
+        session.client.fallback_model = Some(Models::Gemini2Flash);
+        match session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+        {
+            Err(GemError::GeminiAPIError((error, StatusCode::NOT_FOUND))) => {
+                assert!(error.get_message().contains("File abc123"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_session_state_round_trip() {
+        let mut context = Context::new();
+        context.push_message(Role::User, "Hello".to_string());
+        context.push_message(Role::Model, "Hi there".to_string());
+
+        let session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .custom_model("gemini-test-001".to_string())
+            .timeout(std::time::Duration::from_secs(12))
+            .fallback_model(Models::Gemini2Flash)
+            .context(context)
+            .read_timeout(std::time::Duration::from_secs(7))
+            .embedding_model(Models::Custom("embedding-test".to_string()))
+            .max_retries(3)
+            .base_delay(std::time::Duration::from_millis(250))
+            .jitter(false)
+            .max_delay(std::time::Duration::from_secs(5))
+            .requests_per_minute(30)
+            .auto_max_output_tokens(true)
+            .dedupe_identical_requests(true)
+            .sse_streaming(true)
+            .http2_keep_alive_interval(std::time::Duration::from_secs(20))
+            .http2_adaptive_window(true)
+            .build();
+
+        let json = session.save_state().unwrap();
+        assert!(!json.contains("test-key"));
+
+        let restored = GemSession::restore_state("other-key".to_string(), &json).unwrap();
+        assert_eq!(
+            restored.client.model,
+            Models::Custom("gemini-test-001".to_string())
+        );
+        assert_eq!(restored.client.fallback_model, Some(Models::Gemini2Flash));
+        assert_eq!(restored.client.timeout, std::time::Duration::from_secs(12));
+        assert_eq!(restored.client.api_key, "other-key");
+        assert_eq!(restored.context.len(), 2);
+        assert_eq!(
+            restored.context.get_contents()[1].get_text().unwrap(),
+            "Hi there"
+        );
+        assert_eq!(restored.context.get_contents()[1].role, Some(Role::Model));
+
+        let client = &restored.client;
+        assert_eq!(client.read_timeout, Some(std::time::Duration::from_secs(7)));
+        assert_eq!(
+            client.embedding_model,
+            Models::Custom("embedding-test".to_string())
+        );
+        assert_eq!(client.retry_policy.max_retries, 3);
+        assert_eq!(
+            client.retry_policy.base_delay,
+            std::time::Duration::from_millis(250)
+        );
+        assert!(!client.retry_policy.jitter);
+        assert_eq!(
+            client.retry_policy.max_delay,
+            std::time::Duration::from_secs(5)
+        );
+        assert_eq!(
+            client
+                .rate_limiter
+                .as_ref()
+                .map(|limiter| limiter.requests_per_minute()),
+            Some(30)
+        );
+        assert!(client.auto_max_output_tokens);
+        assert!(client.dedupe_identical_requests);
+        assert!(client.sse_streaming);
+        assert_eq!(
+            client.http2_keep_alive_interval,
+            Some(std::time::Duration::from_secs(20))
+        );
+        assert!(client.http2_adaptive_window);
+
+        // States saved before these settings existed still load, with the defaults.
+        let legacy = r#"{"model": "gemini-1.5-flash", "context": {"contents": []}, "timeout": {"secs": 30, "nanos": 0}, "connect_timeout": {"secs": 30, "nanos": 0}, "default_generation_config": null, "fallback_model": null}"#;
+        let restored = GemSession::restore_state("other-key".to_string(), legacy).unwrap();
+        assert_eq!(restored.client.embedding_model, Models::TextEmbedding004);
+        assert_eq!(restored.client.retry_policy.max_retries, 0);
+        assert!(restored.client.retry_policy.jitter);
+        assert!(restored.client.rate_limiter.is_none());
+    }
+
+    #[test]
+    fn test_build_with_env_api_key() {
+        let session = GemSession::Builder()
+            .build_with_env(|name| (name == "GEMINI_API_KEY").then(|| "env-key".to_string()))
+            .unwrap();
+        assert_eq!(session.client.api_key, "env-key");
+
+        assert!(matches!(
+            GemSession::Builder().build_with_env(|_| None),
+            Err(GemError::InvalidConfig(_))
+        ));
     }
 
     #[test]
@@ -545,4 +1886,1367 @@ mod tests {
         let model = Models::Custom("gemini-3-flash-001".to_string());
         assert_eq!(model.to_string(), "gemini-3-flash-001");
     }
+
+    #[test]
+    fn test_models_as_map_key() {
+        let mut limits = std::collections::HashMap::new();
+        limits.insert(Models::Gemini2Flash, 8192);
+        limits.insert(Models::Custom("gemini-3-flash-001".to_string()), 65536);
+
+        assert_eq!(limits.get(&Models::Gemini2Flash), Some(&8192));
+        assert_eq!(
+            limits.get(&Models::Custom("gemini-3-flash-001".to_string())),
+            Some(&65536)
+        );
+        assert_eq!(limits.get(&Models::Gemini15Pro), None);
+        assert_eq!(Models::default(), Models::Gemini2Flash);
+        assert_ne!(Models::Gemini2Flash, Models::Gemini2FlashLite);
+    }
+
+    #[test]
+    fn test_model_aliases() {
+        assert_eq!(Models::GeminiFlashLatest.to_string(), "gemini-flash-latest");
+        assert_eq!(
+            Models::GeminiFlashLiteLatest.to_string(),
+            "gemini-flash-lite-latest"
+        );
+        assert_eq!(Models::GeminiProLatest.to_string(), "gemini-pro-latest");
+
+        let model: Models = serde_json::from_str(r#""gemini-pro-latest""#).unwrap();
+        assert_eq!(model, Models::GeminiProLatest);
+        assert!(model.is_alias());
+        assert!(!Models::Gemini2Flash.is_alias());
+    }
+
+    #[tokio::test]
+    async fn test_validate_key() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, r#"{"models": [{"name": "models/gemini-2.0-flash"}]}"#),
+            MockResponse::new(
+                403,
+                r#"{"error": {"code": 403, "message": "Permission denied", "status": "PERMISSION_DENIED"}}"#,
+            ),
+            MockResponse::new(
+                400,
+                r#"{"error": {"code": 400, "message": "API key not valid. Please pass a valid API key.", "status": "INVALID_ARGUMENT", "details": [{"@type": "type.googleapis.com/google.rpc.ErrorInfo", "reason": "API_KEY_INVALID", "domain": "googleapis.com"}]}}"#,
+            ),
+            MockResponse::new(
+                400,
+                r#"{"error": {"code": 400, "message": "Invalid page size", "status": "INVALID_ARGUMENT"}}"#,
+            ),
+            MockResponse::new(500, ""),
+        ])
+        .await;
+
+        assert!(Client::validate_key_at(&server.url, "good-key")
+            .await
+            .is_ok());
+        match Client::validate_key_at(&server.url, "bad-key").await {
+            Err(GemError::InvalidApiKey(status)) => assert_eq!(status, StatusCode::FORBIDDEN),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Client::validate_key_at(&server.url, "bad-key").await {
+            Err(GemError::InvalidApiKey(status)) => assert_eq!(status, StatusCode::BAD_REQUEST),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Other bad requests are reported as API errors.
+        match Client::validate_key_at(&server.url, "good-key").await {
+            Err(GemError::GeminiAPIError((error, StatusCode::BAD_REQUEST))) => {
+                assert_eq!(error.get_status(), "INVALID_ARGUMENT")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Client::validate_key_at(&server.url, "good-key").await {
+            Err(GemError::ResponseError((_, status))) => {
+                assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert!(requests[0].path.contains("key=good-key"));
+        assert!(requests[0].path.contains("pageSize=1"));
+
+        let unreachable = "http://127.0.0.1:1/";
+        assert!(matches!(
+            Client::validate_key_at(unreachable, "good-key").await,
+            Err(GemError::ConnectionError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens() {
+        let server =
+            MockServer::start(vec![MockResponse::new(200, r#"{"totalTokens": 271}"#)]).await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .model(Models::Gemini2Flash)
+            .build();
+        session.client.base_url = server.url.clone();
+        session
+            .context
+            .push_message(Role::User, "Describe this video".to_string());
+        session.context.push_file(
+            Role::User,
+            FileData {
+                mime_type: "video/mp4".to_string(),
+                file_uri: "https://generativelanguage.googleapis.com/v1beta/files/abc123"
+                    .to_string(),
+            },
+        );
+
+        assert_eq!(session.count_tokens().await.unwrap(), 271);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .path
+            .starts_with("/gemini-2.0-flash:countTokens?key=test-key"));
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["contents"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            body["contents"][1]["parts"][0]["file_data"]["file_uri"],
+            "https://generativelanguage.googleapis.com/v1beta/files/abc123"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                200,
+                r#"{
+                    "models": [{
+                        "name": "models/gemini-2.0-flash",
+                        "displayName": "Gemini 2.0 Flash",
+                        "description": "Fast and versatile",
+                        "inputTokenLimit": 1048576,
+                        "outputTokenLimit": 8192,
+                        "supportedGenerationMethods": ["generateContent", "countTokens"]
+                    }],
+                    "nextPageToken": "page-2"
+                }"#,
+            ),
+            MockResponse::new(
+                200,
+                r#"{
+                    "models": [{
+                        "name": "models/text-embedding-004",
+                        "supportedGenerationMethods": ["embedContent"]
+                    }]
+                }"#,
+            ),
+        ])
+        .await;
+
+        let mut client = Client::new(
+            "test-key".to_string(),
+            Models::default(),
+            std::time::Duration::from_secs(5),
+            std::time::Duration::from_secs(5),
+        );
+        client.base_url = server.url.clone();
+
+        let models = client.list_models().await.unwrap();
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].get_display_name(), "Gemini 2.0 Flash");
+        assert_eq!(models[0].get_input_token_limit(), 1048576);
+        assert!(models[0].supports_generate_content());
+        assert_eq!(models[0].to_model(), Models::Gemini2Flash);
+        assert!(!models[1].supports_generate_content());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].path.contains("pageToken=page-2"));
+    }
+
+    #[tokio::test]
+    async fn test_api_error_status_code() {
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                429,
+                r#"{"error": {"code": 429, "message": "Resource exhausted", "status": "RESOURCE_EXHAUSTED"}}"#,
+            ),
+            MockResponse::new(503, "<html>Service Unavailable</html>"),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let error = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, GemError::GeminiAPIError(_)));
+        assert_eq!(error.status_code(), Some(StatusCode::TOO_MANY_REQUESTS));
+
+        let error = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, GemError::UnexpectedResponse { body, .. } if body == "<html>Service Unavailable</html>")
+        );
+        assert_eq!(error.status_code(), Some(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[tokio::test]
+    async fn test_embed_content() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, r#"{"embedding": {"values": [0.1, -0.2, 0.3]}}"#),
+            MockResponse::new(
+                200,
+                r#"{"embeddings": [{"values": [1.0, 0.0]}, {"values": [0.0, 1.0]}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .embedding_model(Models::Custom("gemini-embedding-001".to_string()))
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let embedding = session
+            .embed_content("What is Rust?", Some(TaskType::RetrievalQuery))
+            .await
+            .unwrap();
+        assert_eq!(embedding, vec![0.1, -0.2, 0.3]);
+
+        let embeddings = session
+            .batch_embed_contents(&["first", "second"], None)
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+
+        let requests = server.requests();
+        assert!(requests[0]
+            .path
+            .starts_with("/gemini-embedding-001:embedContent"));
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["model"], "models/gemini-embedding-001");
+        assert_eq!(body["taskType"], "RETRIEVAL_QUERY");
+        assert_eq!(body["content"]["parts"][0]["text"], "What is Rust?");
+
+        assert!(requests[1]
+            .path
+            .starts_with("/gemini-embedding-001:batchEmbedContents"));
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body["requests"].as_array().unwrap().len(), 2);
+        assert!(body["requests"][0].get("taskType").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_http2_options() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .http2_keep_alive_interval(std::time::Duration::from_secs(20))
+            .http2_adaptive_window(true)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let response = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(response.get_results(), vec!["Hi".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_whitespace_only_response() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"parts": [{"text": " \n "}], "role": "model"}}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        assert!(matches!(
+            session
+                .send_message("Hello", Role::User, &Settings::new())
+                .await,
+            Err(GemError::EmptyApiResponse)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_if_predicate() {
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"parts": [{"text": " "}], "role": "model"}}]}"#,
+            ),
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .max_retries(2)
+            .retry_if(|error| matches!(error, GemError::EmptyApiResponse))
+            .build();
+        session.client.base_url = server.url.clone();
+        session.client.retry_policy.base_delay = std::time::Duration::ZERO;
+
+        let response = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(response.get_results(), vec!["Hi".to_string()]);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_text_stream() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"[
+                {"candidates": [{"content": {"parts": [{"text": "Hello"}], "role": "model"}}]},
+                {"candidates": [{"content": {"parts": [{"text": " there"}], "role": "model"}}]}
+            ]"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let mut stream = session
+            .send_message_text_stream("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        let mut text = String::new();
+        while let Some(delta) = futures::StreamExt::next(&mut stream).await {
+            text.push_str(&delta.unwrap());
+        }
+        assert_eq!(text, "Hello there");
+
+        let contents = session.context.get_contents();
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[1].role, Some(Role::Model));
+        assert_eq!(contents[1].get_text().as_deref(), Some("Hello there"));
+        assert!(server.requests()[0].path.contains(":streamGenerateContent"));
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let unavailable = MockResponse::new(
+            503,
+            r#"{"error": {"code": 503, "message": "The model is overloaded", "status": "UNAVAILABLE"}}"#,
+        )
+        .header("Retry-After", "0");
+        let server = MockServer::start(vec![
+            unavailable.clone(),
+            unavailable,
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .max_retries(3)
+            .base_delay(std::time::Duration::from_millis(1))
+            .jitter(false)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let response = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(response.get_results(), vec!["Hi".to_string()]);
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(session.last_retry_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_on_client_errors() {
+        let server = MockServer::start(vec![MockResponse::new(
+            400,
+            r#"{"error": {"code": 400, "message": "Invalid argument", "status": "INVALID_ARGUMENT"}}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .max_retries(3)
+            .base_delay(std::time::Duration::from_millis(1))
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let error = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.status_code(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(
+            retry_after(&headers),
+            Some(std::time::Duration::from_secs(7))
+        );
+
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        headers.insert(reqwest::header::RETRY_AFTER, date.parse().unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > std::time::Duration::from_secs(25));
+        assert!(delay <= std::time::Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_status() {
+        let server = MockServer::start(vec![MockResponse::new(
+            429,
+            r#"{"error": {"code": 429, "message": "Resource exhausted", "status": "RESOURCE_EXHAUSTED"}}"#,
+        )
+        .header("Retry-After", "3")])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        match session
+            .send_message_stream("Hi", Role::User, &Settings::new())
+            .await
+        {
+            Err(error @ GemError::GeminiAPIError(_)) => {
+                assert_eq!(error.status_code(), Some(StatusCode::TOO_MANY_REQUESTS))
+            }
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        // A missing model falls back like non-streaming requests do.
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                404,
+                r#"{"error": {"code": 404, "message": "models/gemini-0.1 is not found", "status": "NOT_FOUND"}}"#,
+            ),
+            MockResponse::new(
+                200,
+                r#"[{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}]"#,
+            ),
+        ])
+        .await;
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .custom_model("gemini-0.1".to_string())
+            .fallback_model(Models::Gemini2Flash)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let text = session
+            .send_message_stream("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap()
+            .collect_text()
+            .await
+            .unwrap();
+        assert_eq!(text, "Hi");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1]
+            .path
+            .contains(&format!("{}:streamGenerateContent", Models::Gemini2Flash)));
+    }
+
+    #[tokio::test]
+    async fn test_send_message_stream_with_override() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"[{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}]"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let mut settings = Settings::new();
+        settings.set_temperature(0.2);
+        settings.set_max_output_tokens(256);
+        let config = GenerationConfig {
+            temperature: Some(1.5),
+            ..Default::default()
+        };
+
+        let mut stream = session
+            .send_message_stream_with("Hi", Role::User, &settings, &config)
+            .await
+            .unwrap();
+        while futures::StreamExt::next(&mut stream).await.is_some() {}
+
+        let body: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(body["generationConfig"]["temperature"], 1.5);
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 256);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_closes_connection() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"[{"candidates": [{"content": {"parts": [{"text": "Hello"}], "role": "model"}}]},"#,
+        )
+        .hold_open()])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let mut stream = session
+            .send_message_stream("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert!(futures::StreamExt::next(&mut stream).await.unwrap().is_ok());
+        assert_eq!(server.disconnects(), 0);
+        drop(stream);
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while server.disconnects() == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the connection was not closed after dropping the stream");
+        assert_eq!(session.context.get_contents().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_aborted_stream_closes_connection() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"[{"candidates": [{"content": {"parts": [{"text": "Hello"}], "role": "model"}}]},"#,
+        )
+        .hold_open()])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let (handle, registration) = futures::future::AbortHandle::new_pair();
+        let mut stream = session
+            .send_message_stream("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap()
+            .abort_on(registration);
+        assert!(futures::StreamExt::next(&mut stream).await.unwrap().is_ok());
+        handle.abort();
+        assert!(futures::StreamExt::next(&mut stream).await.is_none());
+        assert!(stream.summary().was_cancelled());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while server.disconnects() == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the connection was not closed after aborting the stream");
+        drop(stream);
+        assert_eq!(session.context.get_contents().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_extract() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Person {
+            name: String,
+            age: u32,
+            emails: Vec<String>,
+        }
+
+        impl JsonSchema for Person {
+            fn json_schema() -> serde_json::Value {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "name": String::json_schema(),
+                        "age": u32::json_schema(),
+                        "emails": Vec::<String>::json_schema()
+                    },
+                    "required": ["name", "age", "emails"]
+                })
+            }
+        }
+
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"parts": [{"text": "{\"name\": \"Ada\", \"age\": 36, \"emails\": [\"ada@example.com\"]}"}], "role": "model"}}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .default_generation_config(GenerationConfig {
+                temperature: Some(0.1),
+                ..Default::default()
+            })
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let person: Person = session
+            .extract("Ada, 36, reachable at ada@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            person,
+            Person {
+                name: "Ada".to_string(),
+                age: 36,
+                emails: vec!["ada@example.com".to_string()],
+            }
+        );
+
+        let body: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        let config = &body["generationConfig"];
+        assert_eq!(config["responseMimeType"], RESPONSE_MIME_TYPE_JSON);
+        assert_eq!(config["responseSchema"], Person::json_schema());
+        assert_eq!(
+            config["responseSchema"]["properties"]["emails"]["items"]["type"],
+            "string"
+        );
+        assert!((config["temperature"].as_f64().unwrap() - 0.1).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_last_rate_limit_info() {
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}"#,
+            )
+            .header("X-RateLimit-Limit", "15")
+            .header("X-RateLimit-Remaining", "14")
+            .header("X-RateLimit-Reset", "60"),
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+        assert!(session.last_rate_limit_info().is_none());
+
+        session
+            .send_message("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        let info = session.last_rate_limit_info().unwrap();
+        assert_eq!(info.get_limit(), Some(15));
+        assert_eq!(info.get_remaining(), Some(14));
+        assert_eq!(info.get_reset(), Some(std::time::Duration::from_secs(60)));
+
+        session
+            .send_message("Hi again", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(session.last_rate_limit_info(), Some(info));
+    }
+
+    #[test]
+    fn test_model_from_env() {
+        assert_eq!(
+            "models/gemini-1.5-flash".parse::<Models>().unwrap(),
+            Models::Gemini15Flash
+        );
+        assert_eq!(
+            "gemini-test-001".parse::<Models>().unwrap(),
+            Models::Custom("gemini-test-001".to_string())
+        );
+        assert!(" ".parse::<Models>().is_err());
+
+        assert_eq!(
+            parse_env_model(Some("gemini-1.5-flash".to_string())),
+            Some(Models::Gemini15Flash)
+        );
+        assert_eq!(parse_env_model(Some(" ".to_string())), None);
+        assert_eq!(parse_env_model(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_base_url_and_bearer_token() {
+        let response =
+            r#"{"candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}}]}"#;
+        let server = MockServer::start(vec![MockResponse::new(200, response)]).await;
+
+        let mut session = GemSession::Builder()
+            .base_url(format!(
+                "{}v1/projects/p/locations/l/publishers/google/models",
+                server.url
+            ))
+            .bearer_token("ya29.token".to_string())
+            .model(Models::Gemini2Flash)
+            .build();
+        session
+            .send_message("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].path,
+            "/v1/projects/p/locations/l/publishers/google/models/gemini-2.0-flash:generateContent"
+        );
+        assert_eq!(
+            requests[0].header("authorization"),
+            Some("Bearer ya29.token")
+        );
+
+        let restored =
+            GemSession::restore_state("key".to_string(), &session.save_state().unwrap()).unwrap();
+        assert_eq!(restored.client.base_url, session.client.base_url);
+
+        let session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        assert_eq!(session.client.base_url, GENERATE_CONTENT);
+        assert!(session.client.bearer_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request_and_response_hooks() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{
+                "candidates": [{"content": {"parts": [{"text": "Hi"}], "role": "model"}, "finishReason": "STOP"}],
+                "usageMetadata": {"promptTokenCount": 3, "candidatesTokenCount": 1, "totalTokenCount": 4}
+            }"#,
+        )])
+        .await;
+
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let responses = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (seen_requests, seen_responses) = (requests.clone(), responses.clone());
+        let mut session = GemSession::Builder()
+            .api_key("secret-key".to_string())
+            .model(Models::Gemini2Flash)
+            .on_request(move |info| seen_requests.lock().unwrap().push(format!("{:?}", info)))
+            .on_response(move |info| seen_responses.lock().unwrap().push(info.clone()))
+            .build();
+        session.client.base_url = server.url.clone();
+
+        session
+            .send_message("Hello there!", Role::User, &Settings::new())
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].contains("Gemini2Flash"));
+        assert!(requests[0].contains("estimated_tokens: 3"));
+        assert!(!requests[0].contains("secret-key"));
+
+        let responses = responses.lock().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, StatusCode::OK);
+        assert_eq!(
+            responses[0].finish_reason,
+            Some(crate::types::FinishReason::Stop)
+        );
+        assert_eq!(
+            responses[0].usage.as_ref().unwrap().get_total_token_count(),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn test_requests_per_minute_is_opt_in() {
+        let session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        assert!(session.client.rate_limiter.is_none());
+
+        let session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .requests_per_minute(60)
+            .build();
+        assert!(session.client.rate_limiter.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_auto_max_output_tokens() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, r#"{"totalTokens": 8000}"#),
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Done."}]}, "finishReason": "STOP"}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .model(Models::Gemma2_9bIt)
+            .auto_max_output_tokens(true)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        session
+            .send_message(&"word ".repeat(8000), Role::User, &Settings::new())
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].path.contains(":countTokens"));
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 192);
+    }
+
+    #[tokio::test]
+    async fn test_auto_max_output_tokens_keeps_explicit_limit() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Done."}]}, "finishReason": "STOP"}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .model(Models::Gemma2_9bIt)
+            .auto_max_output_tokens(true)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let mut settings = Settings::new();
+        settings.set_max_output_tokens(100);
+        session
+            .send_message("Hello there!", Role::User, &settings)
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 100);
+    }
+
+    #[test]
+    fn test_max_output_tokens_for() {
+        assert_eq!(Models::Gemma2_9bIt.max_output_tokens_for(100), Some(8092));
+        assert_eq!(Models::Gemini2Flash.max_output_tokens_for(100), Some(8192));
+        assert_eq!(Models::Gemma2_9bIt.max_output_tokens_for(8192), None);
+        assert_eq!(
+            Models::Custom("my-model".to_string()).max_output_tokens_for(100),
+            None
+        );
+    }
+
+    #[test]
+    fn test_models_from_str_round_trip() {
+        let models = [
+            Models::Gemini15ProExp0827,
+            Models::Gemini15FlashExp0827,
+            Models::Gemini15Flash8bExp0827,
+            Models::Gemini15Pro,
+            Models::Gemini2FlashExp,
+            Models::Gemini2Flash,
+            Models::Gemini2FlashLite,
+            Models::Gemini2FlashThinkingExp,
+            Models::Gemini2ProExp1206,
+            Models::Gemini2ProExp,
+            Models::Gemini25ProExp,
+            Models::Gemini15Flash,
+            Models::Gemini10Pro,
+            Models::Gemma2_2bIt,
+            Models::Gemma2_9bIt,
+            Models::Gemma2_27bIt,
+            Models::GeminiFlashLatest,
+            Models::GeminiFlashLiteLatest,
+            Models::GeminiProLatest,
+            Models::TextEmbedding004,
+            Models::Custom("gemini-3-flash-001".to_string()),
+        ];
+        for model in models {
+            assert_eq!(model.to_string().parse::<Models>().unwrap(), model);
+            assert_eq!(Models::try_from(model.to_string().as_str()).unwrap(), model);
+        }
+        assert!(Models::try_from("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_spii_blocked() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"finishReason": "SPII", "index": 0}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let error = session
+            .send_message("My SSN is 123-45-6789", Role::User, &Settings::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, GemError::SpiiBlocked));
+        assert!(error.to_string().contains("personal information"));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_response_keeps_body() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": "none", "promptFeedback": {"blockReason": "OTHER"}}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let error = session
+            .send_message("Hello", Role::User, &Settings::new())
+            .await
+            .unwrap_err();
+        match &error {
+            GemError::UnexpectedResponse { status, body, .. } => {
+                assert_eq!(*status, StatusCode::OK);
+                assert!(body.contains("blockReason"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        let message = error.to_string();
+        assert!(message.contains("invalid type"));
+        assert!(message.contains("blockReason"));
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_identical_requests() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Hi!"}]}, "finishReason": "STOP"}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .dedupe_identical_requests(true)
+            .build();
+        session.client.base_url = server.url.clone();
+        session.context.push_user_message("Hello");
+
+        let settings = Settings::new();
+        let first = session.send_context(&settings).await.unwrap();
+        let second = session.send_context(&settings).await.unwrap();
+        assert_eq!(first.get_results(), second.get_results());
+        assert_eq!(server.requests().len(), 1);
+
+        let mut settings = Settings::new();
+        settings.set_temperature(0.2);
+        session.send_context(&settings).await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_sent_by_default() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Hi!"}]}, "finishReason": "STOP"}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+        session.context.push_user_message("Hello");
+
+        session.send_context(&Settings::new()).await.unwrap();
+        session.send_context(&Settings::new()).await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sse_streaming() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            "data: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \"Hello\"}], \"role\": \"model\"}}]}\r\n\r\n\
+             data: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \" world\"}], \"role\": \"model\"}}]}\r\n\r\n",
+        )
+        .header("Content-Type", "text/event-stream")])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .sse_streaming(true)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let mut stream = session
+            .send_message_stream("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(stream.collect_text().await.unwrap(), "Hello world");
+        drop(stream);
+
+        let requests = server.requests();
+        assert!(requests[0].path.contains(":streamGenerateContent?"));
+        assert!(requests[0].path.contains("alt=sse"));
+        assert_eq!(
+            session.context.get_contents()[1].get_text().as_deref(),
+            Some("Hello world")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_parts() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Both are cats."}]}, "finishReason": "STOP"}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let image = |data: &str| Part::blob(Blob::new("image/png", data.as_bytes()));
+        session
+            .send_parts(
+                vec![
+                    image("first"),
+                    image("second"),
+                    Part::text("What are these?"),
+                ],
+                Role::User,
+                &Settings::new(),
+            )
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        let parts = body["contents"][0]["parts"].as_array().unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0]["inline_data"]["mime_type"], "image/png");
+        assert_eq!(parts[1]["inline_data"]["mime_type"], "image/png");
+        assert_eq!(parts[2]["text"], "What are these?");
+        assert_eq!(session.context.get_contents().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream_outlives_request_timeout() {
+        let chunk =
+            r#"{"candidates": [{"content": {"parts": [{"text": "tick"}], "role": "model"}}]}"#;
+        let parts = [
+            "[".to_string(),
+            format!("{},", chunk),
+            format!("{},", chunk),
+            format!("{}]", chunk),
+        ];
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        let server = MockServer::start(vec![MockResponse::streamed(
+            200,
+            &parts,
+            std::time::Duration::from_millis(150),
+        )])
+        .await;
+
+        // The stream takes about 600ms in total, but never waits more than 150ms for a part.
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .timeout(std::time::Duration::from_millis(400))
+            .read_timeout(std::time::Duration::from_secs(2))
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let mut stream = session
+            .send_message_stream("Count", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(stream.collect_text().await.unwrap(), "tick".repeat(3));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream_hits_read_timeout() {
+        let chunk =
+            r#"{"candidates": [{"content": {"parts": [{"text": "tick"}], "role": "model"}}]}"#;
+        let first = format!("[{},", chunk);
+        let server = MockServer::start(vec![MockResponse::streamed(
+            200,
+            &[first.as_str(), "never"],
+            std::time::Duration::from_millis(50),
+        )
+        .hold_open()])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .timeout(std::time::Duration::from_secs(30))
+            .read_timeout(std::time::Duration::from_millis(300))
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let mut stream = session
+            .send_message_stream("Count", Role::User, &Settings::new())
+            .await
+            .unwrap();
+        let started = std::time::Instant::now();
+        let error = stream.collect_text().await.unwrap_err();
+        assert_eq!(error.partial, "tick");
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_fails_fast() {
+        // Nothing answers on this non-routable address, so connecting can only time out
+        // (or fail right away when the network is unreachable).
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .timeout(std::time::Duration::from_secs(60))
+            .connect_timeout(std::time::Duration::from_millis(200))
+            .build();
+        session.client.base_url = "http://10.255.255.1/".to_string();
+
+        let started = std::time::Instant::now();
+        let error = session
+            .send_message_stream("Hi", Role::User, &Settings::new())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, GemError::ConnectionError(_)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_builder_timeouts() {
+        let session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .timeouts(
+                std::time::Duration::from_secs(5),
+                std::time::Duration::from_secs(90),
+            )
+            .build();
+        assert_eq!(
+            session.client.connect_timeout,
+            std::time::Duration::from_secs(5)
+        );
+        assert_eq!(session.client.timeout, std::time::Duration::from_secs(90));
+    }
+
+    #[tokio::test]
+    async fn test_generate_once_leaves_context_alone() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"parts": [{"text": "Four"}], "role": "model"}}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+        session.context.push_user_message("Earlier question");
+
+        let settings = Settings::new();
+        let (first, second) = futures::join!(
+            session.generate_once(vec![Part::text("2 + 2?")], &settings),
+            session.generate_once(vec![Part::text("2 * 2?")], &settings),
+        );
+        assert_eq!(first.unwrap().get_results(), vec!["Four".to_string()]);
+        assert_eq!(second.unwrap().get_results(), vec!["Four".to_string()]);
+        assert_eq!(session.context.len(), 1);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+            let contents = body["contents"].as_array().unwrap();
+            assert_eq!(contents.len(), 1);
+            assert_eq!(contents[0]["role"], "user");
+            assert!(!request.body.contains("Earlier question"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_function_call_round_trip() {
+        let server = MockServer::start(vec![
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Let me check."}, {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}}]}, "finishReason": "STOP"}]}"#,
+            ),
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "It is 21 degrees in Paris."}]}, "finishReason": "STOP"}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let settings = Settings::new();
+        let response = session
+            .send_message("What's the weather in Paris?", Role::User, &settings)
+            .await
+            .unwrap();
+        let calls = response.get_candidates()[0].get_function_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "get_weather");
+
+        session
+            .context
+            .push_function_response("get_weather", serde_json::json!({ "temperature": 21 }));
+        let response = session.send_context(&settings).await.unwrap();
+        assert_eq!(
+            response.get_results(),
+            vec!["It is 21 degrees in Paris.".to_string()]
+        );
+
+        // The follow-up carries the model's call, so the function response has a match.
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        let contents = body["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents[1]["role"], "model");
+        assert_eq!(contents[1]["parts"][0]["text"], "Let me check.");
+        assert_eq!(
+            contents[1]["parts"][1]["function_call"]["name"],
+            "get_weather"
+        );
+        assert_eq!(
+            contents[2]["parts"][0]["function_response"]["name"],
+            "get_weather"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_repeated_message() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Hi!"}]}, "finishReason": "STOP"}]}"#,
+        )])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .dedupe_identical_requests(true)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let settings = Settings::new();
+        let first = session
+            .send_message("Hello", Role::User, &settings)
+            .await
+            .unwrap();
+        let second = session
+            .send_message("Hello", Role::User, &settings)
+            .await
+            .unwrap();
+        assert_eq!(first.get_results(), second.get_results());
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(session.context.len(), 2);
+
+        // A different turn goes out and is recorded as usual.
+        session
+            .send_message("Bye", Role::User, &settings)
+            .await
+            .unwrap();
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(session.context.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_default_generation_config_is_validated() {
+        let server = MockServer::start(vec![]).await;
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .default_generation_config(GenerationConfig {
+                temperature: Some(3.0),
+                ..Default::default()
+            })
+            .build();
+        session.client.base_url = server.url.clone();
+
+        let error = session
+            .send_message("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, GemError::InvalidConfig(_)));
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_auto_max_output_tokens_sized_for_fallback() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, r#"{"totalTokens": 10}"#),
+            MockResponse::new(
+                404,
+                r#"{"error": {"code": 404, "message": "models/gemini-2.5-pro-exp-03-25 is not found", "status": "NOT_FOUND"}}"#,
+            ),
+            MockResponse::new(
+                200,
+                r#"{"candidates": [{"content": {"role": "model", "parts": [{"text": "Done."}]}, "finishReason": "STOP"}]}"#,
+            ),
+        ])
+        .await;
+
+        let mut session = GemSession::Builder()
+            .api_key("test-key".to_string())
+            .model(Models::Gemini25ProExp)
+            .fallback_model(Models::Gemini2Flash)
+            .auto_max_output_tokens(true)
+            .build();
+        session.client.base_url = server.url.clone();
+
+        session
+            .send_message("Hi", Role::User, &Settings::new())
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        let sized = |index: usize| {
+            let body: serde_json::Value = serde_json::from_str(&requests[index].body).unwrap();
+            body["generationConfig"]["maxOutputTokens"].clone()
+        };
+        assert_eq!(sized(1), 65_536);
+        assert_eq!(sized(2), 8192);
+    }
 }
@@ -9,6 +9,9 @@ use std::fmt;
 
 use crate::types;
 
+/// Number of characters of the response body shown by `GemError::UnexpectedResponse`.
+const MAX_BODY_PREVIEW_CHARS: usize = 500;
+
 /// Represents errors that can occur in the Gem-rs library.
 #[derive(Debug)]
 pub enum GemError {
@@ -27,12 +30,23 @@ pub enum GemError {
     /// Indicates that all candidate responses were blocked by the API.
     AllCandidatesBlocked,
 
-    /// Represents an error returned by the Gemini API.
-    GeminiAPIError(types::Error),
+    /// Indicates that the response was blocked because it may contain sensitive personally
+    /// identifiable information (SPII).
+    SpiiBlocked,
+
+    /// Represents an error returned by the Gemini API, including the HTTP status code.
+    GeminiAPIError((types::Error, reqwest::StatusCode)),
 
-    /// Represents an error that occurred while parsing the API response.
+    /// Represents an error that occurred while parsing JSON.
     ParsingError(serde_json::Error),
 
+    /// Indicates that an API response could not be parsed, keeping the raw body for debugging.
+    UnexpectedResponse {
+        status: reqwest::StatusCode,
+        body: String,
+        source: serde_json::Error,
+    },
+
     /// Represents an error that occurred during the feedback process.
     FeedbackError(String),
 
@@ -41,6 +55,22 @@ pub enum GemError {
 
     /// Represents an error related to file operations.
     FileError(String),
+
+    /// Indicates that the requested model was not found or is no longer available.
+    ModelNotAvailable { model: String },
+
+    /// Indicates that the request settings are invalid and would be rejected by the API.
+    InvalidConfig(String),
+
+    /// Indicates that the inline data in a request exceeds the API's size limit.
+    InlineDataTooLarge { size: usize, limit: usize },
+
+    /// Indicates that the API rejected the API key (HTTP 401 or 403, or a 400 with reason
+    /// `API_KEY_INVALID`).
+    InvalidApiKey(reqwest::StatusCode),
+
+    /// Indicates that a stream went longer than its chunk timeout without a new chunk.
+    Timeout(std::time::Duration),
 }
 
 impl fmt::Display for GemError {
@@ -49,20 +79,79 @@ impl fmt::Display for GemError {
             GemError::EmptyApiResponse => write!(f, "Received an empty response from the API"),
             GemError::PromptFeedbackBlocked => write!(f, "Prompt feedback state is blocked"),
             GemError::AllCandidatesBlocked => write!(f, "All candidates have a block error"),
+            GemError::SpiiBlocked => write!(
+                f,
+                "The response was blocked because it may contain sensitive personal information; remove personal data from the prompt and try again"
+            ),
             GemError::ConnectionError(e) => write!(f, "Connection error: {}", e),
             GemError::ParsingError(e) => write!(f, "Parsing error: {}", e),
-            GemError::GeminiAPIError(e) => write!(f, "Gemini API error: {}", e),
+            GemError::UnexpectedResponse {
+                status,
+                body,
+                source,
+            } => {
+                let preview: String = body.chars().take(MAX_BODY_PREVIEW_CHARS).collect();
+                let ellipsis = if preview.len() < body.len() { "..." } else { "" };
+                write!(
+                    f,
+                    "Unexpected response: {} (status code: {}): {}{}",
+                    source, status, preview, ellipsis
+                )
+            }
+            GemError::GeminiAPIError((e, status)) => {
+                write!(f, "Gemini API error: {} (status code: {})", e, status)
+            }
             GemError::ResponseError((e, status)) => {
                 write!(f, "Response error: {} (status code: {})", e, status)
             }
             GemError::FeedbackError(e) => write!(f, "Feedback error: {}", e),
             GemError::StreamError(e) => write!(f, "Stream error: {}", e),
             GemError::FileError(e) => write!(f, "File error: {}", e),
+            GemError::ModelNotAvailable { model } => write!(f, "Model not available: {}", model),
+            GemError::InvalidConfig(e) => write!(f, "Invalid config: {}", e),
+            GemError::InvalidApiKey(status) => write!(
+                f,
+                "The API key was rejected (status code: {}); check GEMINI_API_KEY",
+                status
+            ),
+            GemError::Timeout(timeout) => {
+                write!(f, "No stream chunk received within {:?}", timeout)
+            }
+            GemError::InlineDataTooLarge { size, limit } => write!(
+                f,
+                "Inline data is {} bytes, over the {} byte limit; upload large files with FileManager instead",
+                size, limit
+            ),
+        }
+    }
+}
+
+impl Error for GemError {
+    /// Returns the underlying HTTP, JSON or API error, if the variant wraps one.
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GemError::ConnectionError(e) => Some(e),
+            GemError::ResponseError((e, _)) => Some(e),
+            GemError::GeminiAPIError((e, _)) => Some(e),
+            GemError::ParsingError(e) => Some(e),
+            GemError::UnexpectedResponse { source, .. } => Some(source),
+            _ => None,
         }
     }
 }
 
-impl Error for GemError {}
+impl GemError {
+    /// Returns the HTTP status code of the response that caused the error, if any.
+    pub fn status_code(&self) -> Option<reqwest::StatusCode> {
+        match self {
+            GemError::ResponseError((_, status)) => Some(*status),
+            GemError::GeminiAPIError((_, status)) => Some(*status),
+            GemError::UnexpectedResponse { status, .. } => Some(*status),
+            GemError::InvalidApiKey(status) => Some(*status),
+            _ => None,
+        }
+    }
+}
 
 /// Represents the reason why the Gemini API finished generating content.
 #[derive(Debug)]
@@ -87,3 +176,42 @@ impl FinishReason {
         matches!(self, FinishReason::Safety | FinishReason::Recitation)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gem_error_is_send_sync() {
+        fn assert_error<E: Error + Send + Sync + 'static>() {}
+        assert_error::<GemError>();
+    }
+
+    #[test]
+    fn test_gem_error_source() {
+        let json_error = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let message = json_error.to_string();
+
+        let error = GemError::ParsingError(json_error);
+        assert!(error.to_string().starts_with("Parsing error: "));
+        assert_eq!(error.source().unwrap().to_string(), message);
+
+        let api_error: types::Error = serde_json::from_value(serde_json::json!({
+            "code": 429,
+            "message": "Resource exhausted",
+            "status": "RESOURCE_EXHAUSTED"
+        }))
+        .unwrap();
+        let error = GemError::GeminiAPIError((api_error, reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(error
+            .source()
+            .unwrap()
+            .to_string()
+            .contains("Resource exhausted"));
+
+        let error = GemError::FileError("missing".to_string());
+        assert_eq!(error.to_string(), "File error: missing");
+        assert!(error.source().is_none());
+        assert!(GemError::EmptyApiResponse.source().is_none());
+    }
+}
@@ -0,0 +1,90 @@
+//! Observability hooks for the Gem-rs library.
+//!
+//! This module provides the callbacks that `GemSession` invokes before every request
+//! and after every response, so metrics and logging can be wired in a single place.
+//! The callbacks never receive the API key or the request body.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+use crate::api::Models;
+use crate::types::{FinishReason, GenerateContentResponse, UsageMetadata};
+
+/// Callback invoked before a request is sent.
+pub type RequestHook = Arc<dyn Fn(&RequestInfo) + Send + Sync>;
+
+/// Callback invoked once the response to a request is received.
+pub type ResponseHook = Arc<dyn Fn(&ResponseInfo) + Send + Sync>;
+
+/// Metadata about a request, passed to the `on_request` hook.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    /// Model the request is sent to.
+    pub model: Models,
+    /// Size in bytes of the JSON request body.
+    pub request_size: usize,
+    /// Rough estimate of the prompt tokens, at about four characters of text per token.
+    pub estimated_tokens: usize,
+    /// Whether the response is streamed.
+    pub streaming: bool,
+}
+
+/// Metadata about a response, passed to the `on_response` hook.
+///
+/// For streamed requests the hook fires when the response headers arrive, so the usage
+/// and finish reason are not known yet.
+#[derive(Debug, Clone)]
+pub struct ResponseInfo {
+    /// Model the request was sent to.
+    pub model: Models,
+    /// HTTP status code of the response.
+    pub status: StatusCode,
+    /// Time elapsed between sending the request and receiving the response.
+    pub latency: Duration,
+    /// Token usage reported by the API, if any.
+    pub usage: Option<UsageMetadata>,
+    /// Finish reason of the first candidate, if any.
+    pub finish_reason: Option<FinishReason>,
+}
+
+impl ResponseInfo {
+    pub(crate) fn new(
+        model: &Models,
+        status: StatusCode,
+        latency: Duration,
+        response: Option<&GenerateContentResponse>,
+    ) -> Self {
+        ResponseInfo {
+            model: model.clone(),
+            status,
+            latency,
+            usage: response.and_then(|response| response.get_usage_metadata().cloned()),
+            finish_reason: response
+                .and_then(|response| response.get_candidates().first())
+                .and_then(|candidate| candidate.get_finish_reason()),
+        }
+    }
+}
+
+/// The hooks registered on a session.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub on_request: Option<RequestHook>,
+    pub on_response: Option<ResponseHook>,
+}
+
+impl Hooks {
+    pub fn request(&self, info: RequestInfo) {
+        if let Some(hook) = &self.on_request {
+            hook(&info);
+        }
+    }
+
+    pub fn response(&self, info: ResponseInfo) {
+        if let Some(hook) = &self.on_response {
+            hook(&info);
+        }
+    }
+}
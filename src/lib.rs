@@ -17,6 +17,10 @@
 //! - `api`: Contains API-related constants and model definitions
 //! - `client`: Provides the main client interface for interacting with the Gemini API
 //! - `errors`: Defines custom error types for the library
+//! - `hooks`: Defines the request and response callbacks used for observability
+//! - `rate_limit`: Provides the token bucket used to limit requests per minute
+//! - `retry`: Defines the retry policy used for failed requests
+//! - `stream`: Provides the stream adapter returned by streaming requests
 //! - `types`: Contains various type definitions used throughout the library
 //! - `utils`: Utility functions for internal use
 
@@ -25,6 +29,12 @@ use std::env;
 pub mod api;
 pub mod client;
 pub mod errors;
+pub mod hooks;
+#[cfg(test)]
+mod mock;
+pub mod rate_limit;
+pub mod retry;
+pub mod stream;
 pub mod types;
 pub mod utils;
 
@@ -48,4 +58,4 @@ pub fn init_log() {
     pretty_env_logger::init();
     env::set_var("RUST_LOG", "info");
     log::info!("Logger initialized");
-}
\ No newline at end of file
+}
@@ -0,0 +1,215 @@
+//! Minimal HTTP server used by the unit tests to stand in for the Gemini API.
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A canned response returned by `MockServer`.
+#[derive(Debug, Clone)]
+pub(crate) struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub hold_open: bool,
+    pub parts: Vec<String>,
+    pub part_delay: Duration,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: &str) -> Self {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+            hold_open: false,
+            parts: Vec::new(),
+            part_delay: Duration::ZERO,
+        }
+    }
+
+    /// Sends the body in `parts` without a length, waiting `delay` before each part.
+    pub fn streamed(status: u16, parts: &[&str], delay: Duration) -> Self {
+        MockResponse {
+            parts: parts.iter().map(|part| part.to_string()).collect(),
+            part_delay: delay,
+            ..MockResponse::new(status, "")
+        }
+    }
+
+    /// Sends the body without a length and keeps the connection open until the client closes it.
+    pub fn hold_open(mut self) -> Self {
+        self.hold_open = true;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// A request received by `MockServer`.
+#[derive(Debug, Clone)]
+pub(crate) struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Serves the queued responses in order, one per connection, and records every request.
+///
+/// Once the queue is exhausted the last response is repeated.
+pub(crate) struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    disconnects: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    pub async fn start(responses: Vec<MockResponse>) -> Self {
+        Self::start_with(|_| responses).await
+    }
+
+    /// Like `start`, but builds the responses from the server's own URL, e.g. for redirects.
+    pub async fn start_with(responses: impl FnOnce(&str) -> Vec<MockResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let disconnects = Arc::new(AtomicUsize::new(0));
+        let mut responses: VecDeque<MockResponse> = responses(&url).into();
+
+        let recorded = requests.clone();
+        let disconnected = disconnects.clone();
+        tokio::spawn(async move {
+            let mut last: Option<MockResponse> = None;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let request = match read_request(&mut socket).await {
+                    Some(request) => request,
+                    None => continue,
+                };
+                recorded.lock().unwrap().push(request);
+
+                let response = match responses.pop_front() {
+                    Some(response) => response,
+                    None => match &last {
+                        Some(response) => response.clone(),
+                        None => MockResponse::new(500, ""),
+                    },
+                };
+                let _ = socket.write_all(&encode_response(&response)).await;
+                for part in &response.parts {
+                    tokio::time::sleep(response.part_delay).await;
+                    if socket.write_all(part.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+                if response.hold_open {
+                    let mut chunk = [0u8; 1024];
+                    while matches!(socket.read(&mut chunk).await, Ok(read) if read > 0) {}
+                    disconnected.fetch_add(1, Ordering::SeqCst);
+                }
+                let _ = socket.shutdown().await;
+                last = Some(response);
+            }
+        });
+
+        MockServer {
+            url,
+            requests,
+            disconnects,
+        }
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Returns how many clients closed a `hold_open` connection.
+    pub fn disconnects(&self) -> usize {
+        self.disconnects.load(Ordering::SeqCst)
+    }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> Option<MockRequest> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    while buffer.len() < header_end + content_length {
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    Some(MockRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&buffer[header_end..]).to_string(),
+    })
+}
+
+fn encode_response(response: &MockResponse) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {} Mock\r\nConnection: close\r\n", response.status);
+    if !response.hold_open && response.parts.is_empty() {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    if !response
+        .headers
+        .iter()
+        .any(|(key, _)| key.eq_ignore_ascii_case("content-type"))
+    {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    for (key, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str("\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(response.body.as_bytes());
+    bytes
+}
@@ -0,0 +1,101 @@
+//! Client-side rate limiting for the Gem-rs library.
+//!
+//! This module provides the token bucket used by `GemSession` to keep the number of
+//! requests under a per-minute limit. Requests wait for a token instead of failing
+//! with a 429 once the limit is reached.
+
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// A token bucket allowing a number of requests per minute.
+///
+/// The bucket starts full and is refilled continuously, so a burst of up to the limit
+/// goes out at once and later requests are spread evenly over the minute. Waiting
+/// requests are served in order.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    tokens_per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `requests` requests per minute.
+    pub fn per_minute(requests: u32) -> Self {
+        let capacity = requests.max(1) as f64;
+        Self::with_rate(capacity, capacity / 60.0)
+    }
+
+    /// Returns the number of requests allowed per minute.
+    pub fn requests_per_minute(&self) -> u32 {
+        self.capacity as u32
+    }
+
+    fn with_rate(capacity: f64, tokens_per_second: f64) -> Self {
+        RateLimiter {
+            capacity,
+            tokens_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent and takes its token.
+    pub async fn acquire(&self) {
+        // The lock is held while waiting, so waiting requests are served in order.
+        let mut bucket = self.bucket.lock().await;
+        self.refill(&mut bucket);
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / self.tokens_per_second;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+            self.refill(&mut bucket);
+        }
+        bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.tokens_per_second).min(self.capacity);
+        bucket.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter_waits_for_tokens() {
+        // Two requests at once, then one every 50ms.
+        let limiter = RateLimiter::with_rate(2.0, 20.0);
+        let started = Instant::now();
+
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(started.elapsed() < Duration::from_millis(40));
+
+        limiter.acquire().await;
+        assert!(started.elapsed() >= Duration::from_millis(45));
+
+        limiter.acquire().await;
+        assert!(started.elapsed() >= Duration::from_millis(95));
+    }
+
+    #[test]
+    fn test_per_minute_rate() {
+        let limiter = RateLimiter::per_minute(120);
+        assert_eq!(limiter.capacity, 120.0);
+        assert_eq!(limiter.tokens_per_second, 2.0);
+    }
+}
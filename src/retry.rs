@@ -0,0 +1,193 @@
+//! Retry support for the Gem-rs library.
+//!
+//! This module provides the `RetryPolicy` used by `GemSession` to retry failed
+//! requests with exponential backoff. Which errors are retried is decided by a
+//! predicate, which defaults to `is_transient`.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::GemError;
+
+/// Predicate deciding whether a failed request should be retried.
+pub type RetryPredicate = Arc<dyn Fn(&GemError) -> bool + Send + Sync>;
+
+/// Controls how failed requests are retried.
+///
+/// Serializing a policy leaves out `retry_if`, which can't be saved.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt. `0` disables retries.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every following retry.
+    pub base_delay: Duration,
+    /// Whether to add a random delay of up to `base_delay` to every retry.
+    pub jitter: bool,
+    /// Longest delay before a retry, also capping a `Retry-After` delay sent by the API.
+    pub max_delay: Duration,
+    /// Decides which errors are retried. `is_transient` is used when `None`.
+    #[serde(skip)]
+    pub retry_if: Option<RetryPredicate>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::from_millis(500),
+            jitter: true,
+            max_delay: Duration::from_secs(60),
+            retry_if: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("base_delay", &self.base_delay)
+            .field("jitter", &self.jitter)
+            .field("max_delay", &self.max_delay)
+            .field("retry_if", &self.retry_if.as_ref().map(|_| "custom"))
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    /// Returns `true` if a request that failed with `error` on attempt `attempt`
+    /// (starting at 0) should be retried.
+    pub fn should_retry(&self, error: &GemError, attempt: u32) -> bool {
+        if attempt >= self.max_retries {
+            return false;
+        }
+        match &self.retry_if {
+            Some(predicate) => predicate(error),
+            None => is_transient(error),
+        }
+    }
+
+    /// Returns the delay before retrying a request that failed with `error`.
+    ///
+    /// A `Retry-After` delay sent by the API takes precedence over the backoff. Either is
+    /// capped at `max_delay`.
+    pub fn delay_for(&self, error: &GemError, attempt: u32) -> Duration {
+        let delay = match error {
+            GemError::GeminiAPIError((e, _)) => match e.get_retry_after() {
+                Some(retry_after) => retry_after,
+                None => self.delay(attempt),
+            },
+            _ => self.delay(attempt),
+        };
+        delay.min(self.max_delay)
+    }
+
+    /// Returns the delay before retry number `attempt` (starting at 0).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        if !self.jitter || self.base_delay.is_zero() {
+            return backoff;
+        }
+
+        // Cheap source of randomness; it only has to spread out concurrent retries.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos() as u64)
+            .unwrap_or_default();
+        let jitter = nanos % (self.base_delay.as_millis() as u64 + 1);
+        backoff + Duration::from_millis(jitter)
+    }
+}
+
+/// Returns `true` for errors that are usually resolved by retrying: rate limiting (429),
+/// an overloaded service (503), and timeouts.
+pub fn is_transient(error: &GemError) -> bool {
+    match error {
+        GemError::ConnectionError(e) => e.is_timeout(),
+        GemError::ResponseError((e, _)) if e.is_timeout() => true,
+        _ => matches!(
+            error.status_code(),
+            Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            jitter: false,
+            max_delay: Duration::from_secs(10),
+            retry_if: None,
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        let delay = policy.delay(1);
+        assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            ..Default::default()
+        };
+        assert!(!policy.should_retry(&GemError::EmptyApiResponse, 0));
+
+        let policy = RetryPolicy {
+            retry_if: Some(Arc::new(|error| {
+                matches!(error, GemError::EmptyApiResponse)
+            })),
+            ..policy
+        };
+        assert!(policy.should_retry(&GemError::EmptyApiResponse, 1));
+        assert!(!policy.should_retry(&GemError::EmptyApiResponse, 2));
+    }
+
+    #[test]
+    fn test_delay_honors_retry_after() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            jitter: false,
+            max_delay: Duration::from_secs(10),
+            retry_if: None,
+        };
+        let error: crate::types::Error = serde_json::from_value(serde_json::json!({
+            "code": 429,
+            "message": "Resource exhausted",
+            "status": "RESOURCE_EXHAUSTED"
+        }))
+        .unwrap();
+
+        let without = GemError::GeminiAPIError((error.clone(), StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(policy.delay_for(&without, 1), Duration::from_millis(200));
+
+        let with = GemError::GeminiAPIError((
+            error.clone().with_retry_after(Some(Duration::from_secs(4))),
+            StatusCode::TOO_MANY_REQUESTS,
+        ));
+        assert_eq!(policy.delay_for(&with, 1), Duration::from_secs(4));
+
+        // A server asking for an hour doesn't stall the caller past `max_delay`.
+        let long = GemError::GeminiAPIError((
+            error.with_retry_after(Some(Duration::from_secs(3600))),
+            StatusCode::TOO_MANY_REQUESTS,
+        ));
+        assert_eq!(policy.delay_for(&long, 1), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,801 @@
+//! Streaming support for the Gem-rs library.
+//!
+//! This module provides the `ResponseStream` adapter returned by the streaming
+//! methods of `GemSession`. It yields the same chunks as the underlying HTTP
+//! stream while recording data about the stream that callers can inspect once
+//! they are done consuming it.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::{AbortRegistration, Abortable, Pending};
+use futures::{Future, Stream, StreamExt};
+use reqwest_streams::error::{StreamBodyError, StreamBodyKind};
+
+use crate::errors::GemError;
+use crate::types::{
+    Content, Context as GemContext, GenerateContentResponse, Part, PartData, Role, UsageMetadata,
+};
+
+/// Boxed stream of raw response chunks as produced by the HTTP layer.
+pub(crate) type ChunkStream =
+    Box<dyn Stream<Item = Result<GenerateContentResponse, StreamBodyError>> + Unpin>;
+
+/// Parses a body of Server-Sent Events, as sent by `streamGenerateContent?alt=sse`, into
+/// response chunks.
+///
+/// Every event carries one JSON chunk in its `data:` lines, so a chunk is parsed as soon
+/// as its event ends, regardless of how the body is split by the transport. Events larger
+/// than `max_event_size` bytes end the stream with an error.
+pub(crate) fn sse_stream<S, B, E>(body: S, max_event_size: usize) -> ChunkStream
+where
+    S: Stream<Item = Result<B, E>> + Unpin + 'static,
+    B: AsRef<[u8]>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    struct State<S> {
+        body: S,
+        buffer: Vec<u8>,
+        data: String,
+        ended: bool,
+    }
+
+    fn stream_error(
+        kind: StreamBodyKind,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+        message: &str,
+    ) -> StreamBodyError {
+        StreamBodyError::new(kind, source, Some(message.to_string()))
+    }
+
+    let state = State {
+        body,
+        buffer: Vec::new(),
+        data: String::new(),
+        ended: false,
+    };
+    let stream = futures::stream::unfold(state, move |mut state| async move {
+        loop {
+            while let Some(end) = state.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = state.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\r', '\n']);
+
+                if line.is_empty() {
+                    // A blank line dispatches the event.
+                    if state.data.is_empty() {
+                        continue;
+                    }
+                    let data = std::mem::take(&mut state.data);
+                    let chunk =
+                        serde_json::from_str::<GenerateContentResponse>(&data).map_err(|e| {
+                            stream_error(
+                                StreamBodyKind::CodecError,
+                                Some(Box::new(e)),
+                                "invalid JSON in event",
+                            )
+                        });
+                    return Some((chunk, state));
+                }
+
+                // Other fields (event, id, retry) and comments are ignored.
+                if let Some(data) = line.strip_prefix("data:") {
+                    if !state.data.is_empty() {
+                        state.data.push('\n');
+                    }
+                    state.data.push_str(data.strip_prefix(' ').unwrap_or(data));
+                }
+            }
+
+            if state.ended {
+                return None;
+            }
+            if state.buffer.len() + state.data.len() > max_event_size {
+                state.ended = true;
+                state.buffer.clear();
+                state.data.clear();
+                let error = stream_error(
+                    StreamBodyKind::MaxLenReachedError,
+                    None,
+                    "event exceeds the maximum size",
+                );
+                return Some((Err(error), state));
+            }
+
+            match state.body.next().await {
+                Some(Ok(bytes)) => state.buffer.extend_from_slice(bytes.as_ref()),
+                Some(Err(e)) => {
+                    state.ended = true;
+                    state.buffer.clear();
+                    state.data.clear();
+                    let error = stream_error(
+                        StreamBodyKind::InputOutputError,
+                        Some(Box::new(e)),
+                        "failed to read the response body",
+                    );
+                    return Some((Err(error), state));
+                }
+                None => {
+                    // Dispatch a last event that isn't followed by a blank line.
+                    state.ended = true;
+                    state.buffer.extend_from_slice(b"\n\n");
+                }
+            }
+        }
+    });
+    Box::new(Box::pin(stream))
+}
+
+/// Predicate evaluated against the accumulated text of a stream; returning `true` ends the stream.
+pub type StreamStopPredicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Data collected while a `ResponseStream` is being consumed.
+#[derive(Debug, Clone, Default)]
+pub struct StreamSummary {
+    time_to_first_token: Option<Duration>,
+    stopped_early: bool,
+    cancelled: bool,
+    usage_metadata: Option<UsageMetadata>,
+}
+
+impl StreamSummary {
+    /// Returns the time elapsed between sending the request and receiving the first chunk.
+    ///
+    /// This is `None` until the first chunk has been yielded by the stream.
+    pub fn get_time_to_first_token(&self) -> Option<Duration> {
+        self.time_to_first_token
+    }
+
+    /// Returns `true` if the stream was ended by the stop predicate set in `Settings`.
+    pub fn was_stopped_early(&self) -> bool {
+        self.stopped_early
+    }
+
+    /// Returns `true` if the stream was ended through the `AbortHandle` passed to `abort_on`.
+    pub fn was_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Returns the usage metadata of the last chunk that carried any.
+    ///
+    /// The API sends the totals for the whole response with the final chunk, so this is
+    /// complete once the stream has been consumed.
+    pub fn get_usage_metadata(&self) -> Option<&UsageMetadata> {
+        self.usage_metadata.as_ref()
+    }
+
+    /// Returns the total token count of the response, once it has been received.
+    pub fn get_total_token_count(&self) -> Option<i32> {
+        self.usage_metadata
+            .as_ref()
+            .and_then(|usage| usage.get_total_token_count())
+    }
+}
+
+/// Error returned when a stream fails after some text was already received.
+#[derive(Debug)]
+pub struct PartialStreamError {
+    /// The text received before the stream failed.
+    pub partial: String,
+    /// The error that interrupted the stream.
+    pub error: GemError,
+}
+
+impl std::fmt::Display for PartialStreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (after {} bytes of partial text)",
+            self.error,
+            self.partial.len()
+        )
+    }
+}
+
+impl std::error::Error for PartialStreamError {}
+
+/// A stream of `GenerateContentResponse` chunks returned by the Gemini API.
+///
+/// `ResponseStream` can be consumed like any other `Stream`. Information about the
+/// stream, such as the time to first token, is available through `summary`.
+///
+/// When returned by a `GemSession`, the parts of the first candidate, with consecutive text
+/// merged, are recorded in the session context as a `Role::Model` turn once the stream
+/// completes. Nothing is recorded if the stream fails midway or is dropped before it completes.
+///
+/// Dropping the stream before it completes aborts the request: the HTTP response body is
+/// dropped with it and its connection is closed rather than returned to the pool. To stop
+/// a stream consumed by another task, pass an `AbortRegistration` to `abort_on`.
+pub struct ResponseStream<'a> {
+    inner: ChunkStream,
+    started: Instant,
+    summary: StreamSummary,
+    text: String,
+    parts: Vec<Part>,
+    stop_when: Option<StreamStopPredicate>,
+    abort: Option<Abortable<Pending<()>>>,
+    chunk_timeout: Option<Duration>,
+    chunk_deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+    timed_out: bool,
+    finished: bool,
+    failed: bool,
+    record_to: Option<&'a mut GemContext>,
+}
+
+impl<'a> ResponseStream<'a> {
+    /// Wraps a chunk stream whose request was sent at `started`.
+    pub(crate) fn new(inner: ChunkStream, started: Instant) -> Self {
+        ResponseStream {
+            inner,
+            started,
+            summary: StreamSummary::default(),
+            text: String::new(),
+            parts: Vec::new(),
+            stop_when: None,
+            abort: None,
+            chunk_timeout: None,
+            chunk_deadline: None,
+            timed_out: false,
+            finished: false,
+            failed: false,
+            record_to: None,
+        }
+    }
+
+    /// Sets the predicate that ends the stream once it matches the accumulated text.
+    pub(crate) fn stop_when(mut self, predicate: Option<StreamStopPredicate>) -> Self {
+        self.stop_when = predicate;
+        self
+    }
+
+    /// Sets the longest gap allowed between chunks, starting now for the first chunk.
+    pub(crate) fn chunk_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.chunk_timeout = timeout;
+        self.chunk_deadline = timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+        self
+    }
+
+    /// Sets the context the accumulated parts are recorded in once the stream completes.
+    pub(crate) fn record_to<'b>(self, context: &'b mut GemContext) -> ResponseStream<'b> {
+        ResponseStream {
+            inner: self.inner,
+            started: self.started,
+            summary: self.summary,
+            text: self.text,
+            parts: self.parts,
+            stop_when: self.stop_when,
+            abort: self.abort,
+            chunk_timeout: self.chunk_timeout,
+            chunk_deadline: self.chunk_deadline,
+            timed_out: self.timed_out,
+            finished: self.finished,
+            failed: self.failed,
+            record_to: Some(context),
+        }
+    }
+
+    /// Ends the stream as soon as the `AbortHandle` paired with `registration` is aborted,
+    /// e.g. from another task when a user clicks stop.
+    ///
+    /// The HTTP response is dropped, closing its connection, and the partial text is not
+    /// recorded in the session context.
+    pub fn abort_on(mut self, registration: AbortRegistration) -> Self {
+        self.abort = Some(Abortable::new(futures::future::pending(), registration));
+        self
+    }
+
+    /// Returns the data collected so far while consuming the stream.
+    pub fn summary(&self) -> &StreamSummary {
+        &self.summary
+    }
+
+    /// Returns the text of the first candidate accumulated from the chunks received so far.
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+
+    /// Consumes the rest of the stream and returns the full text of the first candidate.
+    ///
+    /// If the stream fails midway, the text received up to that point is returned along
+    /// with the error.
+    pub async fn collect_text(&mut self) -> Result<String, PartialStreamError> {
+        while let Some(chunk) = self.next().await {
+            if let Err(e) = chunk {
+                return Err(PartialStreamError {
+                    partial: self.text.clone(),
+                    error: self.gem_error(e),
+                });
+            }
+        }
+        Ok(self.text.clone())
+    }
+
+    /// Converts an error yielded by the stream, keeping chunk timeouts distinguishable.
+    fn gem_error(&self, error: StreamBodyError) -> GemError {
+        match self.chunk_timeout {
+            Some(timeout) if self.timed_out => GemError::Timeout(timeout),
+            _ => GemError::StreamError(error.to_string()),
+        }
+    }
+
+    /// Returns an error once the chunk deadline has passed.
+    fn poll_chunk_deadline(&mut self, cx: &mut Context<'_>) -> Option<StreamBodyError> {
+        let deadline = self.chunk_deadline.as_mut()?;
+        if deadline.as_mut().poll(cx).is_pending() {
+            return None;
+        }
+        self.timed_out = true;
+        self.failed = true;
+        self.finish();
+        Some(StreamBodyError::new(
+            StreamBodyKind::InputOutputError,
+            None,
+            Some(format!(
+                "no chunk received within {:?}",
+                self.chunk_timeout.unwrap_or_default()
+            )),
+        ))
+    }
+
+    /// Ends the stream, drops the underlying HTTP response and records the parts, unless
+    /// the stream failed or was cancelled.
+    fn finish(&mut self) {
+        self.finished = true;
+        self.inner = Box::new(futures::stream::empty());
+        self.abort = None;
+        self.chunk_deadline = None;
+        if let Some(context) = self.record_to.take() {
+            if !self.failed && !self.summary.cancelled && !self.parts.is_empty() {
+                context.push_content(Content {
+                    role: Some(Role::Model),
+                    parts: std::mem::take(&mut self.parts),
+                });
+            }
+        }
+    }
+}
+
+/// Appends the parts of a chunk's first candidate to `parts`, extending the last part when
+/// both are text of the same kind, since streamed text arrives split across chunks.
+fn merge_parts(parts: &mut Vec<Part>, chunk: &GenerateContentResponse) {
+    let Some(content) = chunk
+        .get_candidates()
+        .first()
+        .and_then(|candidate| candidate.get_content())
+    else {
+        return;
+    };
+    for part in &content.parts {
+        if let (Some(last), PartData::Text { text }) = (parts.last_mut(), &part.data) {
+            if let PartData::Text { text: last_text } = &mut last.data {
+                if last.thought == part.thought {
+                    last_text.push_str(text);
+                    continue;
+                }
+            }
+        }
+        parts.push(part.clone());
+    }
+}
+
+/// Returns the text of the first candidate in a chunk, if any.
+pub(crate) fn chunk_text(chunk: &GenerateContentResponse) -> Option<String> {
+    chunk
+        .get_candidates()
+        .first()
+        .and_then(|candidate| candidate.get_content())
+        .and_then(|content| content.get_text())
+}
+
+/// A stream of the text deltas of the first candidate, returned by
+/// `GemSession::send_message_text_stream`.
+///
+/// Chunks without text are skipped. Like `ResponseStream`, the full reply is recorded in the
+/// session context once the stream completes.
+pub struct TextStream<'a> {
+    inner: ResponseStream<'a>,
+}
+
+impl<'a> TextStream<'a> {
+    /// Wraps a response stream, yielding only the text of its chunks.
+    pub(crate) fn new(inner: ResponseStream<'a>) -> Self {
+        TextStream { inner }
+    }
+
+    /// Ends the stream as soon as the `AbortHandle` paired with `registration` is aborted.
+    ///
+    /// See `ResponseStream::abort_on`.
+    pub fn abort_on(self, registration: AbortRegistration) -> Self {
+        TextStream {
+            inner: self.inner.abort_on(registration),
+        }
+    }
+
+    /// Returns the data collected so far while consuming the stream.
+    pub fn summary(&self) -> &StreamSummary {
+        self.inner.summary()
+    }
+
+    /// Returns the text accumulated from the chunks received so far.
+    pub fn get_text(&self) -> &str {
+        self.inner.get_text()
+    }
+}
+
+impl Stream for TextStream<'_> {
+    type Item = Result<String, GemError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => match chunk_text(&chunk) {
+                    Some(text) if !text.is_empty() => return Poll::Ready(Some(Ok(text))),
+                    _ => continue,
+                },
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(Some(Err(self.inner.gem_error(e))))
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Stream for ResponseStream<'_> {
+    type Item = Result<GenerateContentResponse, StreamBodyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+
+        // The wrapped future never completes, so it is only ready once aborted.
+        if let Some(abort) = &mut self.abort {
+            if Pin::new(abort).poll(cx).is_ready() {
+                self.summary.cancelled = true;
+                self.finish();
+                return Poll::Ready(None);
+            }
+        }
+
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if poll.is_pending() {
+            if let Some(error) = self.poll_chunk_deadline(cx) {
+                return Poll::Ready(Some(Err(error)));
+            }
+        }
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let (Some(timeout), Some(deadline)) =
+                    (self.chunk_timeout, self.chunk_deadline.as_mut())
+                {
+                    deadline
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + timeout);
+                }
+                if self.summary.time_to_first_token.is_none() {
+                    self.summary.time_to_first_token = Some(self.started.elapsed());
+                }
+                if let Some(text) = chunk_text(chunk) {
+                    self.text.push_str(&text);
+                }
+                merge_parts(&mut self.parts, chunk);
+                if let Some(usage) = chunk.get_usage_metadata() {
+                    self.summary.usage_metadata = Some(usage.clone());
+                }
+                let stop = match &self.stop_when {
+                    Some(predicate) => predicate(&self.text),
+                    None => false,
+                };
+                if stop {
+                    self.summary.stopped_early = true;
+                    self.finish();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.failed = true,
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest_streams::error::StreamBodyKind;
+
+    use super::*;
+
+    fn chunk(text: &str) -> GenerateContentResponse {
+        serde_json::from_value(serde_json::json!({
+            "candidates": [
+                { "content": { "parts": [{ "text": text }], "role": "model" } }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_time_to_first_token_recorded() {
+        let started = Instant::now();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let chunks = futures::stream::iter(vec![Ok(chunk("Hello")), Ok(chunk(" world"))]);
+        let mut stream = ResponseStream::new(Box::new(chunks), started);
+        assert!(stream.summary().get_time_to_first_token().is_none());
+
+        while let Some(response) = stream.next().await {
+            assert!(response.is_ok());
+        }
+
+        let ttft = stream.summary().get_time_to_first_token().unwrap();
+        assert!(ttft >= Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn test_stop_when_predicate() {
+        let chunks = futures::stream::iter(vec![
+            Ok(chunk("<answer>")),
+            Ok(chunk("42")),
+            Ok(chunk("</answer>")),
+            Ok(chunk(" and some trailing text")),
+        ]);
+        let mut stream = ResponseStream::new(Box::new(chunks), Instant::now())
+            .stop_when(Some(Arc::new(|text: &str| text.contains("</answer>"))));
+
+        let mut received = 0;
+        while let Some(response) = stream.next().await {
+            assert!(response.is_ok());
+            received += 1;
+        }
+
+        assert_eq!(received, 3);
+        assert_eq!(stream.get_text(), "<answer>42</answer>");
+        assert!(stream.summary().was_stopped_early());
+    }
+
+    #[tokio::test]
+    async fn test_collect_text_keeps_partial_on_error() {
+        let chunks = futures::stream::iter(vec![
+            Ok(chunk("The quick ")),
+            Ok(chunk("brown fox")),
+            Err(StreamBodyError::new(
+                StreamBodyKind::InputOutputError,
+                None,
+                Some("connection reset".to_string()),
+            )),
+            Ok(chunk(" jumps")),
+        ]);
+        let mut stream = ResponseStream::new(Box::new(chunks), Instant::now());
+
+        let error = stream.collect_text().await.unwrap_err();
+        assert_eq!(error.partial, "The quick brown fox");
+        match error.error {
+            GemError::StreamError(message) => assert!(message.contains("connection reset")),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_text() {
+        let chunks = futures::stream::iter(vec![Ok(chunk("Hello")), Ok(chunk(" world"))]);
+        let mut stream = ResponseStream::new(Box::new(chunks), Instant::now());
+        assert_eq!(stream.collect_text().await.unwrap(), "Hello world");
+    }
+
+    #[tokio::test]
+    async fn test_text_stream_records_turn() {
+        let chunks =
+            futures::stream::iter(vec![Ok(chunk("Hello")), Ok(chunk("")), Ok(chunk(" world"))]);
+        let mut context = GemContext::new();
+        context.push_message(Role::User, "Hi".to_string());
+
+        let mut stream = TextStream::new(
+            ResponseStream::new(Box::new(chunks), Instant::now()).record_to(&mut context),
+        );
+        let mut deltas = Vec::new();
+        while let Some(delta) = stream.next().await {
+            deltas.push(delta.unwrap());
+        }
+        assert_eq!(deltas, vec!["Hello", " world"]);
+
+        assert_eq!(context.len(), 2);
+        let turn = &context.get_contents()[1];
+        assert_eq!(turn.role, Some(Role::Model));
+        assert_eq!(turn.get_text().as_deref(), Some("Hello world"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_records_function_calls() {
+        let call: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "parts": [
+                { "functionCall": { "name": "get_weather", "args": {} } }
+            ], "role": "model" } }]
+        }))
+        .unwrap();
+        let chunks =
+            futures::stream::iter(vec![Ok(chunk("Let me ")), Ok(chunk("check.")), Ok(call)]);
+        let mut context = GemContext::new();
+        let mut stream =
+            ResponseStream::new(Box::new(chunks), Instant::now()).record_to(&mut context);
+        assert_eq!(stream.collect_text().await.unwrap(), "Let me check.");
+        drop(stream);
+
+        let turn = &context.get_contents()[0];
+        assert_eq!(turn.role, Some(Role::Model));
+        assert_eq!(turn.parts.len(), 2);
+        assert_eq!(turn.get_text().as_deref(), Some("Let me check."));
+        assert!(matches!(turn.parts[1].data, PartData::FunctionCall { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_failed_stream_not_recorded() {
+        let chunks = futures::stream::iter(vec![
+            Ok(chunk("Half a")),
+            Err(StreamBodyError::new(
+                StreamBodyKind::InputOutputError,
+                None,
+                Some("connection reset".to_string()),
+            )),
+        ]);
+        let mut context = GemContext::new();
+        context.push_message(Role::User, "Hi".to_string());
+
+        let mut stream =
+            ResponseStream::new(Box::new(chunks), Instant::now()).record_to(&mut context);
+        assert!(stream.collect_text().await.is_err());
+        while stream.next().await.is_some() {}
+        drop(stream);
+
+        assert_eq!(context.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_stream_recorded() {
+        let chunks = futures::stream::iter(vec![Ok(chunk("Yes.")), Ok(chunk(" Also..."))]);
+        let mut context = GemContext::new();
+
+        let mut stream = ResponseStream::new(Box::new(chunks), Instant::now())
+            .stop_when(Some(Arc::new(|text: &str| text.ends_with('.'))))
+            .record_to(&mut context);
+        assert_eq!(stream.collect_text().await.unwrap(), "Yes.");
+        drop(stream);
+
+        assert_eq!(
+            context.get_contents()[0].get_text().as_deref(),
+            Some("Yes.")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aborted_stream_not_recorded() {
+        let chunks =
+            futures::stream::iter(vec![Ok(chunk("Once upon"))]).chain(futures::stream::pending());
+        let mut context = GemContext::new();
+        context.push_message(Role::User, "Tell me a story".to_string());
+        let (handle, registration) = futures::future::AbortHandle::new_pair();
+
+        let mut stream = ResponseStream::new(Box::new(chunks), Instant::now())
+            .abort_on(registration)
+            .record_to(&mut context);
+        assert!(stream.next().await.unwrap().is_ok());
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            handle.abort();
+        });
+        let next = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("the stream did not end after being aborted");
+        assert!(next.is_none());
+        assert!(stream.summary().was_cancelled());
+        assert_eq!(stream.get_text(), "Once upon");
+        drop(stream);
+
+        assert_eq!(context.len(), 1);
+    }
+
+    fn sse_body(
+        parts: &[&str],
+    ) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>> + Unpin + 'static {
+        let parts: Vec<Result<Vec<u8>, std::io::Error>> = parts
+            .iter()
+            .map(|part| Ok(part.as_bytes().to_vec()))
+            .collect();
+        futures::stream::iter(parts)
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream_split_chunks() {
+        let first =
+            r#"{"candidates": [{"content": {"parts": [{"text": "Hello"}], "role": "model"}}]}"#;
+        let second =
+            r#"{"candidates": [{"content": {"parts": [{"text": " world"}], "role": "model"}}]}"#;
+        let body = format!("data: {}\r\n\r\n: keep-alive\n\ndata: {}", first, second);
+        // Split in the middle of the `data:` prefix and of the JSON objects.
+        let parts = [
+            &body[..3],
+            &body[3..40],
+            &body[40..first.len() + 12],
+            &body[first.len() + 12..],
+        ];
+
+        let mut stream = ResponseStream::new(sse_stream(sse_body(&parts), 16384), Instant::now());
+        assert_eq!(stream.collect_text().await.unwrap(), "Hello world");
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream_multiline_data_and_errors() {
+        let body =
+            "data: {\"candidates\":\ndata: [{\"content\": {\"parts\": [{\"text\": \"Hi\"}]}}]}\n\n";
+        let mut stream = sse_stream(sse_body(&[body]), 16384);
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(chunk_text(&chunk).as_deref(), Some("Hi"));
+        assert!(stream.next().await.is_none());
+
+        let mut stream = sse_stream(sse_body(&["data: {not json}\n\n"]), 16384);
+        assert!(stream.next().await.unwrap().is_err());
+
+        let mut stream = sse_stream(sse_body(&["data: {\"candidates\": []"]), 8);
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_summary_keeps_last_usage_metadata() {
+        let last: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [
+                { "content": { "parts": [{ "text": "!" }], "role": "model" } }
+            ],
+            "usageMetadata": {
+                "promptTokenCount": 4,
+                "candidatesTokenCount": 3,
+                "totalTokenCount": 7
+            }
+        }))
+        .unwrap();
+        let chunks = futures::stream::iter(vec![Ok(chunk("Hello")), Ok(last), Ok(chunk(""))]);
+        let mut stream = ResponseStream::new(Box::new(chunks), Instant::now());
+
+        stream.collect_text().await.unwrap();
+        assert_eq!(stream.summary().get_total_token_count(), Some(7));
+        assert_eq!(
+            stream
+                .summary()
+                .get_usage_metadata()
+                .unwrap()
+                .get_prompt_token_count(),
+            Some(4)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunk_timeout() {
+        // Two chunks 20ms apart, then a stall longer than the timeout.
+        let chunks = futures::stream::iter(vec![chunk("Hello"), chunk(" world"), chunk("!")])
+            .enumerate()
+            .then(|(i, chunk)| async move {
+                let delay = if i == 2 { 500 } else { 20 };
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                Ok(chunk)
+            });
+        let mut stream = TextStream::new(
+            ResponseStream::new(Box::new(Box::pin(chunks)), Instant::now())
+                .chunk_timeout(Some(Duration::from_millis(100))),
+        );
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), "Hello");
+        assert_eq!(stream.next().await.unwrap().unwrap(), " world");
+        match stream.next().await {
+            Some(Err(GemError::Timeout(timeout))) => {
+                assert_eq!(timeout, Duration::from_millis(100))
+            }
+            other => panic!("unexpected item: {:?}", other),
+        }
+        assert!(stream.next().await.is_none());
+        assert_eq!(stream.get_text(), "Hello world");
+    }
+}
@@ -1,21 +1,57 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use base64::{engine::general_purpose, Engine as _};
-use dotenv::dotenv;
-use log::log;
 use reqwest::header;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::sync::Mutex;
 
-use crate::{errors::GemError, utils::get_mime_type};
+use crate::{
+    api::{Models, API_BASE_URL, MAX_INLINE_DATA_SIZE},
+    errors::GemError,
+    stream::StreamStopPredicate,
+    utils::{extract_code_blocks, get_extension, get_mime_type, sniff_mime_type},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged, rename_all = "camelCase")] // Untagged for different types
 pub enum PartData {
-    InlineData { inline_data: Blob },
-    FileData { file_data: FileData },
-    Text { text: String },
+    InlineData {
+        #[serde(alias = "inlineData")]
+        inline_data: Blob,
+    },
+    FileData {
+        #[serde(alias = "fileData")]
+        file_data: FileData,
+    },
+    FunctionCall {
+        #[serde(alias = "functionCall")]
+        function_call: FunctionCall,
+    },
+    FunctionResponse {
+        #[serde(alias = "functionResponse")]
+        function_response: FunctionResponse,
+    },
+    Text {
+        text: String,
+    },
+}
+
+/// Maximum number of characters of text shown by `GenerateContentResponse::debug_summary`.
+const DEBUG_PREVIEW_CHARS: usize = 80;
+
+/// Cuts `text` to `DEBUG_PREVIEW_CHARS` characters, noting how much was left out.
+fn preview(text: &str) -> String {
+    let length = text.chars().count();
+    if length <= DEBUG_PREVIEW_CHARS {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(DEBUG_PREVIEW_CHARS).collect();
+    format!("{}... ({} more chars)", cut, length - DEBUG_PREVIEW_CHARS)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
@@ -24,11 +60,38 @@ pub enum Role {
     Model,
     #[default]
     User,
+    System, // Routed into the system instruction by `Context`, never sent as a turn
+}
+
+impl std::str::FromStr for Role {
+    type Err = GemError;
+
+    /// Parses a role name, ignoring case. Besides the Gemini names, `assistant`, `ai` and
+    /// `bot` map to `Role::Model` and `human` maps to `Role::User`.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "model" | "assistant" | "ai" | "bot" => Ok(Role::Model),
+            "user" | "human" => Ok(Role::User),
+            "system" => Ok(Role::System),
+            _ => Err(GemError::InvalidConfig(format!("unknown role: {:?}", name))),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Model => write!(f, "model"),
+            Role::User => write!(f, "user"),
+            Role::System => write!(f, "system"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")] // Ensure enum variants match the JSON casing
-pub(crate) enum FinishReason {
+#[allow(clippy::enum_variant_names)]
+pub enum FinishReason {
     FinishReasonUnspecified, // Default value. This value is unused.
     Stop,                    // Natural stop point of the model or provided stop sequence.
     MaxTokens,  // The maximum number of tokens as specified in the request was reached.
@@ -48,6 +111,7 @@ pub struct GenerateContentResponse {
     candidates: Vec<Candidate>,
     prompt_feedback: Option<PromptFeedback>, // This is optional
     usage_metadata: Option<UsageMetadata>,   // This is optional
+    model_version: Option<String>,           // Concrete model version that generated the response
 }
 
 impl GenerateContentResponse {
@@ -55,6 +119,70 @@ impl GenerateContentResponse {
         &self.candidates
     }
 
+    /// Reassembles the chunks of a streamed response into a single response.
+    ///
+    /// Candidates are matched by index (or by position when the index is missing) and
+    /// ordered by index regardless of arrival order. Missing indices are filled with empty
+    /// candidates, so a candidate's position always equals its index. Adjacent
+    /// text parts are concatenated, the last finish reason, token count and usage metadata
+    /// win, and safety ratings are unioned by category, keeping the latest rating.
+    pub fn merge(chunks: Vec<GenerateContentResponse>) -> GenerateContentResponse {
+        let mut candidates: Vec<(i32, Candidate)> = Vec::new();
+        let mut prompt_feedback = None;
+        let mut usage_metadata = None;
+        let mut model_version = None;
+
+        for chunk in chunks {
+            prompt_feedback = prompt_feedback.or(chunk.prompt_feedback);
+            usage_metadata = chunk.usage_metadata.or(usage_metadata);
+            model_version = chunk.model_version.or(model_version);
+
+            for (position, candidate) in chunk.candidates.into_iter().enumerate() {
+                let index = candidate.index.unwrap_or(position as i32);
+                match candidates.iter_mut().find(|(i, _)| *i == index) {
+                    Some((_, merged)) => merged.merge(candidate),
+                    None => candidates.push((index, candidate)),
+                }
+            }
+        }
+
+        candidates.sort_by_key(|(index, _)| *index);
+        let mut ordered: Vec<Candidate> = Vec::with_capacity(candidates.len());
+        for (index, mut candidate) in candidates {
+            if index < 0 {
+                continue;
+            }
+            while (ordered.len() as i32) < index {
+                ordered.push(Candidate::empty(ordered.len() as i32));
+            }
+            candidate.index = Some(index);
+            ordered.push(candidate);
+        }
+        GenerateContentResponse {
+            candidates: ordered,
+            prompt_feedback,
+            usage_metadata,
+            model_version,
+        }
+    }
+
+    /// Returns the finish reason of every candidate, ordered by candidate index.
+    ///
+    /// Candidates without an index keep their position in the response.
+    pub fn finish_reasons(&self) -> Vec<Option<FinishReason>> {
+        let mut candidates: Vec<(i32, &Candidate)> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .map(|(position, candidate)| (candidate.index.unwrap_or(position as i32), candidate))
+            .collect();
+        candidates.sort_by_key(|(index, _)| *index);
+        candidates
+            .into_iter()
+            .map(|(_, candidate)| candidate.get_finish_reason())
+            .collect()
+    }
+
     pub fn get_results(&self) -> Vec<String> {
         let mut texts = Vec::new();
         for candidate in &self.candidates {
@@ -67,10 +195,248 @@ impl GenerateContentResponse {
         texts
     }
 
+    /// Like `get_results`, but with the text of every text part of each candidate instead of
+    /// only the first, e.g. when text is interleaved with function calls or code.
+    pub fn get_all_results(&self) -> Vec<String> {
+        self.candidates
+            .iter()
+            .filter_map(|candidate| candidate.get_content())
+            .filter_map(|content| join_text(content.parts.iter()))
+            .collect()
+    }
+
     pub fn get_usage_metadata(&self) -> Option<&UsageMetadata> {
         self.usage_metadata.as_ref()
     }
 
+    /// Returns the concrete model version that served the request, e.g. when an alias was used.
+    pub fn get_model_version(&self) -> Option<&str> {
+        self.model_version.as_deref()
+    }
+
+    /// Returns every part across all candidates that matches `predicate`.
+    pub fn collect_parts<F>(&self, predicate: F) -> Vec<&Part>
+    where
+        F: Fn(&Part) -> bool,
+    {
+        self.candidates
+            .iter()
+            .filter_map(|candidate| candidate.get_content())
+            .flat_map(|content| content.parts.iter())
+            .filter(|part| predicate(part))
+            .collect()
+    }
+
+    /// Deserializes the text of the first candidate, e.g. a response generated with a response schema.
+    ///
+    /// The answer text is parsed as a whole, so the JSON may have any root type, such as an
+    /// array, and may be split across several text parts. Thought parts are ignored.
+    pub fn parse_json<T: DeserializeOwned>(&self) -> Result<T, GemError> {
+        let text = self.final_answer_text().ok_or(GemError::EmptyApiResponse)?;
+        serde_json::from_str(&text).map_err(GemError::ParsingError)
+    }
+
+    /// Returns `true` if no candidate has anything usable: every candidate has no content,
+    /// no parts, or only text parts that are empty or whitespace.
+    pub fn is_effectively_empty(&self) -> bool {
+        self.candidates
+            .iter()
+            .all(|candidate| match candidate.get_content() {
+                Some(content) => content.parts.iter().all(|part| match &part.data {
+                    PartData::Text { text } => text.trim().is_empty(),
+                    _ => false,
+                }),
+                None => true,
+            })
+    }
+
+    /// Returns the answer text of the first candidate, leaving out any thought parts.
+    pub fn final_answer_text(&self) -> Option<String> {
+        self.first_candidate_text(|part| !part.is_thought())
+    }
+
+    /// Returns the candidate the model is most confident in, by average token
+    /// log-probability, when `response_logprobs` was requested for several candidates.
+    ///
+    /// Candidates without log-probabilities rank below all others; on a tie the earlier
+    /// candidate wins. Returns the first candidate if none has log-probabilities.
+    pub fn best_candidate_by_logprob(&self) -> Option<&Candidate> {
+        let mut best: Option<&Candidate> = None;
+        for candidate in &self.candidates {
+            let better = match best {
+                None => true,
+                Some(best) => match (candidate.avg_logprobs, best.avg_logprobs) {
+                    (Some(score), Some(best_score)) => score.total_cmp(&best_score).is_gt(),
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                },
+            };
+            if better {
+                best = Some(candidate);
+            }
+        }
+        best
+    }
+
+    /// Returns the `(language, code)` pairs of the markdown code blocks in the answer text
+    /// of the first candidate. See `utils::extract_code_blocks`.
+    pub fn extract_code_blocks(&self) -> Vec<(Option<String>, String)> {
+        self.final_answer_text()
+            .map(|text| extract_code_blocks(&text))
+            .unwrap_or_default()
+    }
+
+    /// Returns the reasoning text of the first candidate, when thoughts were requested.
+    pub fn thoughts_text(&self) -> Option<String> {
+        self.first_candidate_text(Part::is_thought)
+    }
+
+    /// Concatenates the text parts of the first candidate that match `predicate`.
+    fn first_candidate_text(&self, predicate: impl Fn(&Part) -> bool) -> Option<String> {
+        let content = self
+            .candidates
+            .first()
+            .and_then(|candidate| candidate.get_content())?;
+        join_text(content.parts.iter().filter(|part| predicate(part)))
+    }
+
+    /// Returns a short human-readable summary of the response for logs and bug reports.
+    ///
+    /// Text is cut to a preview and inline data is replaced by its MIME type and size.
+    pub fn debug_summary(&self) -> String {
+        let mut summary = format!("candidates: {}", self.candidates.len());
+        if let Some(model_version) = &self.model_version {
+            summary.push_str(&format!(", model: {}", model_version));
+        }
+        if let Some(block_reason) = self
+            .prompt_feedback
+            .as_ref()
+            .and_then(|feedback| feedback.get_block_reason())
+        {
+            summary.push_str(&format!(", prompt blocked: {:?}", block_reason));
+        }
+        if let Some(usage) = &self.usage_metadata {
+            let count = |count: Option<i32>| count.map_or("-".to_string(), |c| c.to_string());
+            summary.push_str(&format!(
+                "\nusage: prompt {}, candidates {}, total {}",
+                count(usage.prompt_token_count),
+                count(usage.candidates_token_count),
+                count(usage.total_token_count)
+            ));
+        }
+
+        for (position, candidate) in self.candidates.iter().enumerate() {
+            let finish_reason = candidate
+                .get_finish_reason()
+                .map_or("-".to_string(), |reason| format!("{:?}", reason));
+            summary.push_str(&format!(
+                "\n[{}] finish: {}",
+                candidate.index.unwrap_or(position as i32),
+                finish_reason
+            ));
+
+            let parts = candidate
+                .get_content()
+                .map(|content| content.parts.as_slice())
+                .unwrap_or_default();
+            for part in parts {
+                let prefix = if part.is_thought() { "thought " } else { "" };
+                let line = match &part.data {
+                    PartData::Text { text } => format!("{}text: {:?}", prefix, preview(text)),
+                    PartData::InlineData { inline_data } => format!(
+                        "inline data: {} ({} bytes base64)",
+                        inline_data.mime_type,
+                        inline_data.data.len()
+                    ),
+                    PartData::FileData { file_data } => {
+                        format!("file: {} ({})", file_data.file_uri, file_data.mime_type)
+                    }
+                    PartData::FunctionCall { function_call } => {
+                        format!("function call: {}", function_call.name)
+                    }
+                    PartData::FunctionResponse { function_response } => {
+                        format!("function response: {}", function_response.name)
+                    }
+                };
+                summary.push_str(&format!("\n    {}", line));
+            }
+        }
+        summary
+    }
+
+    /// Returns every text part across all candidates.
+    pub fn text_parts(&self) -> Vec<&Part> {
+        self.collect_parts(|part| matches!(part.data, PartData::Text { .. }))
+    }
+
+    /// Returns every inline data part across all candidates.
+    pub fn inline_data_parts(&self) -> Vec<&Part> {
+        self.collect_parts(|part| matches!(part.data, PartData::InlineData { .. }))
+    }
+
+    /// Returns every function call part across all candidates.
+    pub fn function_call_parts(&self) -> Vec<&Part> {
+        self.collect_parts(|part| matches!(part.data, PartData::FunctionCall { .. }))
+    }
+
+    /// Writes every image returned by the first candidate to `dir`, named `image_<n>.<ext>`,
+    /// and returns the paths of the written files.
+    pub fn save_images(&self, dir: &Path) -> Result<Vec<PathBuf>, GemError> {
+        let Some(content) = self
+            .candidates
+            .first()
+            .and_then(|candidate| candidate.get_content())
+        else {
+            return Ok(Vec::new());
+        };
+
+        let mut paths = Vec::new();
+        for blob in content.get_inline_data() {
+            if !blob.mime_type.starts_with("image/") {
+                continue;
+            }
+            let extension = get_extension(&blob.mime_type).unwrap_or("bin");
+            let path = dir.join(format!("image_{}.{}", paths.len(), extension));
+            std::fs::write(&path, blob.decode()?)
+                .map_err(|e| GemError::FileError(e.to_string()))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// Returns every file data part across all candidates.
+    pub fn file_data_parts(&self) -> Vec<&Part> {
+        self.collect_parts(|part| matches!(part.data, PartData::FileData { .. }))
+    }
+
+    /// Returns the first candidate's text cut at the earliest occurrence of any of `sequences`.
+    ///
+    /// The full text is returned when none of the sequences occur.
+    pub fn truncate_at(&self, sequences: &[String]) -> String {
+        let text = self
+            .candidates
+            .first()
+            .and_then(|candidate| candidate.get_content())
+            .and_then(|content| content.get_text())
+            .unwrap_or_default();
+
+        let end = sequences
+            .iter()
+            .filter(|sequence| !sequence.is_empty())
+            .filter_map(|sequence| text.find(sequence.as_str()))
+            .min()
+            .unwrap_or(text.len());
+        text[..end].to_string()
+    }
+
+    /// Returns `true` if any candidate was stopped for potentially containing sensitive
+    /// personally identifiable information (SPII).
+    pub fn was_spii_blocked(&self) -> bool {
+        self.candidates
+            .iter()
+            .any(|candidate| candidate.finish_reason == Some(FinishReason::Spii))
+    }
+
     pub(crate) fn feedback(&self) -> Option<BlockReason> {
         match self.prompt_feedback.is_some()
             && self
@@ -94,6 +460,9 @@ pub struct Candidate {
     safety_ratings: Option<Vec<SafetyRating>>, // List of safety ratings for the response
     token_count: Option<i32>,            // The token count for this candidate
     index: Option<i32>,                  // Index of the candidate in the list
+    grounding_metadata: Option<GroundingMetadata>, // Sources used to ground the response
+    logprobs_result: Option<LogprobsResult>, // Token log-probabilities, when requested
+    avg_logprobs: Option<f64>,           // Average log-probability of the candidate's tokens
 }
 
 impl Candidate {
@@ -101,15 +470,286 @@ impl Candidate {
         self.content.as_ref()
     }
 
+    /// Returns the index of the candidate in the response, when the API sent one.
+    pub fn get_index(&self) -> Option<i32> {
+        self.index
+    }
+
+    /// Creates a candidate with no content, used to fill gaps in merged responses.
+    fn empty(index: i32) -> Self {
+        Candidate {
+            content: None,
+            finish_reason: None,
+            safety_ratings: None,
+            token_count: None,
+            index: Some(index),
+            grounding_metadata: None,
+            logprobs_result: None,
+            avg_logprobs: None,
+        }
+    }
+
+    #[allow(dead_code)]
     pub(crate) fn is_blocked(&self) -> bool {
         (self.finish_reason == Some(FinishReason::Safety))
             || (self.finish_reason == Some(FinishReason::Recitation))
             || (self.finish_reason == Some(FinishReason::ProhibitedContent))
     }
 
-    pub(crate) fn get_token_count(&self) -> Option<i32> {
+    /// Appends a later chunk of the same candidate, as done by `GenerateContentResponse::merge`.
+    fn merge(&mut self, chunk: Candidate) {
+        if let Some(content) = chunk.content {
+            match &mut self.content {
+                Some(merged) => {
+                    merged.role = merged.role.take().or(content.role);
+                    for part in content.parts {
+                        match (merged.parts.last_mut(), &part.data) {
+                            (
+                                Some(Part {
+                                    data: PartData::Text { text },
+                                    thought,
+                                }),
+                                PartData::Text { text: next },
+                            ) if *thought == part.thought => text.push_str(next),
+                            _ => merged.parts.push(part),
+                        }
+                    }
+                }
+                None => self.content = Some(content),
+            }
+        }
+
+        if let Some(ratings) = chunk.safety_ratings {
+            let merged = self.safety_ratings.get_or_insert_with(Vec::new);
+            for rating in ratings {
+                match merged
+                    .iter_mut()
+                    .find(|merged| merged.category == rating.category)
+                {
+                    Some(merged) => *merged = rating,
+                    None => merged.push(rating),
+                }
+            }
+        }
+
+        self.finish_reason = chunk.finish_reason.or(self.finish_reason.take());
+        self.token_count = chunk.token_count.or(self.token_count);
+        self.grounding_metadata = chunk.grounding_metadata.or(self.grounding_metadata.take());
+        if let Some(logprobs) = chunk.logprobs_result {
+            match &mut self.logprobs_result {
+                Some(merged) => {
+                    merged.top_candidates.extend(logprobs.top_candidates);
+                    merged.chosen_candidates.extend(logprobs.chosen_candidates);
+                }
+                None => self.logprobs_result = Some(logprobs),
+            }
+        }
+        self.avg_logprobs = chunk.avg_logprobs.or(self.avg_logprobs);
+    }
+
+    pub fn get_finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason.clone()
+    }
+
+    pub fn get_safety_ratings(&self) -> Option<&Vec<SafetyRating>> {
+        self.safety_ratings.as_ref()
+    }
+
+    pub fn get_token_count(&self) -> Option<i32> {
         self.token_count
     }
+
+    pub fn get_function_calls(&self) -> Vec<FunctionCall> {
+        self.content
+            .iter()
+            .flat_map(|content| content.parts.iter())
+            .filter_map(|part| match &part.data {
+                PartData::FunctionCall { function_call } => Some(function_call.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn get_grounding_metadata(&self) -> Option<&GroundingMetadata> {
+        self.grounding_metadata.as_ref()
+    }
+
+    /// Returns the token log-probabilities, set when `response_logprobs` was requested.
+    pub fn get_logprobs(&self) -> Option<&LogprobsResult> {
+        self.logprobs_result.as_ref()
+    }
+
+    pub fn get_avg_logprobs(&self) -> Option<f64> {
+        self.avg_logprobs
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogprobsResult {
+    #[serde(default)]
+    top_candidates: Vec<TopCandidates>, // Most likely tokens at each decoding step
+    #[serde(default)]
+    chosen_candidates: Vec<LogprobsCandidate>, // Token chosen at each decoding step
+}
+
+impl LogprobsResult {
+    /// Returns the most likely tokens at each decoding step, in the order of the output.
+    pub fn get_top_candidates(&self) -> &[TopCandidates] {
+        &self.top_candidates
+    }
+
+    /// Returns the token chosen at each decoding step, in the order of the output.
+    pub fn get_chosen_candidates(&self) -> &[LogprobsCandidate] {
+        &self.chosen_candidates
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopCandidates {
+    #[serde(default)]
+    candidates: Vec<LogprobsCandidate>, // Sorted by log-probability, most likely first
+}
+
+impl TopCandidates {
+    pub fn get_candidates(&self) -> &[LogprobsCandidate] {
+        &self.candidates
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogprobsCandidate {
+    token: Option<String>,        // The token string
+    token_id: Option<i32>,        // The token id
+    log_probability: Option<f64>, // Log-probability of the token
+}
+
+impl LogprobsCandidate {
+    pub fn get_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn get_token_id(&self) -> Option<i32> {
+        self.token_id
+    }
+
+    pub fn get_log_probability(&self) -> Option<f64> {
+        self.log_probability
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroundingMetadata {
+    grounding_supports: Option<Vec<GroundingSupport>>, // Links between response text and grounding chunks
+    grounding_chunks: Option<Vec<GroundingChunk>>,     // Sources the response was grounded on
+    web_search_queries: Option<Vec<String>>,           // Google Search queries run by the model
+}
+
+impl GroundingMetadata {
+    pub fn get_grounding_supports(&self) -> &[GroundingSupport] {
+        self.grounding_supports.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_grounding_chunks(&self) -> &[GroundingChunk] {
+        self.grounding_chunks.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_web_search_queries(&self) -> &[String] {
+        self.web_search_queries.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the web sources backing a grounding support, in the order of its chunk indices.
+    pub fn get_sources(&self, support: &GroundingSupport) -> Vec<&WebSource> {
+        support
+            .get_grounding_chunk_indices()
+            .iter()
+            .filter_map(|index| self.get_grounding_chunks().get(*index as usize))
+            .filter_map(|chunk| chunk.get_web())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroundingChunk {
+    web: Option<WebSource>, // Set when the chunk comes from the web
+}
+
+impl GroundingChunk {
+    pub fn get_web(&self) -> Option<&WebSource> {
+        self.web.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSource {
+    uri: Option<String>,   // URI of the source
+    title: Option<String>, // Title of the source
+}
+
+impl WebSource {
+    pub fn get_uri(&self) -> Option<&str> {
+        self.uri.as_deref()
+    }
+
+    pub fn get_title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroundingSupport {
+    segment: Option<Segment>, // The segment of the response text this support applies to
+    #[serde(default)]
+    grounding_chunk_indices: Vec<i32>, // Indices of the grounding chunks backing the segment
+    #[serde(default)]
+    confidence_scores: Vec<f32>, // Confidence score for each chunk, in the same order
+}
+
+impl GroundingSupport {
+    pub fn get_segment(&self) -> Option<&Segment> {
+        self.segment.as_ref()
+    }
+
+    pub fn get_grounding_chunk_indices(&self) -> &[i32] {
+        &self.grounding_chunk_indices
+    }
+
+    pub fn get_confidence_scores(&self) -> &[f32] {
+        &self.confidence_scores
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Segment {
+    #[serde(default)]
+    part_index: i32, // Index of the part the segment belongs to
+    #[serde(default)]
+    start_index: i32, // Start byte offset in the part text, inclusive
+    #[serde(default)]
+    end_index: i32, // End byte offset in the part text, exclusive
+    text: Option<String>, // The text of the segment
+}
+
+impl Segment {
+    pub fn get_part_index(&self) -> i32 {
+        self.part_index
+    }
+
+    pub fn get_start_index(&self) -> i32 {
+        self.start_index
+    }
+
+    pub fn get_end_index(&self) -> i32 {
+        self.end_index
+    }
+
+    pub fn get_text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -128,6 +768,79 @@ impl Content {
         }
         None
     }
+
+    /// Returns the text of every text part concatenated, unlike `get_text`, which only
+    /// returns the first one.
+    pub fn get_all_text(&self) -> String {
+        join_text(self.parts.iter()).unwrap_or_default()
+    }
+
+    /// Returns the inline data of every inline data part, e.g. images generated by the model.
+    pub fn get_inline_data(&self) -> Vec<&Blob> {
+        self.parts
+            .iter()
+            .filter_map(|part| match &part.data {
+                PartData::InlineData { inline_data } => Some(inline_data),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the parts that contain the model's reasoning.
+    pub fn thought_parts(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter().filter(|part| part.is_thought())
+    }
+
+    /// Returns the parts that make up the model's answer, leaving out its reasoning.
+    pub fn answer_parts(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter().filter(|part| !part.is_thought())
+    }
+
+    /// Returns the concatenated text of the thought parts, if any.
+    pub fn get_thoughts_text(&self) -> Option<String> {
+        join_text(self.thought_parts())
+    }
+
+    /// Returns the concatenated text of the answer parts, if any.
+    pub fn get_answer_text(&self) -> Option<String> {
+        join_text(self.answer_parts())
+    }
+}
+
+/// Maximum number of stop sequences accepted by the API.
+const MAX_STOP_SEQUENCES: usize = 5;
+
+/// Maximum number of top candidates per step accepted for `GenerationConfig::logprobs`.
+const MAX_LOGPROBS: u32 = 20;
+
+/// Checks that an optional sampling parameter is within `range`.
+fn check_range(
+    name: &str,
+    value: Option<f32>,
+    range: std::ops::RangeInclusive<f32>,
+) -> Result<(), GemError> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(GemError::InvalidConfig(format!(
+            "{} {} is outside the range {:?}",
+            name, value, range
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Concatenates the text of the text parts in `parts`, or `None` when there are none.
+fn join_text<'a>(parts: impl Iterator<Item = &'a Part>) -> Option<String> {
+    let texts: Vec<&str> = parts
+        .filter_map(|part| match &part.data {
+            PartData::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    if texts.is_empty() {
+        None
+    } else {
+        Some(texts.concat())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -139,10 +852,46 @@ pub struct NoRoleContent {
 pub struct Part {
     #[serde(flatten)] // This enables the union-like behavior for the different possible types
     pub data: PartData, // Union field that can be one of several types
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thought: Option<bool>, // Set by the model on parts that contain its reasoning
+}
+
+impl Part {
+    /// Creates a text part.
+    pub fn text(text: &str) -> Self {
+        Part {
+            data: PartData::Text {
+                text: text.to_string(),
+            },
+            thought: None,
+        }
+    }
+
+    /// Creates a part referencing an uploaded file.
+    pub fn file_data(file_data: FileData) -> Self {
+        Part {
+            data: PartData::FileData { file_data },
+            thought: None,
+        }
+    }
+
+    /// Creates a part with inline data.
+    pub fn blob(blob: Blob) -> Self {
+        Part {
+            data: PartData::InlineData { inline_data: blob },
+            thought: None,
+        }
+    }
+
+    /// Returns `true` if the part contains the model's reasoning rather than its answer.
+    pub fn is_thought(&self) -> bool {
+        self.thought.unwrap_or(false)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Blob {
+    #[serde(alias = "mimeType")]
     mime_type: String,
     data: String, // Base64 encoded data
 }
@@ -151,44 +900,237 @@ impl Blob {
     pub fn new(mime_type: &str, data: &[u8]) -> Self {
         Blob {
             mime_type: mime_type.to_string(),
-            data: general_purpose::STANDARD.encode(&data),
+            data: general_purpose::STANDARD.encode(data),
         }
     }
+
+    pub fn get_mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    /// Decodes the base64 encoded data, e.g. to save an image generated by the model.
+    pub fn decode(&self) -> Result<Vec<u8>, GemError> {
+        general_purpose::STANDARD
+            .decode(&self.data)
+            .map_err(|e| GemError::FileError(format!("Invalid base64 inline data: {}", e)))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileData {
+    #[serde(alias = "mimeType")]
     pub mime_type: String,
+    #[serde(alias = "fileUri")]
     pub file_uri: String, // File URI
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct PromptFeedback {
-    block_reason: Option<BlockReason>, // Block reason, optional
-    safety_ratings: Vec<SafetyRating>, // A vector of SafetyRating objects
+pub struct FunctionCall {
+    pub name: String, // Name of the function to call
+    #[serde(default)]
+    pub args: Value, // Function arguments as a JSON object
 }
 
-impl PromptFeedback {
-    pub(crate) fn get_block_reason(&self) -> Option<BlockReason> {
-        self.block_reason.clone()
+impl FunctionCall {
+    /// Deserializes the call arguments into `T`.
+    pub fn args_as<T: DeserializeOwned>(&self) -> Result<T, GemError> {
+        serde_json::from_value(self.args.clone()).map_err(GemError::ParsingError)
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "SCREAMING_SNAKE_CASE")] // Ensure enum variants match the JSON casing
-pub(crate) enum BlockReason {
-    BlockReasonUnspecified, // Default value, unused
-    Safety,                 // Blocked for safety reasons
-    Other,                  // Blocked for unknown reasons
-    Blocklist,              // Blocked due to blacklist terms
-    ProhibitedContent,      // Blocked due to prohibited content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionResponse {
+    pub name: String,    // Name of the function that was called
+    pub response: Value, // Function result as a JSON object
 }
 
-impl std::fmt::Display for BlockReason {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            BlockReason::BlockReasonUnspecified => write!(f, "Unspecified"),
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Tool {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_declarations: Option<Vec<FunctionDeclaration>>, // Functions the model may call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub google_search: Option<GoogleSearch>, // Lets the model ground its answer with Google Search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub google_search_retrieval: Option<GoogleSearchRetrieval>, // Search grounding with a dynamic threshold
+}
+
+/// Google Search tool. It has no options; its presence enables search grounding.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct GoogleSearch {}
+
+/// Google Search retrieval tool, which lets the model decide when a search is worth it.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleSearchRetrieval {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamic_retrieval_config: Option<DynamicRetrievalConfig>, // When to trigger a search
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DynamicRetrievalConfig {
+    pub mode: DynamicRetrievalMode, // Whether retrieval is dynamic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamic_threshold: Option<f32>, // Search when the predicted benefit is above this, 0.0 to 1.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DynamicRetrievalMode {
+    ModeUnspecified, // Always trigger retrieval
+    ModeDynamic,     // Trigger retrieval only when the model decides it is needed
+}
+
+impl Tool {
+    /// Creates a tool that lets the model ground its answers with Google Search.
+    pub fn google_search() -> Self {
+        Tool {
+            google_search: Some(GoogleSearch {}),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a Google Search retrieval tool that only searches when the model predicts
+    /// the answer would benefit from it by more than `threshold`, from 0.0 (always search)
+    /// to 1.0 (rarely search).
+    pub fn google_search_dynamic(threshold: f32) -> Self {
+        Tool {
+            google_search_retrieval: Some(GoogleSearchRetrieval {
+                dynamic_retrieval_config: Some(DynamicRetrievalConfig {
+                    mode: DynamicRetrievalMode::ModeDynamic,
+                    dynamic_threshold: Some(threshold),
+                }),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a tool exposing the given function declarations to the model.
+    pub fn functions(declarations: Vec<FunctionDeclaration>) -> Self {
+        Tool {
+            function_declarations: Some(declarations),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDeclaration {
+    pub name: String,        // Name of the function
+    pub description: String, // What the function does, used by the model to decide when to call it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>, // Optional: JSON schema (OpenAPI subset) of the arguments
+}
+
+impl FunctionDeclaration {
+    pub fn new(name: &str, description: &str, parameters: Option<Value>) -> Self {
+        FunctionDeclaration {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+        }
+    }
+
+    /// Checks that the parameters schema only uses the OpenAPI subset accepted by Gemini.
+    ///
+    /// Nested objects and arrays are checked recursively. Keywords such as `$ref` or `oneOf`
+    /// are rejected with `GemError::InvalidConfig`, since the API would fail the request.
+    pub fn validate(&self) -> Result<(), GemError> {
+        match &self.parameters {
+            Some(parameters) => validate_schema(parameters, "parameters")
+                .map_err(|e| GemError::InvalidConfig(format!("Function `{}`: {}", self.name, e))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// JSON Schema keywords outside the OpenAPI subset supported by Gemini.
+const UNSUPPORTED_SCHEMA_KEYWORDS: &[&str] = &[
+    "$ref",
+    "$defs",
+    "definitions",
+    "$schema",
+    "$id",
+    "oneOf",
+    "allOf",
+    "not",
+    "if",
+    "then",
+    "else",
+    "const",
+    "additionalProperties",
+    "patternProperties",
+];
+
+/// Walks a schema and its nested `properties`, `items` and `anyOf` schemas, returning a
+/// description of the first unsupported keyword found.
+fn validate_schema(schema: &Value, path: &str) -> Result<(), String> {
+    let object = schema
+        .as_object()
+        .ok_or_else(|| format!("{} is not a schema object", path))?;
+
+    if let Some(keyword) = UNSUPPORTED_SCHEMA_KEYWORDS
+        .iter()
+        .find(|keyword| object.contains_key(**keyword))
+    {
+        return Err(format!(
+            "unsupported schema keyword `{}` at {}",
+            keyword, path
+        ));
+    }
+
+    if let Some(properties) = object.get("properties") {
+        let properties = properties
+            .as_object()
+            .ok_or_else(|| format!("{}.properties is not an object", path))?;
+        for (name, property) in properties {
+            validate_schema(property, &format!("{}.properties.{}", path, name))?;
+        }
+    }
+    if let Some(items) = object.get("items") {
+        validate_schema(items, &format!("{}.items", path))?;
+    }
+    if let Some(any_of) = object.get("anyOf") {
+        let any_of = any_of
+            .as_array()
+            .ok_or_else(|| format!("{}.anyOf is not an array", path))?;
+        for (index, schema) in any_of.iter().enumerate() {
+            validate_schema(schema, &format!("{}.anyOf[{}]", path, index))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PromptFeedback {
+    block_reason: Option<BlockReason>, // Block reason, optional
+    safety_ratings: Vec<SafetyRating>, // A vector of SafetyRating objects
+}
+
+impl PromptFeedback {
+    #[allow(dead_code)]
+    pub(crate) fn get_block_reason(&self) -> Option<BlockReason> {
+        self.block_reason.clone()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")] // Ensure enum variants match the JSON casing
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum BlockReason {
+    BlockReasonUnspecified, // Default value, unused
+    Safety,                 // Blocked for safety reasons
+    Other,                  // Blocked for unknown reasons
+    Blocklist,              // Blocked due to blacklist terms
+    ProhibitedContent,      // Blocked due to prohibited content
+}
+
+impl std::fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockReason::BlockReasonUnspecified => write!(f, "Unspecified"),
             BlockReason::Safety => write!(f, "Safety"),
             BlockReason::Other => write!(f, "Other"),
             BlockReason::Blocklist => write!(f, "Blocklist"),
@@ -198,12 +1140,31 @@ impl std::fmt::Display for BlockReason {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct SafetyRating {
+pub struct SafetyRating {
     category: Option<String>,    // The safety category
     probability: Option<String>, // The probability of the content being unsafe
     blocked: Option<bool>,       // Whether the content is blocked
 }
 
+impl SafetyRating {
+    pub fn get_category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    /// Returns the category as a `HarmCategory`, or `None` if it is missing or unknown.
+    pub fn harm_category(&self) -> Option<HarmCategory> {
+        self.category.as_deref().and_then(HarmCategory::from_name)
+    }
+
+    pub fn get_probability(&self) -> Option<&str> {
+        self.probability.as_deref()
+    }
+
+    pub fn get_blocked(&self) -> Option<bool> {
+        self.blocked
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UsageMetadata {
@@ -231,6 +1192,73 @@ impl UsageMetadata {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TaskType {
+    RetrievalQuery,     // The text is a search query
+    RetrievalDocument,  // The text is a document from the corpus being searched
+    SemanticSimilarity, // The embedding is used for semantic textual similarity
+    Classification,     // The embedding is used for classification
+    Clustering,         // The embedding is used for clustering
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    name: String, // Resource name, e.g. models/gemini-2.0-flash
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    input_token_limit: i32, // Maximum number of input tokens
+    #[serde(default)]
+    output_token_limit: i32, // Maximum number of output tokens
+    #[serde(default)]
+    supported_generation_methods: Vec<String>, // e.g. generateContent, countTokens
+}
+
+impl ModelInfo {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    pub fn get_description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn get_input_token_limit(&self) -> i32 {
+        self.input_token_limit
+    }
+
+    pub fn get_output_token_limit(&self) -> i32 {
+        self.output_token_limit
+    }
+
+    pub fn get_supported_generation_methods(&self) -> &[String] {
+        &self.supported_generation_methods
+    }
+
+    /// Returns `true` if the model can be used with `generateContent`.
+    pub fn supports_generate_content(&self) -> bool {
+        self.supported_generation_methods
+            .iter()
+            .any(|method| method == "generateContent")
+    }
+
+    /// Returns the model as a `Models` value that can be passed to the session builder,
+    /// `Models::Custom` for names without a variant.
+    pub fn to_model(&self) -> Models {
+        self.name
+            .parse()
+            .unwrap_or_else(|_| Models::Custom(self.name.clone()))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Status {
@@ -268,28 +1296,34 @@ impl File {
         file_name: &str,
         bytes: Vec<u8>,
         mime_type: &str,
-        api_key: &str,
+        manager: &FileManager,
+        progress: &mut UploadProgress<'_>,
     ) -> Result<Self, GemError> {
-        Self::upload(file_name, bytes, mime_type, api_key).await
+        Self::upload(file_name, bytes, mime_type, manager, progress).await
     }
 
     async fn upload(
         file_name: &str,
         buffer: Vec<u8>,
         mime_type: &str,
-        api_key: &str,
+        manager: &FileManager,
+        progress: &mut UploadProgress<'_>,
     ) -> Result<Self, GemError> {
+        let api_key = manager.api_key.as_str();
+        let base_url = manager.base_url.as_str();
+        let poll = manager.poll;
+        let headers = &manager.upload_headers;
         let num_bytes = buffer.len();
 
         let client = reqwest::Client::new();
 
         let reserve_response = match client
-            .post("https://generativelanguage.googleapis.com/upload/v1beta/files")
+            .post(format!("{}upload/v1beta/files", base_url))
             .query(&[("key", api_key)])
-            .header("X-Goog-Upload-Protocol", "resumable")
-            .header("X-Goog-Upload-Command", "start")
-            .header("X-Goog-Upload-Header-Content-Length", num_bytes.to_string())
-            .header("X-Goog-Upload-Header-Content-Type", mime_type)
+            .header(&headers.protocol, &headers.protocol_value)
+            .header(&headers.command, "start")
+            .header(&headers.content_length, num_bytes.to_string())
+            .header(&headers.content_type, mime_type)
             .header(header::CONTENT_TYPE, "application/json")
             .json(&json!({
                 "file": { "display_name": file_name }
@@ -301,30 +1335,58 @@ impl File {
             Err(e) => return Err(GemError::FileError(e.to_string())),
         };
 
-        let location = match reserve_response.headers().get("X-Goog-Upload-URL") {
+        let location = match reserve_response.headers().get(&headers.upload_url) {
             Some(loc) => match loc.to_str() {
                 Ok(l) => l,
                 Err(e) => return Err(GemError::FileError(e.to_string())),
             },
             None => {
-                return Err(GemError::FileError(
-                    "X-Goog-Upload-URL header not found".to_string(),
-                ))
+                return Err(GemError::FileError(format!(
+                    "{} header not found",
+                    headers.upload_url
+                )))
             }
         };
 
-        // Uploading the file's bytes
-        let upload_response = match client
-            .put(location)
-            .header("Content-Length", num_bytes.to_string())
-            .header("X-Goog-Upload-Offset", "0")
-            .header("X-Goog-Upload-Command", "upload, finalize")
-            .body(buffer)
-            .send()
-            .await
-        {
-            Ok(response) => response,
-            Err(e) => return Err(GemError::FileError(e.to_string())),
+        // Uploading the file's bytes, in one request or in chunks of `upload_chunk_size`
+        let chunk_size = match manager.upload_chunk_size {
+            Some(size) if size > 0 && size < num_bytes => size,
+            _ => num_bytes,
+        };
+        let buffer = bytes::Bytes::from(buffer);
+        let mut offset = 0;
+        let upload_response = loop {
+            let end = (offset + chunk_size).min(num_bytes);
+            let command = match end == num_bytes {
+                true => "upload, finalize",
+                false => "upload",
+            };
+            let response = match client
+                .put(location)
+                .header("Content-Length", (end - offset).to_string())
+                .header(&headers.offset, offset.to_string())
+                .header(&headers.command, command)
+                .body(buffer.slice(offset..end))
+                .send()
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => return Err(GemError::FileError(e.to_string())),
+            };
+            if !response.status().is_success() {
+                return Err(GemError::FileError(format!(
+                    "Failed to upload bytes {}..{} (status code: {})",
+                    offset,
+                    end,
+                    response.status()
+                )));
+            }
+            progress(end, num_bytes);
+
+            if end == num_bytes {
+                break response;
+            }
+            offset = end;
         };
 
         let upload_text_response = match upload_response.text().await {
@@ -357,10 +1419,7 @@ impl File {
         let mut timeout = 0;
         loop {
             let file_state = match client
-                .get(&format!(
-                    "https://generativelanguage.googleapis.com/v1beta/{}",
-                    file.name
-                ))
+                .get(format!("{}v1beta/{}", base_url, file.name))
                 .query(&[("key", api_key)])
                 .send()
                 .await
@@ -405,89 +1464,304 @@ impl File {
                 ));
             }
 
-            if timeout >= 3 {
+            if timeout >= poll.max_attempts {
                 return Err(GemError::FileError("File processing timeout".to_string()));
             }
 
             timeout += 1;
-            std::thread::sleep(std::time::Duration::from_secs(3));
+            tokio::time::sleep(poll.interval).await;
         }
 
         file.api_key = api_key.to_string();
         Ok(file)
     }
 
-    //TODO: Something with the API cause the cached files in cloud to change uri every time they are deleted
-    async fn delete(self) -> Result<(), GemError> {
+    /// Deletes the remote file through `v1beta/{name}`, which stays valid when the uri changes.
+    async fn delete(self, base_url: &str) -> Result<(), GemError> {
         log::info!("Deleting file: {:#?}", self);
-        if self.api_key == "" {
+        if self.api_key.is_empty() {
             log::info!("API key not found: {:#?}", self.display_name);
             return Err(GemError::FileError("API key not found".to_string()));
         }
         let client = reqwest::Client::new();
         match client
-            .delete(self.uri)
+            .delete(format!("{}v1beta/{}", base_url, self.name))
             .query(&[("key", self.api_key.clone())])
             .send()
             .await
         {
-            Ok(_) => {
+            Ok(response) if response.status().is_success() => {
                 log::info!("File deleted successfully: {:#?}", self.display_name);
                 Ok(())
             }
+            Ok(response) => Err(GemError::FileError(format!(
+                "Failed to delete {} (status code: {})",
+                self.name,
+                response.status()
+            ))),
             Err(e) => Err(GemError::FileError(e.to_string())),
         }
     }
 }
 
+/// Granularity of upload chunk sizes required by the resumable upload endpoint (256 KiB).
+const UPLOAD_CHUNK_GRANULARITY: usize = 256 * 1024;
+
+/// Callback receiving the number of bytes uploaded so far and the total size of the file.
+pub type UploadProgress<'a> = dyn FnMut(usize, usize) + Send + 'a;
+
+/// Uploads files to the Gemini files API and caches them by content hash.
+///
+/// Every method takes `&self`, so a single `FileManager` can be shared across tasks behind an `Arc`.
 #[derive(Debug)]
 pub struct FileManager {
     files: Mutex<HashMap<String, File>>,
+    uploads: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>, // Locks of the uploads in flight, by hash
     api_key: String,
+    base_url: String,
+    poll: PollConfig,
+    upload_headers: UploadHeaders,
+    upload_chunk_size: Option<usize>, // Size of the chunks large files are uploaded in
+}
+
+/// Lock shared by concurrent uploads of the same bytes, removed from the map of uploads
+/// in flight once the last of them finishes or is dropped.
+struct UploadLock<'a> {
+    uploads: &'a std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    hash: &'a str,
+    lock: Arc<Mutex<()>>,
+}
+
+impl<'a> UploadLock<'a> {
+    fn acquire(
+        uploads: &'a std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+        hash: &'a str,
+    ) -> Self {
+        let lock = uploads
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .entry(hash.to_string())
+            .or_default()
+            .clone();
+        UploadLock {
+            uploads,
+            hash,
+            lock,
+        }
+    }
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        // Forget the lock once nobody else is waiting on it.
+        let mut uploads = self
+            .uploads
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if Arc::strong_count(&self.lock) == 2 {
+            uploads.remove(self.hash);
+        }
+    }
+}
+
+/// Header names of the resumable upload protocol used by `FileManager`.
+///
+/// Defaults to the Google scheme. Override it when uploads go through a proxy or backend
+/// that uses different header names.
+#[derive(Debug, Clone)]
+pub struct UploadHeaders {
+    pub protocol: String,       // Selects the upload protocol
+    pub protocol_value: String, // Value of the protocol header
+    pub command: String,        // Carries the upload command (start, upload, finalize)
+    pub content_length: String, // Size in bytes of the file being uploaded
+    pub content_type: String,   // MIME type of the file being uploaded
+    pub offset: String,         // Offset of the uploaded bytes
+    pub upload_url: String,     // Response header with the URL to upload the bytes to
+}
+
+impl UploadHeaders {
+    /// Returns the header scheme of the Google resumable upload protocol.
+    pub fn google() -> Self {
+        UploadHeaders {
+            protocol: "X-Goog-Upload-Protocol".to_string(),
+            protocol_value: "resumable".to_string(),
+            command: "X-Goog-Upload-Command".to_string(),
+            content_length: "X-Goog-Upload-Header-Content-Length".to_string(),
+            content_type: "X-Goog-Upload-Header-Content-Type".to_string(),
+            offset: "X-Goog-Upload-Offset".to_string(),
+            upload_url: "X-Goog-Upload-URL".to_string(),
+        }
+    }
+}
+
+impl Default for UploadHeaders {
+    fn default() -> Self {
+        Self::google()
+    }
+}
+
+/// How often and how many times an upload is polled while the file is processed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PollConfig {
+    max_attempts: u32,             // Number of waits before giving up
+    interval: std::time::Duration, // Wait between two polls
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        PollConfig {
+            max_attempts: 3,
+            interval: std::time::Duration::from_secs(3),
+        }
+    }
 }
 
 impl FileManager {
-    pub fn new(api_key: Option<String>) -> Self {
-        if let Some(api_key) = api_key {
-            Self {
-                files: Mutex::new(HashMap::new()),
-                api_key,
-            }
-        } else {
-            dotenv().expect("Failed to load Gemini API key");
-            let api_key = std::env::var("GEMINI_API_KEY").unwrap();
+    /// Creates a `FileManager` using the API key in the `GEMINI_API_KEY` environment variable.
+    ///
+    /// Returns `GemError::InvalidConfig` if the variable is not set. Use `with_api_key` when
+    /// the key comes from elsewhere, e.g. a secrets manager.
+    pub fn new() -> Result<Self, GemError> {
+        Self::from_env(|name| std::env::var(name).ok())
+    }
 
-            Self {
-                files: Mutex::new(HashMap::new()),
-                api_key: api_key.to_string(),
-            }
+    /// Creates a `FileManager`, looking up `GEMINI_API_KEY` through `env`.
+    fn from_env(env: impl Fn(&str) -> Option<String>) -> Result<Self, GemError> {
+        match env("GEMINI_API_KEY") {
+            Some(api_key) => Ok(Self::with_api_key(api_key)),
+            None => Err(GemError::InvalidConfig(
+                "GEMINI_API_KEY is not set".to_string(),
+            )),
         }
     }
 
+    /// Creates a `FileManager` using the given API key.
+    pub fn with_api_key(api_key: String) -> Self {
+        Self {
+            files: Mutex::new(HashMap::new()),
+            uploads: std::sync::Mutex::new(HashMap::new()),
+            api_key,
+            base_url: API_BASE_URL.to_string(),
+            poll: PollConfig::default(),
+            upload_headers: UploadHeaders::default(),
+            upload_chunk_size: None,
+        }
+    }
+
+    /// Sets how many times an upload waits `interval` for the file to finish processing
+    /// before failing. Defaults to 3 waits of 3 seconds.
+    pub fn with_poll_config(mut self, max_attempts: u32, interval: std::time::Duration) -> Self {
+        self.poll = PollConfig {
+            max_attempts,
+            interval,
+        };
+        self
+    }
+
+    /// Sets the header scheme used for resumable uploads.
+    pub fn with_upload_headers(mut self, headers: UploadHeaders) -> Self {
+        self.upload_headers = headers;
+        self
+    }
+
+    /// Uploads files larger than `chunk_size` bytes in several requests of that size,
+    /// reporting progress after each one. Smaller files are still sent in a single request.
+    ///
+    /// The Google upload endpoint requires the size to be a multiple of 256 KiB, so
+    /// `chunk_size` is rounded up to the next multiple.
+    pub fn with_upload_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.upload_chunk_size = Some(chunk_size.max(1).next_multiple_of(UPLOAD_CHUNK_GRANULARITY));
+        self
+    }
+
+    /// Uploads `bytes`, or returns the cached file if the same bytes were uploaded before.
+    ///
+    /// Concurrent calls with the same bytes wait for a single upload instead of each
+    /// uploading a copy; uploads of different bytes still run in parallel.
     pub async fn add_file_from_bytes(
         &self,
         file_name: &str,
         bytes: Vec<u8>,
         mime_type: &str,
     ) -> Result<FileData, GemError> {
+        self.add_file_from_bytes_with_progress(file_name, bytes, mime_type, |_, _| {})
+            .await
+    }
+
+    /// Like `add_file_from_bytes`, calling `progress` with the bytes uploaded so far and
+    /// the total after every uploaded chunk. See `with_upload_chunk_size`.
+    ///
+    /// `progress` is not called when the file is already cached.
+    pub async fn add_file_from_bytes_with_progress<F>(
+        &self,
+        file_name: &str,
+        bytes: Vec<u8>,
+        mime_type: &str,
+        mut progress: F,
+    ) -> Result<FileData, GemError>
+    where
+        F: FnMut(usize, usize) + Send,
+    {
         let hash = sha256::digest(&bytes);
+        let upload = UploadLock::acquire(&self.uploads, &hash);
+
+        let _guard = upload.lock.lock().await;
         match self.get_file(&hash).await {
             Some(file) => Ok(file),
             None => {
-                let file = File::new(file_name, bytes, mime_type, &self.api_key).await?;
-                let mime_type = file.mime_type.clone();
-                let file_uri = file.uri.clone();
-                let mut files = self.files.lock().await;
-                files.insert(hash, file);
-                Ok(FileData {
-                    mime_type: mime_type,
-                    file_uri: file_uri,
-                })
+                self.upload_file(file_name, bytes, mime_type, hash.clone(), &mut progress)
+                    .await
             }
         }
     }
 
-    pub async fn add_file(&mut self, file_path: &Path) -> Result<FileData, GemError> {
+    async fn upload_file(
+        &self,
+        file_name: &str,
+        bytes: Vec<u8>,
+        mime_type: &str,
+        hash: String,
+        progress: &mut UploadProgress<'_>,
+    ) -> Result<FileData, GemError> {
+        let file = File::new(file_name, bytes, mime_type, self, progress).await?;
+        let mime_type = file.mime_type.clone();
+        let file_uri = file.uri.clone();
+        let mut files = self.files.lock().await;
+        files.insert(hash, file);
+        Ok(FileData {
+            mime_type,
+            file_uri,
+        })
+    }
+
+    pub async fn add_file(&self, file_path: &Path) -> Result<FileData, GemError> {
+        self.add_file_with_mime_type(file_path, None).await
+    }
+
+    /// Uploads a file, using `mime_type` instead of guessing it when given.
+    ///
+    /// Without an override, the MIME type comes from the extension, then from the first
+    /// bytes of the file. Files matching neither are rejected.
+    pub async fn add_file_with_mime_type(
+        &self,
+        file_path: &Path,
+        mime_type: Option<&str>,
+    ) -> Result<FileData, GemError> {
+        self.add_file_with_progress(file_path, mime_type, |_, _| {})
+            .await
+    }
+
+    /// Like `add_file_with_mime_type`, calling `progress` with the bytes uploaded so far
+    /// and the total after every uploaded chunk. See `with_upload_chunk_size`.
+    pub async fn add_file_with_progress<F>(
+        &self,
+        file_path: &Path,
+        mime_type: Option<&str>,
+        progress: F,
+    ) -> Result<FileData, GemError>
+    where
+        F: FnMut(usize, usize) + Send,
+    {
         if !file_path.exists() {
             return Err(GemError::FileError("File does not exist".to_string()));
         }
@@ -508,27 +1782,17 @@ impl FileManager {
             Err(e) => return Err(GemError::FileError(e.to_string())),
         };
 
-        let mime_type = match get_mime_type(file_path) {
-            Some(ext) => ext,
+        let mime_type = match mime_type
+            .map(str::to_string)
+            .or_else(|| get_mime_type(file_path))
+            .or_else(|| sniff_mime_type(&buffer).map(str::to_string))
+        {
+            Some(mime_type) => mime_type,
             None => return Err(GemError::FileError("Unsupported file type".to_string())),
         };
 
-        let hash = sha256::digest(&buffer);
-
-        match self.get_file(&hash).await {
-            Some(file) => Ok(file),
-            None => {
-                let file = File::new(file_name, buffer, &mime_type, &self.api_key).await?;
-                let mime_type = file.mime_type.clone();
-                let file_uri = file.uri.clone();
-                let mut files = self.files.lock().await;
-                files.insert(hash, file);
-                Ok(FileData {
-                    mime_type: mime_type,
-                    file_uri: file_uri,
-                })
-            }
-        }
+        self.add_file_from_bytes_with_progress(file_name, buffer, &mime_type, progress)
+            .await
     }
 
     pub async fn check_file(&self, hash: &str) -> bool {
@@ -565,20 +1829,20 @@ impl FileManager {
         for hash in to_remove {
             let file = files.remove(&hash);
             if let Some(file) = file {
-                let _ = file.delete().await;
+                let _ = file.delete(&self.base_url).await;
             }
         }
 
         None
     }
 
-    pub async fn fetch_list(&mut self) -> Result<HashMap<String, File>, GemError> {
+    pub async fn fetch_list(&self) -> Result<HashMap<String, File>, GemError> {
         let client = reqwest::Client::new();
         let mut files = Vec::new();
         let mut page_token: Option<String> = None;
 
         loop {
-            let mut request = client.get("https://generativelanguage.googleapis.com/v1beta/files");
+            let mut request = client.get(format!("{}v1beta/files", self.base_url));
 
             if let Some(token) = &page_token {
                 request = request.query(&[("pageToken", token), ("key", &self.api_key)]);
@@ -642,21 +1906,21 @@ impl FileManager {
         Ok(files_map_clone)
     }
 
-    pub async fn delete_file(&mut self, hash: &str) -> Result<(), GemError> {
+    pub async fn delete_file(&self, hash: &str) -> Result<(), GemError> {
         let mut files = self.files.lock().await;
         let file = files.remove(hash);
         match file {
-            Some(file) => file.delete().await,
+            Some(file) => file.delete(&self.base_url).await,
             None => Ok(()),
         }
     }
 
-    pub async fn clear_files(&mut self) {
+    pub async fn clear_files(&self) {
         let mut files = self.files.lock().await;
         let keys: Vec<String> = files.keys().cloned().collect();
         for key in keys {
             if let Some(file) = files.remove(&key) {
-                let _ = file.delete().await;
+                let _ = file.delete(&self.base_url).await;
             }
         }
     }
@@ -668,71 +1932,485 @@ pub struct SafetySetting {
     threshold: HarmBlockThreshold, // Enum for the harm block threshold
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl SafetySetting {
+    pub fn new(category: HarmCategory, threshold: HarmBlockThreshold) -> Self {
+        SafetySetting {
+            category,
+            threshold,
+        }
+    }
+
+    /// Builds one safety setting per entry of `map`, ordered by category.
+    pub fn from_map(map: HashMap<HarmCategory, HarmBlockThreshold>) -> Vec<SafetySetting> {
+        let mut settings: Vec<SafetySetting> = map
+            .into_iter()
+            .map(|(category, threshold)| SafetySetting::new(category, threshold))
+            .collect();
+        settings.sort_by(|a, b| a.category.cmp(&b.category));
+        settings
+    }
+
+    pub fn get_category(&self) -> &HarmCategory {
+        &self.category
+    }
+
+    pub fn get_threshold(&self) -> &HarmBlockThreshold {
+        &self.threshold
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")] // To match the JSON format
-enum HarmCategory {
+#[allow(clippy::enum_variant_names)]
+pub enum HarmCategory {
     HarmCategoryHateSpeech,
     HarmCategorySexuallyExplicit,
     HarmCategoryDangerousContent,
     HarmCategoryHarassment,
+    HarmCategoryCivicIntegrity,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Error {
-    code: i32,
-    message: String,
-    status: String,
-}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error {}: {} ({})", self.code, self.message, self.status)
+impl HarmCategory {
+    /// Parses a category name as returned by the API, e.g. `HARM_CATEGORY_HARASSMENT`.
+    ///
+    /// The `HARM_CATEGORY_` prefix is optional and case is ignored. Returns `None` for
+    /// categories this library doesn't know about.
+    pub fn from_name(name: &str) -> Option<HarmCategory> {
+        let name = name.trim().to_ascii_uppercase();
+        match name.strip_prefix("HARM_CATEGORY_").unwrap_or(&name) {
+            "HATE_SPEECH" => Some(HarmCategory::HarmCategoryHateSpeech),
+            "SEXUALLY_EXPLICIT" => Some(HarmCategory::HarmCategorySexuallyExplicit),
+            "DANGEROUS_CONTENT" => Some(HarmCategory::HarmCategoryDangerousContent),
+            "HARASSMENT" => Some(HarmCategory::HarmCategoryHarassment),
+            "CIVIC_INTEGRITY" => Some(HarmCategory::HarmCategoryCivicIntegrity),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "SCREAMING_SNAKE_CASE")] // To match the JSON format
-pub enum HarmBlockThreshold {
-    HarmBlockThresholdUnspecified, // Unspecified threshold
-    BlockLowAndAbove,              // Block content with NEGIGIBLE and above
-    BlockMediumAndAbove,           // Block content with NEGIGIBLE, LOW, and above
-    BlockOnlyHigh,                 // Block content with only HIGH harm probability
-    BlockNone,                     // All content will be allowed
+/// Rate limit state reported by the `X-RateLimit-*` headers of a response.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RateLimitInfo {
+    limit: Option<u64>,                 // Requests allowed in the current window
+    remaining: Option<u64>,             // Requests left in the current window
+    reset: Option<std::time::Duration>, // Time until the window resets
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct GenerationConfig {
-    stop_sequences: Option<Vec<String>>, // Optional: Up to 5 stop sequences
-    response_mime_type: Option<String>, // Optional: MIME type of the response (e.g., text/plain, application/json)
-    max_output_tokens: Option<u32>,     // Optional: Max tokens for the response up to 8192
-    temperature: Option<f32>,           // Optional: Controls randomness of the output [0.0, 2.0]
-    top_p: Option<f32>, // Optional: Maximum cumulative probability for nucleus sampling
-    top_k: Option<u32>, // Optional: Maximum number of tokens to consider for top-k sampling
-}
+impl RateLimitInfo {
+    pub fn get_limit(&self) -> Option<u64> {
+        self.limit
+    }
 
-pub struct Settings {
-    safety_settings: Option<Vec<SafetySetting>>,
-    generation_config: Option<GenerationConfig>,
-    system_instruction: Option<String>,
-    stream_max_json_size: Option<u32>,
-}
+    pub fn get_remaining(&self) -> Option<u64> {
+        self.remaining
+    }
 
-impl Settings {
-    pub fn new() -> Self {
-        Settings {
-            safety_settings: None,
-            generation_config: None,
-            system_instruction: None,
-            stream_max_json_size: Some(16384),
+    pub fn get_reset(&self) -> Option<std::time::Duration> {
+        self.reset
+    }
+
+    /// Reads the rate limit headers of a response, or `None` if it carries none.
+    ///
+    /// Both `X-RateLimit-Limit` and the `X-RateLimit-Limit-Requests` form are accepted.
+    /// The reset is given in seconds or as a duration such as `1m30s`.
+    pub(crate) fn from_headers(headers: &header::HeaderMap) -> Option<Self> {
+        let value = |name: &str| {
+            [name.to_string(), format!("{}-requests", name)]
+                .iter()
+                .find_map(|name| headers.get(name.as_str())?.to_str().ok())
+                .map(str::trim)
+        };
+        let info = RateLimitInfo {
+            limit: value("x-ratelimit-limit").and_then(|v| v.parse().ok()),
+            remaining: value("x-ratelimit-remaining").and_then(|v| v.parse().ok()),
+            reset: value("x-ratelimit-reset").and_then(parse_reset),
+        };
+        if info == RateLimitInfo::default() {
+            None
+        } else {
+            Some(info)
         }
     }
+}
 
-    pub fn set_stream_max_json_size(&mut self, size: u32) {
-        self.stream_max_json_size = Some(size);
+/// Parses a reset value given in seconds (`"30"`, `"0.5"`) or as a duration (`"1m30s"`, `"20ms"`).
+fn parse_reset(value: &str) -> Option<std::time::Duration> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return std::time::Duration::try_from_secs_f64(seconds).ok();
     }
 
-    pub fn set_all_safety_settings(&mut self, threshold: HarmBlockThreshold) {
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = &rest[number_end..];
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        total += number
+            * match &rest[..unit_end] {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                "ms" => 0.001,
+                _ => return None,
+            };
+        rest = &rest[unit_end..];
+    }
+    std::time::Duration::try_from_secs_f64(total).ok()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Error {
+    code: i32,
+    message: String,
+    status: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    details: Vec<ErrorDetail>, // Structured details, e.g. the reason for the error
+    #[serde(skip)]
+    retry_after: Option<std::time::Duration>, // From the Retry-After header of the response
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    #[serde(rename = "@type")]
+    type_url: Option<String>, // Type of the detail, e.g. `type.googleapis.com/google.rpc.ErrorInfo`
+    reason: Option<String>, // Machine-readable reason, e.g. `API_KEY_INVALID`
+}
+
+impl ErrorDetail {
+    pub fn get_type_url(&self) -> Option<&str> {
+        self.type_url.as_deref()
+    }
+
+    pub fn get_reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+}
+
+impl Error {
+    pub fn get_code(&self) -> i32 {
+        self.code
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn get_status(&self) -> &str {
+        &self.status
+    }
+
+    pub fn get_details(&self) -> &[ErrorDetail] {
+        &self.details
+    }
+
+    /// Returns `true` if one of the details gives `reason` as the reason for the error.
+    pub fn has_reason(&self, reason: &str) -> bool {
+        self.details
+            .iter()
+            .any(|detail| detail.get_reason() == Some(reason))
+    }
+
+    /// Returns how long the API asked to wait before retrying, if it did.
+    pub fn get_retry_after(&self) -> Option<std::time::Duration> {
+        self.retry_after
+    }
+
+    pub(crate) fn with_retry_after(mut self, retry_after: Option<std::time::Duration>) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error {}: {} ({})", self.code, self.message, self.status)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")] // To match the JSON format
+pub enum HarmBlockThreshold {
+    HarmBlockThresholdUnspecified, // Unspecified threshold
+    BlockLowAndAbove,              // Block content with NEGIGIBLE and above
+    BlockMediumAndAbove,           // Block content with NEGIGIBLE, LOW, and above
+    BlockOnlyHigh,                 // Block content with only HIGH harm probability
+    BlockNone,                     // All content will be allowed
+}
+
+/// Response MIME type for JSON output.
+pub const RESPONSE_MIME_TYPE_JSON: &str = "application/json";
+
+/// Response MIME type for output constrained to one of the values of an enum schema.
+pub const RESPONSE_MIME_TYPE_ENUM: &str = "text/x.enum";
+
+/// Types that can describe their JSON representation as a response schema.
+///
+/// Used by `GemSession::extract` to constrain the response to the shape of `Self`.
+pub trait JsonSchema {
+    /// Returns the OpenAPI schema object describing `Self`, as accepted by `responseSchema`.
+    fn json_schema() -> Value;
+}
+
+macro_rules! impl_json_schema {
+    ($type_name:literal: $($ty:ty),+) => {
+        $(impl JsonSchema for $ty {
+            fn json_schema() -> Value {
+                json!({ "type": $type_name })
+            }
+        })+
+    };
+}
+
+impl_json_schema!("string": String);
+impl_json_schema!("boolean": bool);
+impl_json_schema!("integer": i32, i64, u32, u64);
+impl_json_schema!("number": f32, f64);
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn json_schema() -> Value {
+        json!({ "type": "array", "items": T::json_schema() })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn json_schema() -> Value {
+        let mut schema = T::json_schema();
+        schema["nullable"] = json!(true);
+        schema
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>, // Optional: Up to 5 stop sequences
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>, // Optional: MIME type of the response (e.g., text/plain, application/json)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>, // Optional: Max tokens for the response up to 8192
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>, // Optional: Controls randomness of the output [0.0, 2.0]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>, // Optional: Maximum cumulative probability for nucleus sampling [0.0, 1.0]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>, // Optional: Maximum number of tokens to consider for top-k sampling
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_modalities: Option<Vec<Modality>>, // Optional: Modalities the response may contain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_config: Option<ThinkingConfig>, // Optional: Thinking settings for 2.5 models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>, // Optional: Schema the JSON response must follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>, // Optional: Penalizes tokens that already appeared in the response [-2.0, 2.0]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>, // Optional: Penalizes tokens by how often they appeared in the response [-2.0, 2.0]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i32>, // Optional: Seed used for decoding, for reproducible output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_logprobs: Option<bool>, // Optional: Whether to return the log-probabilities of the output tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u32>, // Optional: Number of top tokens returned per step with response_logprobs, up to 20
+}
+
+impl GenerationConfig {
+    /// Returns a config with the fields set in `self` taking precedence over those in `base`.
+    pub fn merged_over(&self, base: Option<&GenerationConfig>) -> GenerationConfig {
+        let Some(base) = base else {
+            return self.clone();
+        };
+        let base = base.clone();
+        GenerationConfig {
+            stop_sequences: self.stop_sequences.clone().or(base.stop_sequences),
+            response_mime_type: self.response_mime_type.clone().or(base.response_mime_type),
+            max_output_tokens: self.max_output_tokens.or(base.max_output_tokens),
+            temperature: self.temperature.or(base.temperature),
+            top_p: self.top_p.or(base.top_p),
+            top_k: self.top_k.or(base.top_k),
+            response_modalities: self
+                .response_modalities
+                .clone()
+                .or(base.response_modalities),
+            thinking_config: self.thinking_config.clone().or(base.thinking_config),
+            response_schema: self.response_schema.clone().or(base.response_schema),
+            presence_penalty: self.presence_penalty.or(base.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(base.frequency_penalty),
+            seed: self.seed.or(base.seed),
+            response_logprobs: self.response_logprobs.or(base.response_logprobs),
+            logprobs: self.logprobs.or(base.logprobs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ThinkingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<i32>, // Thinking tokens budget; -1 is dynamic, 0 disables thinking
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_thoughts: Option<bool>, // Whether thought parts are returned in the response
+}
+
+impl ThinkingConfig {
+    /// Budget value that lets the model decide how much to think.
+    pub const DYNAMIC_BUDGET: i32 = -1;
+
+    /// Budget value that disables thinking on models that allow it.
+    pub const OFF_BUDGET: i32 = 0;
+
+    /// Lets the model adjust its thinking budget to the complexity of the request.
+    pub fn dynamic() -> Self {
+        Self::budget(Self::DYNAMIC_BUDGET)
+    }
+
+    /// Disables thinking. Not every model accepts this.
+    pub fn off() -> Self {
+        Self::budget(Self::OFF_BUDGET)
+    }
+
+    /// Uses a fixed thinking budget of `tokens`.
+    pub fn budget(tokens: i32) -> Self {
+        ThinkingConfig {
+            thinking_budget: Some(tokens),
+            include_thoughts: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")] // To match the JSON format
+pub enum Modality {
+    Text,
+    Image,
+    Audio,
+}
+
+impl std::fmt::Display for Modality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Modality::Text => write!(f, "TEXT"),
+            Modality::Image => write!(f, "IMAGE"),
+            Modality::Audio => write!(f, "AUDIO"),
+        }
+    }
+}
+
+/// Service tier requested for a call.
+///
+/// Only Vertex AI honors it, through the `X-Vertex-AI-LLM-Request-Type` header, which selects
+/// between Provisioned Throughput (`Dedicated`) and pay-as-you-go capacity (`Shared`). The
+/// Gemini Developer API has no priority parameter, so it is not sent there and a warning is logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestPriority {
+    Dedicated, // Only use Provisioned Throughput
+    Shared,    // Only use pay-as-you-go capacity
+}
+
+impl RequestPriority {
+    /// Header that carries the priority on Vertex AI.
+    pub const HEADER: &'static str = "X-Vertex-AI-LLM-Request-Type";
+
+    /// Returns the value of the priority header.
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            RequestPriority::Dedicated => "dedicated",
+            RequestPriority::Shared => "shared",
+        }
+    }
+
+    /// Returns `true` if the backend at `url` honors the priority header.
+    pub fn is_supported_by(url: &str) -> bool {
+        url.contains("aiplatform.googleapis.com")
+    }
+}
+
+/// Per-request settings, serializable with `to_json` and `from_json` so they can live in
+/// a config file. The stream stop predicate is code and, like the stream chunk timeout, is
+/// not serialized.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    safety_settings: Option<Vec<SafetySetting>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Vec<Part>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stream_max_json_size: Option<u32>,
+    #[serde(skip)]
+    stream_stop_when: Option<StreamStopPredicate>,
+    #[serde(skip)]
+    stream_chunk_timeout: Option<std::time::Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Tool>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_priority: Option<RequestPriority>,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Settings {
+            safety_settings: None,
+            generation_config: None,
+            system_instruction: None,
+            stream_max_json_size: Some(16384),
+            stream_stop_when: None,
+            stream_chunk_timeout: None,
+            tools: None,
+            request_priority: None,
+        }
+    }
+
+    /// Serializes the settings to JSON.
+    pub fn to_json(&self) -> Result<String, GemError> {
+        serde_json::to_string_pretty(self).map_err(GemError::ParsingError)
+    }
+
+    /// Loads settings saved with `to_json`. Missing fields are left unset.
+    pub fn from_json(json: &str) -> Result<Settings, GemError> {
+        serde_json::from_str(json).map_err(GemError::ParsingError)
+    }
+
+    pub fn set_stream_max_json_size(&mut self, size: u32) {
+        self.stream_max_json_size = Some(size);
+    }
+
+    /// Ends streamed responses as soon as `predicate` returns `true` for the text received so far.
+    ///
+    /// The stream stops yielding chunks and the connection is dropped, but the server may keep
+    /// generating (and billing) for a short while after that.
+    pub fn set_stream_stop_when<F>(&mut self, predicate: F)
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.stream_stop_when = Some(Arc::new(predicate));
+    }
+
+    pub(crate) fn get_stream_stop_when(&self) -> Option<StreamStopPredicate> {
+        self.stream_stop_when.clone()
+    }
+
+    /// Ends streamed responses with `GemError::Timeout` when no chunk arrives within
+    /// `timeout` of the previous one, or of the response headers for the first chunk.
+    ///
+    /// Unlike the session's read timeout, this bounds the gap between parsed chunks, so a
+    /// server that keeps the connection busy without completing a chunk is caught too.
+    pub fn set_stream_chunk_timeout(&mut self, timeout: std::time::Duration) {
+        self.stream_chunk_timeout = Some(timeout);
+    }
+
+    pub(crate) fn get_stream_chunk_timeout(&self) -> Option<std::time::Duration> {
+        self.stream_chunk_timeout
+    }
+
+    pub fn set_all_safety_settings(&mut self, threshold: HarmBlockThreshold) {
         self.safety_settings = Some(vec![
             SafetySetting {
                 category: HarmCategory::HarmCategoryHateSpeech,
@@ -753,6 +2431,43 @@ impl Settings {
         ]);
     }
 
+    /// Applies Google's recommended baseline, blocking content with a medium or high
+    /// probability of harm (`BlockMediumAndAbove`) in every category, civic integrity
+    /// included.
+    ///
+    /// Unlike `set_all_safety_settings(HarmBlockThreshold::BlockNone)`, which turns the
+    /// filters off, this keeps the API's safety filtering on.
+    pub fn use_recommended_safety(&mut self) {
+        self.safety_settings = Some(
+            [
+                HarmCategory::HarmCategoryHateSpeech,
+                HarmCategory::HarmCategorySexuallyExplicit,
+                HarmCategory::HarmCategoryDangerousContent,
+                HarmCategory::HarmCategoryHarassment,
+                HarmCategory::HarmCategoryCivicIntegrity,
+            ]
+            .into_iter()
+            .map(|category| SafetySetting::new(category, HarmBlockThreshold::BlockMediumAndAbove))
+            .collect(),
+        );
+    }
+
+    pub fn set_safety_settings_map(&mut self, map: HashMap<HarmCategory, HarmBlockThreshold>) {
+        self.safety_settings = Some(SafetySetting::from_map(map));
+    }
+
+    /// Sets the block threshold of a single harm category, keeping the other categories.
+    pub fn set_safety_setting(&mut self, category: HarmCategory, threshold: HarmBlockThreshold) {
+        let settings = self.safety_settings.get_or_insert_with(Vec::new);
+        match settings
+            .iter_mut()
+            .find(|setting| setting.category == category)
+        {
+            Some(setting) => setting.threshold = threshold,
+            None => settings.push(SafetySetting::new(category, threshold)),
+        }
+    }
+
     pub fn set_advance_settings(
         &mut self,
         stop_sequences: Option<Vec<String>>,
@@ -763,12 +2478,20 @@ impl Settings {
         top_k: Option<u32>,
     ) {
         self.generation_config = Some(GenerationConfig {
-            stop_sequences: stop_sequences,
-            response_mime_type: response_mime_type,
-            max_output_tokens: max_output_tokens,
-            temperature: temperature,
-            top_p: top_p,
-            top_k: top_k,
+            stop_sequences,
+            response_mime_type,
+            max_output_tokens,
+            temperature,
+            top_p,
+            top_k,
+            response_modalities: None,
+            thinking_config: None,
+            response_schema: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            response_logprobs: None,
+            logprobs: None,
         });
     }
 
@@ -777,12 +2500,8 @@ impl Settings {
             Some(config) => config.temperature = Some(temperature),
             None => {
                 self.generation_config = Some(GenerationConfig {
-                    stop_sequences: None,
-                    response_mime_type: None,
-                    max_output_tokens: None,
                     temperature: Some(temperature),
-                    top_p: None,
-                    top_k: None,
+                    ..Default::default()
                 });
             }
         }
@@ -793,274 +2512,3299 @@ impl Settings {
             Some(config) => config.max_output_tokens = Some(max_output_tokens),
             None => {
                 self.generation_config = Some(GenerationConfig {
-                    stop_sequences: None,
-                    response_mime_type: None,
                     max_output_tokens: Some(max_output_tokens),
-                    temperature: None,
-                    top_p: None,
-                    top_k: None,
+                    ..Default::default()
                 });
             }
         }
     }
 
-    pub fn set_system_instruction(&mut self, instruction: &str) {
-        self.system_instruction = Some(instruction.to_string());
+    pub fn set_top_p(&mut self, top_p: f32) {
+        match &mut self.generation_config {
+            Some(config) => config.top_p = Some(top_p),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    top_p: Some(top_p),
+                    ..Default::default()
+                });
+            }
+        }
     }
 
-    pub fn get_stream_max_json_size(&self) -> u32 {
-        self.stream_max_json_size.unwrap_or(16384)
+    pub fn set_top_k(&mut self, top_k: u32) {
+        match &mut self.generation_config {
+            Some(config) => config.top_k = Some(top_k),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    top_k: Some(top_k),
+                    ..Default::default()
+                });
+            }
+        }
     }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct GenerateContentRequest {
-    contents: Vec<Content>, // Required: List of content objects (conversation history and latest request)
-    safety_settings: Option<Vec<SafetySetting>>, // Optional: Safety settings to block unsafe content
-    generation_config: Option<GenerationConfig>, // Optional: Configuration for model generation
-    system_instruction: Option<NoRoleContent>,   // Optional: Developer set system instructions
-}
 
-impl GenerateContentRequest {
-    fn new(
-        context: &Context,
-        config: Option<GenerationConfig>,
-        safety: Option<Vec<SafetySetting>>,
-        system_instruction: Option<NoRoleContent>,
-    ) -> Self {
-        GenerateContentRequest {
-            contents: context.contents.clone(),
-            safety_settings: match safety {
-                Some(s) => Some(s),
-                None => Some(vec![
-                    SafetySetting {
-                        category: HarmCategory::HarmCategoryHateSpeech,
-                        threshold: HarmBlockThreshold::BlockNone,
-                    },
-                    SafetySetting {
-                        category: HarmCategory::HarmCategorySexuallyExplicit,
-                        threshold: HarmBlockThreshold::BlockNone,
-                    },
-                    SafetySetting {
-                        category: HarmCategory::HarmCategoryDangerousContent,
-                        threshold: HarmBlockThreshold::BlockNone,
-                    },
-                    SafetySetting {
-                        category: HarmCategory::HarmCategoryHarassment,
-                        threshold: HarmBlockThreshold::BlockNone,
-                    },
-                ]),
-            },
-            generation_config: match config {
-                Some(c) => Some(c),
-                None => Some(GenerationConfig {
-                    max_output_tokens: Some(8192),
-                    temperature: Some(1.0),
-                    response_mime_type: None,
-                    stop_sequences: None,
-                    top_k: None,
-                    top_p: None,
-                }),
-            },
-            system_instruction,
+    pub fn set_presence_penalty(&mut self, presence_penalty: f32) {
+        match &mut self.generation_config {
+            Some(config) => config.presence_penalty = Some(presence_penalty),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    presence_penalty: Some(presence_penalty),
+                    ..Default::default()
+                });
+            }
         }
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Context {
-    contents: Vec<Content>,
-}
+    pub fn set_frequency_penalty(&mut self, frequency_penalty: f32) {
+        match &mut self.generation_config {
+            Some(config) => config.frequency_penalty = Some(frequency_penalty),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    frequency_penalty: Some(frequency_penalty),
+                    ..Default::default()
+                });
+            }
+        }
+    }
 
-impl Context {
-    pub fn new() -> Self {
-        Context {
-            contents: Vec::new(),
+    pub fn set_seed(&mut self, seed: i32) {
+        match &mut self.generation_config {
+            Some(config) => config.seed = Some(seed),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    seed: Some(seed),
+                    ..Default::default()
+                });
+            }
         }
     }
 
-    pub fn push_message(&mut self, role: Role, content: String) {
-        self.contents.push(Content {
-            role: Some(role),
-            parts: vec![Part {
-                data: PartData::Text {
-                    text: content.to_string(),
-                },
-            }],
+    /// Requests the log-probabilities of the output tokens, available through
+    /// `Candidate::get_logprobs`.
+    pub fn set_response_logprobs(&mut self, response_logprobs: bool) {
+        match &mut self.generation_config {
+            Some(config) => config.response_logprobs = Some(response_logprobs),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    response_logprobs: Some(response_logprobs),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    /// Sets the number of top tokens returned per decoding step with `set_response_logprobs`.
+    pub fn set_logprobs(&mut self, logprobs: u32) {
+        match &mut self.generation_config {
+            Some(config) => config.logprobs = Some(logprobs),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    logprobs: Some(logprobs),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    pub fn set_response_modalities(&mut self, modalities: Vec<Modality>) {
+        match &mut self.generation_config {
+            Some(config) => config.response_modalities = Some(modalities),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    response_modalities: Some(modalities),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    pub fn set_thinking_config(&mut self, thinking_config: ThinkingConfig) {
+        match &mut self.generation_config {
+            Some(config) => config.thinking_config = Some(thinking_config),
+            None => {
+                self.generation_config = Some(GenerationConfig {
+                    thinking_config: Some(thinking_config),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    /// Sets the number of tokens the model may spend thinking, keeping `include_thoughts`.
+    ///
+    /// Use `ThinkingConfig::DYNAMIC_BUDGET` to let the model decide.
+    pub fn set_thinking_budget(&mut self, tokens: i32) {
+        let mut thinking_config = self.get_thinking_config().unwrap_or_default();
+        thinking_config.thinking_budget = Some(tokens);
+        self.set_thinking_config(thinking_config);
+    }
+
+    /// Sets whether thought parts are returned in the response, keeping the thinking budget.
+    pub fn set_include_thoughts(&mut self, include_thoughts: bool) {
+        let mut thinking_config = self.get_thinking_config().unwrap_or_default();
+        thinking_config.include_thoughts = Some(include_thoughts);
+        self.set_thinking_config(thinking_config);
+    }
+
+    fn get_thinking_config(&self) -> Option<ThinkingConfig> {
+        self.generation_config
+            .as_ref()
+            .and_then(|config| config.thinking_config.clone())
+    }
+
+    /// Clears the sampling temperature, so the model's default is used.
+    pub fn clear_temperature(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.temperature = None;
+        }
+    }
+
+    /// Clears the maximum number of tokens in the response, so the model's default is used.
+    pub fn clear_max_output_tokens(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.max_output_tokens = None;
+        }
+    }
+
+    /// Clears the nucleus sampling probability, so the model's default is used.
+    pub fn clear_top_p(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.top_p = None;
+        }
+    }
+
+    /// Clears the number of tokens considered for top-k sampling, so the model's default is used.
+    pub fn clear_top_k(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.top_k = None;
+        }
+    }
+
+    /// Clears the stop sequences, so the model's default is used.
+    pub fn clear_stop_sequences(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.stop_sequences = None;
+        }
+    }
+
+    /// Clears the response modalities, so the model's default is used.
+    pub fn clear_response_modalities(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.response_modalities = None;
+        }
+    }
+
+    /// Clears the thinking config, so the model's default is used.
+    pub fn clear_thinking_config(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.thinking_config = None;
+        }
+    }
+
+    /// Clears the response schema. The response MIME type is left unchanged.
+    pub fn clear_response_schema(&mut self) {
+        if let Some(config) = &mut self.generation_config {
+            config.response_schema = None;
+        }
+    }
+
+    /// Sets the schema the JSON response must follow.
+    ///
+    /// The response MIME type is switched to `application/json` unless it was set to a
+    /// JSON or enum type already, since the schema is ignored for plain text responses.
+    pub fn set_response_schema(&mut self, schema: Value) {
+        let config = self
+            .generation_config
+            .get_or_insert_with(GenerationConfig::default);
+        if !matches!(
+            config.response_mime_type.as_deref(),
+            Some(RESPONSE_MIME_TYPE_JSON | RESPONSE_MIME_TYPE_ENUM)
+        ) {
+            config.response_mime_type = Some(RESPONSE_MIME_TYPE_JSON.to_string());
+        }
+        config.response_schema = Some(schema);
+    }
+
+    /// Checks the settings against the capabilities of `model`.
+    pub(crate) fn validate_for_model(&self, model: &Models) -> Result<(), GemError> {
+        self.validate_generation_config(model)?;
+        self.validate_response_modalities(model)?;
+        self.validate_thinking_budget(model)?;
+        self.validate_response_schema()?;
+        self.validate_tools()
+    }
+
+    /// Checks the parameter schemas of every function declared in the tools, and that
+    /// dynamic retrieval thresholds are between 0.0 and 1.0.
+    pub(crate) fn validate_tools(&self) -> Result<(), GemError> {
+        let tools = self.tools.iter().flatten();
+        for tool in tools.clone() {
+            let threshold = tool
+                .google_search_retrieval
+                .as_ref()
+                .and_then(|retrieval| retrieval.dynamic_retrieval_config.as_ref())
+                .and_then(|config| config.dynamic_threshold);
+            if let Some(threshold) = threshold {
+                if !(0.0..=1.0).contains(&threshold) {
+                    return Err(GemError::InvalidConfig(format!(
+                        "Dynamic retrieval threshold {} is outside the range 0.0..=1.0",
+                        threshold
+                    )));
+                }
+            }
+        }
+        tools
+            .flat_map(|tool| tool.function_declarations.iter().flatten())
+            .try_for_each(FunctionDeclaration::validate)
+    }
+
+    /// Checks that the sampling parameters are within the ranges accepted by the API, and
+    /// that `max_output_tokens` fits the output limit of `model` when it is known.
+    pub(crate) fn validate_generation_config(&self, model: &Models) -> Result<(), GemError> {
+        let config = match &self.generation_config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        check_range("Temperature", config.temperature, 0.0..=2.0)?;
+        check_range("Top P", config.top_p, 0.0..=1.0)?;
+        check_range("Presence penalty", config.presence_penalty, -2.0..=2.0)?;
+        check_range("Frequency penalty", config.frequency_penalty, -2.0..=2.0)?;
+
+        if let Some(stop_sequences) = &config.stop_sequences {
+            if stop_sequences.len() > MAX_STOP_SEQUENCES {
+                return Err(GemError::InvalidConfig(format!(
+                    "{} stop sequences were given, at most {} are allowed",
+                    stop_sequences.len(),
+                    MAX_STOP_SEQUENCES
+                )));
+            }
+        }
+        if let Some(logprobs) = config.logprobs {
+            if logprobs > MAX_LOGPROBS {
+                return Err(GemError::InvalidConfig(format!(
+                    "Logprobs {} is over the maximum of {}",
+                    logprobs, MAX_LOGPROBS
+                )));
+            }
+        }
+        if let Some(max_output_tokens) = config.max_output_tokens {
+            let limit = model.token_limits().map(|(_, output)| output);
+            if max_output_tokens == 0 || limit.is_some_and(|limit| max_output_tokens > limit) {
+                return Err(GemError::InvalidConfig(format!(
+                    "Max output tokens {} is outside the range 1..={} supported by {}",
+                    max_output_tokens,
+                    limit.unwrap_or(u32::MAX),
+                    model
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a response schema is only combined with a MIME type that honours it.
+    pub(crate) fn validate_response_schema(&self) -> Result<(), GemError> {
+        let config = match &self.generation_config {
+            Some(config) if config.response_schema.is_some() => config,
+            _ => return Ok(()),
+        };
+
+        match config.response_mime_type.as_deref() {
+            Some(RESPONSE_MIME_TYPE_JSON | RESPONSE_MIME_TYPE_ENUM) => Ok(()),
+            other => Err(GemError::InvalidConfig(format!(
+                "A response schema requires the response MIME type to be {} or {}, got {}",
+                RESPONSE_MIME_TYPE_JSON,
+                RESPONSE_MIME_TYPE_ENUM,
+                other.unwrap_or("none")
+            ))),
+        }
+    }
+
+    /// Checks that a fixed thinking budget is within the bounds supported by `model`.
+    ///
+    /// The dynamic sentinel is always accepted, and models without known bounds are not checked.
+    pub(crate) fn validate_thinking_budget(&self, model: &Models) -> Result<(), GemError> {
+        let budget = match self
+            .generation_config
+            .as_ref()
+            .and_then(|config| config.thinking_config.as_ref())
+            .and_then(|thinking| thinking.thinking_budget)
+        {
+            Some(budget) if budget != ThinkingConfig::DYNAMIC_BUDGET => budget,
+            _ => return Ok(()),
+        };
+
+        match model.thinking_budget_range() {
+            Some(range) if !range.contains(&budget) => Err(GemError::InvalidConfig(format!(
+                "Thinking budget {} is outside the range {}..={} supported by {}",
+                budget,
+                range.start(),
+                range.end(),
+                model
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that every requested response modality is supported by `model`.
+    pub(crate) fn validate_response_modalities(&self, model: &Models) -> Result<(), GemError> {
+        let modalities = match self
+            .generation_config
+            .as_ref()
+            .and_then(|config| config.response_modalities.as_ref())
+        {
+            Some(modalities) => modalities,
+            None => return Ok(()),
+        };
+
+        for modality in modalities {
+            if !model.supports_response_modality(modality) {
+                return Err(GemError::InvalidConfig(format!(
+                    "Model {} does not support the {} response modality",
+                    model, modality
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the `max_output_tokens` a request would use, taking `default_config` when
+    /// the settings have no generation config.
+    pub(crate) fn effective_max_output_tokens(
+        &self,
+        default_config: Option<&GenerationConfig>,
+    ) -> Option<u32> {
+        self.generation_config
+            .as_ref()
+            .or(default_config)
+            .and_then(|config| config.max_output_tokens)
+    }
+
+    /// Returns a copy of the settings using `default_config` when they have no generation
+    /// config, as the request built from them does.
+    pub(crate) fn with_default_generation_config(
+        &self,
+        default_config: Option<&GenerationConfig>,
+    ) -> Settings {
+        Settings {
+            generation_config: self
+                .generation_config
+                .clone()
+                .or_else(|| default_config.cloned()),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the settings with `config` merged over their generation config,
+    /// falling back to `default_config` when the settings have none.
+    pub(crate) fn with_generation_config_override(
+        &self,
+        config: &GenerationConfig,
+        default_config: Option<&GenerationConfig>,
+    ) -> Settings {
+        let base = self.generation_config.as_ref().or(default_config);
+        Settings {
+            generation_config: Some(config.merged_over(base)),
+            ..self.clone()
+        }
+    }
+
+    pub fn set_tools(&mut self, tools: Vec<Tool>) {
+        self.tools = Some(tools);
+    }
+
+    /// Adds the Google Search tool, so the model can ground its answers with search results.
+    ///
+    /// Sources are available through `Candidate::get_grounding_metadata`.
+    pub fn enable_google_search(&mut self) {
+        let tools = self.tools.get_or_insert_with(Vec::new);
+        if !tools.iter().any(|tool| tool.google_search.is_some()) {
+            tools.push(Tool::google_search());
+        }
+    }
+
+    /// Adds the Google Search retrieval tool in dynamic mode, so the model only searches
+    /// when it predicts the answer would benefit from it by more than `threshold`.
+    ///
+    /// Lower thresholds ground more answers, at the cost of more searches. The threshold
+    /// must be between 0.0 and 1.0; it is checked when the request is sent. Calling this
+    /// again replaces the threshold.
+    pub fn enable_google_search_dynamic(&mut self, threshold: f32) {
+        let tools = self.tools.get_or_insert_with(Vec::new);
+        tools.retain(|tool| tool.google_search_retrieval.is_none());
+        tools.push(Tool::google_search_dynamic(threshold));
+    }
+
+    /// Requests a service tier for the call. See `RequestPriority` for the backends that honor it.
+    pub fn set_request_priority(&mut self, priority: RequestPriority) {
+        self.request_priority = Some(priority);
+    }
+
+    pub fn get_request_priority(&self) -> Option<RequestPriority> {
+        self.request_priority
+    }
+
+    pub fn set_system_instruction(&mut self, instruction: &str) {
+        self.system_instruction = Some(vec![Part::text(instruction)]);
+    }
+
+    /// Sets a system instruction made of several parts, e.g. text along with a file or blob.
+    pub fn set_system_instruction_parts(&mut self, parts: Vec<Part>) {
+        self.system_instruction = Some(parts);
+    }
+
+    pub fn get_stream_max_json_size(&self) -> u32 {
+        self.stream_max_json_size.unwrap_or(16384)
+    }
+}
+
+impl Settings {
+    /// Creates a `SettingsBuilder` for configuring `Settings` in a single expression.
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder(Settings::new())
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fluent builder for `Settings`, created with `Settings::builder()`.
+///
+/// Each method calls the matching `Settings::set_*` method.
+pub struct SettingsBuilder(Settings);
+
+impl SettingsBuilder {
+    /// Sets the sampling temperature.
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.0.set_temperature(temperature);
+        self
+    }
+
+    /// Sets the maximum number of tokens in the response.
+    pub fn max_output_tokens(mut self, max_output_tokens: u32) -> Self {
+        self.0.set_max_output_tokens(max_output_tokens);
+        self
+    }
+
+    /// Sets the nucleus sampling probability.
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.0.set_top_p(top_p);
+        self
+    }
+
+    /// Sets the number of tokens considered for top-k sampling.
+    pub fn top_k(mut self, top_k: u32) -> Self {
+        self.0.set_top_k(top_k);
+        self
+    }
+
+    /// Sets the presence penalty.
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.0.set_presence_penalty(presence_penalty);
+        self
+    }
+
+    /// Sets the frequency penalty.
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.0.set_frequency_penalty(frequency_penalty);
+        self
+    }
+
+    /// Sets the seed used for decoding.
+    pub fn seed(mut self, seed: i32) -> Self {
+        self.0.set_seed(seed);
+        self
+    }
+
+    /// Requests the log-probabilities of the output tokens.
+    pub fn response_logprobs(mut self, response_logprobs: bool) -> Self {
+        self.0.set_response_logprobs(response_logprobs);
+        self
+    }
+
+    /// Sets the number of top tokens returned per decoding step.
+    pub fn logprobs(mut self, logprobs: u32) -> Self {
+        self.0.set_logprobs(logprobs);
+        self
+    }
+
+    /// Sets the modalities the response may contain.
+    pub fn response_modalities(mut self, modalities: Vec<Modality>) -> Self {
+        self.0.set_response_modalities(modalities);
+        self
+    }
+
+    /// Sets the thinking config.
+    pub fn thinking_config(mut self, thinking_config: ThinkingConfig) -> Self {
+        self.0.set_thinking_config(thinking_config);
+        self
+    }
+
+    /// Sets the thinking budget in tokens.
+    pub fn thinking_budget(mut self, tokens: i32) -> Self {
+        self.0.set_thinking_budget(tokens);
+        self
+    }
+
+    /// Sets whether thought parts are returned in the response.
+    pub fn include_thoughts(mut self, include_thoughts: bool) -> Self {
+        self.0.set_include_thoughts(include_thoughts);
+        self
+    }
+
+    /// Sets the schema the JSON response must follow.
+    pub fn response_schema(mut self, schema: Value) -> Self {
+        self.0.set_response_schema(schema);
+        self
+    }
+
+    /// Sets a text system instruction.
+    pub fn system_instruction(mut self, instruction: &str) -> Self {
+        self.0.set_system_instruction(instruction);
+        self
+    }
+
+    /// Sets a system instruction made of several parts.
+    pub fn system_instruction_parts(mut self, parts: Vec<Part>) -> Self {
+        self.0.set_system_instruction_parts(parts);
+        self
+    }
+
+    /// Sets the same block threshold for every harm category.
+    pub fn all_safety_settings(mut self, threshold: HarmBlockThreshold) -> Self {
+        self.0.set_all_safety_settings(threshold);
+        self
+    }
+
+    /// Sets the block threshold of each harm category.
+    pub fn safety_settings_map(mut self, map: HashMap<HarmCategory, HarmBlockThreshold>) -> Self {
+        self.0.set_safety_settings_map(map);
+        self
+    }
+
+    /// Applies Google's recommended safety thresholds to every category.
+    pub fn recommended_safety(mut self) -> Self {
+        self.0.use_recommended_safety();
+        self
+    }
+
+    /// Sets the block threshold of a single harm category.
+    pub fn safety_setting(mut self, category: HarmCategory, threshold: HarmBlockThreshold) -> Self {
+        self.0.set_safety_setting(category, threshold);
+        self
+    }
+
+    /// Sets the tools the model may use.
+    pub fn tools(mut self, tools: Vec<Tool>) -> Self {
+        self.0.set_tools(tools);
+        self
+    }
+
+    /// Enables grounding with Google Search.
+    pub fn google_search(mut self) -> Self {
+        self.0.enable_google_search();
+        self
+    }
+
+    /// Adds the Google Search retrieval tool in dynamic mode. See `Settings::enable_google_search_dynamic`.
+    pub fn google_search_dynamic(mut self, threshold: f32) -> Self {
+        self.0.enable_google_search_dynamic(threshold);
+        self
+    }
+
+    /// Sets the request priority.
+    pub fn request_priority(mut self, priority: RequestPriority) -> Self {
+        self.0.set_request_priority(priority);
+        self
+    }
+
+    /// Sets the maximum size of a streamed JSON chunk.
+    pub fn stream_max_json_size(mut self, size: u32) -> Self {
+        self.0.set_stream_max_json_size(size);
+        self
+    }
+
+    /// Ends streamed responses once `predicate` matches the text received so far.
+    pub fn stream_stop_when<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.0.set_stream_stop_when(predicate);
+        self
+    }
+
+    /// Sets the longest gap allowed between streamed chunks. See `Settings::set_stream_chunk_timeout`.
+    pub fn stream_chunk_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.0.set_stream_chunk_timeout(timeout);
+        self
+    }
+
+    /// Builds the `Settings`.
+    pub fn build(self) -> Settings {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentRequest {
+    contents: Vec<Content>, // Required: List of content objects (conversation history and latest request)
+    safety_settings: Option<Vec<SafetySetting>>, // Optional: Safety settings to block unsafe content
+    generation_config: Option<GenerationConfig>, // Optional: Configuration for model generation
+    system_instruction: Option<NoRoleContent>,   // Optional: Developer set system instructions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Tool>>, // Optional: Tools the model may use to generate the response
+}
+
+impl GenerateContentRequest {
+    fn new(
+        context: &Context,
+        config: Option<GenerationConfig>,
+        safety: Option<Vec<SafetySetting>>,
+        system_instruction: Option<NoRoleContent>,
+        tools: Option<Vec<Tool>>,
+    ) -> Self {
+        GenerateContentRequest {
+            contents: context.contents.clone(),
+            safety_settings: match safety {
+                Some(s) => Some(s),
+                None => Some(vec![
+                    SafetySetting {
+                        category: HarmCategory::HarmCategoryHateSpeech,
+                        threshold: HarmBlockThreshold::BlockNone,
+                    },
+                    SafetySetting {
+                        category: HarmCategory::HarmCategorySexuallyExplicit,
+                        threshold: HarmBlockThreshold::BlockNone,
+                    },
+                    SafetySetting {
+                        category: HarmCategory::HarmCategoryDangerousContent,
+                        threshold: HarmBlockThreshold::BlockNone,
+                    },
+                    SafetySetting {
+                        category: HarmCategory::HarmCategoryHarassment,
+                        threshold: HarmBlockThreshold::BlockNone,
+                    },
+                ]),
+            },
+            generation_config: match config {
+                Some(c) => Some(c),
+                None => Some(GenerationConfig {
+                    max_output_tokens: Some(8192),
+                    temperature: Some(1.0),
+                    response_mime_type: None,
+                    stop_sequences: None,
+                    top_k: None,
+                    top_p: None,
+                    response_modalities: None,
+                    thinking_config: None,
+                    response_schema: None,
+                    presence_penalty: None,
+                    frequency_penalty: None,
+                    seed: None,
+                    response_logprobs: None,
+                    logprobs: None,
+                }),
+            },
+            system_instruction,
+            tools,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Context {
+    contents: Vec<Content>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    system_parts: Vec<Part>, // Parts pushed with `Role::System`
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context {
+            contents: Vec::new(),
+            system_parts: Vec::new(),
+        }
+    }
+
+    /// Pushes a turn made of arbitrary parts, e.g. several images followed by a question.
+    ///
+    /// Parts pushed with `Role::System` are added to the system instruction instead.
+    pub fn push_parts(&mut self, role: Option<Role>, parts: Vec<Part>) {
+        match role {
+            Some(Role::System) => self.system_parts.extend(parts),
+            role => self.contents.push(Content { role, parts }),
+        }
+    }
+
+    /// Pushes a single content, routing `Role::System` into the system instruction.
+    pub fn push_content(&mut self, content: Content) {
+        self.push_parts(content.role, content.parts);
+    }
+
+    pub fn push_message(&mut self, role: Role, content: String) {
+        self.push_parts(Some(role), vec![Part::text(&content)]);
+    }
+
+    /// Pushes a text turn from the user.
+    pub fn push_user_message(&mut self, text: &str) {
+        self.push_parts(Some(Role::User), vec![Part::text(text)]);
+    }
+
+    /// Pushes a text turn from the model, e.g. to replay a conversation or give an example answer.
+    pub fn push_model_message(&mut self, text: &str) {
+        self.push_parts(Some(Role::Model), vec![Part::text(text)]);
+    }
+
+    /// Pushes `(source_label, content)` documents as one user turn, each in a labelled block
+    /// so the model can cite its sources.
+    pub fn push_retrieved_documents(&mut self, docs: Vec<(String, String)>) {
+        if docs.is_empty() {
+            return;
+        }
+
+        let block = docs
+            .iter()
+            .map(|(source, content)| {
+                format!(
+                    "--- BEGIN DOCUMENT [{}] ---\n{}\n--- END DOCUMENT [{}] ---",
+                    source, content, source
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n");
+
+        self.push_message(Role::User, block);
+    }
+
+    pub fn push_function_response(&mut self, name: &str, response: Value) {
+        self.push_function_responses(vec![(name.to_string(), response)]);
+    }
+
+    /// Pushes the results of several function calls as a single user turn, one part per
+    /// `(name, response)` pair, as required when answering parallel function calls.
+    /// Nothing is pushed when `responses` is empty.
+    pub fn push_function_responses(&mut self, responses: Vec<(String, Value)>) {
+        if responses.is_empty() {
+            return;
+        }
+        self.contents.push(Content {
+            role: Some(Role::User),
+            parts: responses
+                .into_iter()
+                .map(|(name, response)| Part {
+                    data: PartData::FunctionResponse {
+                        function_response: FunctionResponse { name, response },
+                    },
+                    thought: None,
+                })
+                .collect(),
+        });
+    }
+
+    pub fn push_contents(&mut self, contents: Vec<Content>) {
+        for content in contents {
+            self.push_content(content);
+        }
+    }
+
+    pub fn push_file(&mut self, role: Role, file_data: FileData) {
+        self.push_parts(Some(role), vec![Part::file_data(file_data)]);
+    }
+
+    /// Pushes a file stored in Google Cloud Storage, referenced by its `gs://` URI.
+    ///
+    /// The file is not uploaded through the files API. Only Vertex AI accepts GCS URIs.
+    pub fn push_gcs_file(
+        &mut self,
+        role: Role,
+        gs_uri: &str,
+        mime_type: &str,
+    ) -> Result<(), GemError> {
+        let valid = match gs_uri.strip_prefix("gs://") {
+            Some(path) => {
+                matches!(path.split_once('/'), Some((bucket, object)) if !bucket.is_empty() && !object.is_empty())
+            }
+            None => false,
+        };
+        if !valid {
+            return Err(GemError::FileError(format!(
+                "{} is not a gs://bucket/object URI",
+                gs_uri
+            )));
+        }
+
+        self.push_file(
+            role,
+            FileData {
+                mime_type: mime_type.to_string(),
+                file_uri: gs_uri.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn push_blob(&mut self, role: Role, blob: Blob) {
+        self.push_parts(Some(role), vec![Part::blob(blob)]);
+    }
+
+    pub fn push_message_with_file(&mut self, role: Role, content: &str, file_data: FileData) {
+        self.push_parts(
+            Some(role),
+            vec![Part::text(content), Part::file_data(file_data)],
+        );
+    }
+
+    pub fn push_message_with_blob(&mut self, role: Role, content: &str, blob: Blob) {
+        self.push_parts(Some(role), vec![Part::text(content), Part::blob(blob)]);
+    }
+
+    pub fn build(&self, settings: &Settings) -> GenerateContentRequest {
+        self.build_with_default_config(settings, None)
+    }
+
+    /// Builds the request, falling back to `default_config` when `settings` has no
+    /// generation config of its own.
+    pub(crate) fn build_with_default_config(
+        &self,
+        settings: &Settings,
+        default_config: Option<&GenerationConfig>,
+    ) -> GenerateContentRequest {
+        GenerateContentRequest::new(
+            self,
+            settings
+                .generation_config
+                .clone()
+                .or_else(|| default_config.cloned()),
+            settings.safety_settings.clone(),
+            self.system_instruction(settings),
+            settings.tools.clone(),
+        )
+    }
+
+    /// Combines the system instruction of `settings` with the parts pushed as `Role::System`.
+    fn system_instruction(&self, settings: &Settings) -> Option<NoRoleContent> {
+        let mut parts = settings.system_instruction.clone().unwrap_or_default();
+        parts.extend(self.system_parts.iter().cloned());
+        if parts.is_empty() {
+            None
+        } else {
+            Some(NoRoleContent { parts })
+        }
+    }
+
+    /// Returns the size in bytes of the JSON request body that would be sent for this context.
+    pub fn estimated_request_size(&self, settings: &Settings) -> usize {
+        serde_json::to_vec(&self.build(settings))
+            .map(|body| body.len())
+            .unwrap_or(0)
+    }
+
+    /// Counts the parts of the context by type, including the parts pushed as `Role::System`.
+    pub fn content_breakdown(&self) -> ContentBreakdown {
+        let mut breakdown = ContentBreakdown {
+            turns: self.contents.len(),
+            ..Default::default()
+        };
+        let parts = self
+            .contents
+            .iter()
+            .flat_map(|content| content.parts.iter())
+            .chain(self.system_parts.iter());
+        for part in parts {
+            match &part.data {
+                PartData::Text { text } => {
+                    breakdown.text_parts += 1;
+                    breakdown.text_length += text.chars().count();
+                }
+                PartData::FileData { .. } => breakdown.file_parts += 1,
+                PartData::InlineData { inline_data } => {
+                    breakdown.blob_parts += 1;
+                    breakdown.blob_size += inline_data.data.len();
+                }
+                PartData::FunctionCall { .. } => breakdown.function_call_parts += 1,
+                PartData::FunctionResponse { .. } => breakdown.function_response_parts += 1,
+            }
+        }
+        breakdown
+    }
+
+    /// Returns the total size in bytes of the base64 encoded inline data in the context.
+    pub fn inline_data_size(&self) -> usize {
+        self.contents
+            .iter()
+            .flat_map(|content| content.parts.iter())
+            .chain(self.system_parts.iter())
+            .map(|part| match &part.data {
+                PartData::InlineData { inline_data } => inline_data.data.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Returns a copy of the context with only its text parts, for re-sending the
+    /// conversation to a text-only model.
+    ///
+    /// Files, inline data and function calls and responses are dropped without a
+    /// placeholder, and turns left without any text are removed. System parts are filtered
+    /// the same way.
+    pub fn text_only(&self) -> Context {
+        let is_text = |part: &&Part| matches!(part.data, PartData::Text { .. });
+        Context {
+            contents: self
+                .contents
+                .iter()
+                .map(|content| Content {
+                    parts: content.parts.iter().filter(is_text).cloned().collect(),
+                    role: content.role.clone(),
+                })
+                .filter(|content| !content.parts.is_empty())
+                .collect(),
+            system_parts: self.system_parts.iter().filter(is_text).cloned().collect(),
+        }
+    }
+
+    /// Checks that the inline data in the context fits in a single request.
+    pub(crate) fn validate_inline_data_size(&self) -> Result<(), GemError> {
+        let size = self.inline_data_size();
+        if size > MAX_INLINE_DATA_SIZE {
+            return Err(GemError::InlineDataTooLarge {
+                size,
+                limit: MAX_INLINE_DATA_SIZE,
+            });
+        }
+        Ok(())
+    }
+
+    /// Removes every turn. Parts pushed as `Role::System` are kept.
+    pub fn clear(&mut self) {
+        self.contents.clear();
+    }
+
+    /// Keeps only the last `n` turns, for a sliding window over a long conversation.
+    /// Parts pushed as `Role::System` are kept.
+    pub fn truncate_to_last(&mut self, n: usize) {
+        let excess = self.contents.len().saturating_sub(n);
+        self.contents.drain(..excess);
+    }
+
+    /// Removes and returns the turn at `index`, or `None` if it is out of bounds.
+    pub fn remove(&mut self, index: usize) -> Option<Content> {
+        if index < self.contents.len() {
+            Some(self.contents.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Removes and returns the last turn.
+    pub fn pop(&mut self) -> Option<Content> {
+        self.contents.pop()
+    }
+
+    /// Returns the number of characters in the text parts of the context, including the
+    /// parts pushed as `Role::System`.
+    pub fn total_text_len(&self) -> usize {
+        self.content_breakdown().text_length
+    }
+
+    /// Returns the parts pushed as `Role::System`.
+    pub fn get_system_parts(&self) -> &[Part] {
+        &self.system_parts
+    }
+
+    /// Removes the parts pushed as `Role::System`.
+    pub fn clear_system_parts(&mut self) {
+        self.system_parts.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.contents.len()
+    }
+
+    pub fn get_contents(&self) -> &Vec<Content> {
+        &self.contents
+    }
+
+    pub fn get_contents_mut(&mut self) -> &mut Vec<Content> {
+        &mut self.contents
+    }
+}
+
+/// Counts of the parts in a `Context` by type, returned by `Context::content_breakdown`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContentBreakdown {
+    pub turns: usize,                   // Number of turns, not counting system parts
+    pub text_parts: usize,              // Number of text parts
+    pub file_parts: usize,              // Number of file data parts
+    pub blob_parts: usize,              // Number of inline data parts
+    pub function_call_parts: usize,     // Number of function call parts
+    pub function_response_parts: usize, // Number of function response parts
+    pub text_length: usize,             // Total number of characters in text parts
+    pub blob_size: usize,               // Total size in bytes of the base64 encoded inline data
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::mock::{MockResponse, MockServer};
+
+    /// Builds a response that answers every step of an upload: the upload start (through the
+    /// upload URL header), the upload itself (through `file`), and the state poll.
+    fn upload_response(url: &str, state: &str) -> MockResponse {
+        let file = serde_json::json!({
+            "name": "files/abc123",
+            "uri": format!("{}v1beta/files/abc123", url),
+            "displayName": "notes.txt",
+            "mimeType": "text/plain",
+            "sizeBytes": "5",
+            "createTime": "2024-01-01T00:00:00Z",
+            "updateTime": "2024-01-01T00:00:00Z",
+            "expirationTime": "2999-01-01T00:00:00Z",
+            "sha256Hash": "abc123",
+            "state": state
+        });
+        let mut body = file.clone();
+        body["file"] = file;
+        MockResponse::new(200, &body.to_string())
+            .header("X-Goog-Upload-URL", &format!("{}upload", url))
+    }
+
+    #[test]
+    fn test_deserialize_generate_content_response() {
+        let json_data = r#"
+        {
+            "candidates": [
+                {
+                    "content": {
+                        "parts": [
+                            {
+                                "text": "Sample text"
+                            }
+                        ],
+                        "role": "model"
+                    },
+                    "finishReason": "STOP",
+                    "safetyRatings": [
+                        {
+                            "category": "violence",
+                            "probability": "low",
+                            "blocked": false
+                        }
+                    ],
+                    "tokenCount": 10,
+                    "index": 0
+                }
+            ],
+            "promptFeedback": {
+                "blockReason": "SAFETY",
+                "safetyRatings": [
+                    {
+                        "category": "violence",
+                        "probability": "low",
+                        "blocked": false
+                    }
+                ]
+            },
+            "usageMetadata": {
+                "promptTokenCount": 5,
+                "cachedContentTokenCount": 3,
+                "candidatesTokenCount": 10,
+                "totalTokenCount": 18
+            }
+        }
+        "#;
+
+        let response: GenerateContentResponse = serde_json::from_str(json_data).unwrap();
+
+        assert_eq!(response.candidates.len(), 1);
+        let candidate = &response.candidates[0];
+        assert_eq!(candidate.content.as_ref().unwrap().parts.len(), 1);
+        assert_eq!(
+            candidate.content.as_ref().unwrap().role.as_ref().unwrap(),
+            &Role::Model
+        );
+        assert_eq!(
+            candidate.finish_reason.as_ref().unwrap(),
+            &FinishReason::Stop
+        );
+        assert_eq!(candidate.safety_ratings.as_ref().unwrap().len(), 1);
+        assert_eq!(candidate.token_count.unwrap(), 10);
+        assert_eq!(candidate.index.unwrap(), 0);
+
+        let prompt_feedback = response.prompt_feedback.as_ref().unwrap();
+        assert_eq!(
+            prompt_feedback.block_reason.as_ref().unwrap(),
+            &BlockReason::Safety
+        );
+        assert_eq!(prompt_feedback.safety_ratings.len(), 1);
+
+        let usage_metadata = response.usage_metadata.as_ref().unwrap();
+        assert_eq!(usage_metadata.prompt_token_count.unwrap(), 5);
+        assert_eq!(usage_metadata.cached_content_token_count.unwrap(), 3);
+        assert_eq!(usage_metadata.candidates_token_count.unwrap(), 10);
+        assert_eq!(usage_metadata.total_token_count.unwrap(), 18);
+    }
+
+    #[test]
+    fn test_collect_parts() {
+        let json_data = r#"
+        {
+            "candidates": [
+                {
+                    "content": {
+                        "parts": [
+                            { "text": "Here is the image" },
+                            { "inlineData": { "mimeType": "image/png", "data": "aGVsbG8=" } }
+                        ],
+                        "role": "model"
+                    }
+                },
+                {
+                    "content": {
+                        "parts": [
+                            { "text": "And the file" },
+                            { "fileData": { "mimeType": "application/pdf", "fileUri": "files/abc" } }
+                        ],
+                        "role": "model"
+                    }
+                }
+            ]
+        }
+        "#;
+
+        let response: GenerateContentResponse = serde_json::from_str(json_data).unwrap();
+
+        let texts: Vec<&str> = response
+            .text_parts()
+            .iter()
+            .filter_map(|part| match &part.data {
+                PartData::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, vec!["Here is the image", "And the file"]);
+        assert_eq!(response.inline_data_parts().len(), 1);
+        assert_eq!(response.file_data_parts().len(), 1);
+        assert_eq!(response.collect_parts(|_| true).len(), 4);
+    }
+
+    #[test]
+    fn test_zero_temperature_serialized() {
+        let mut settings = Settings::new();
+        settings.set_temperature(0.0);
+        settings.set_top_k(1);
+
+        let request = serde_json::to_string(&Context::new().build(&settings)).unwrap();
+        assert!(request.contains(r#""temperature":0.0"#));
+        assert!(request.contains(r#""topK":1"#));
+    }
+
+    #[test]
+    fn test_push_retrieved_documents() {
+        let mut context = Context::new();
+        context.push_retrieved_documents(vec![
+            (
+                "wiki/rust".to_string(),
+                "Rust is a systems language.".to_string(),
+            ),
+            (
+                "docs/tokio".to_string(),
+                "Tokio is an async runtime.".to_string(),
+            ),
+        ]);
+
+        assert_eq!(context.len(), 1);
+        let content = &context.get_contents()[0];
+        assert_eq!(content.role, Some(Role::User));
+        assert_eq!(
+            content.get_text().unwrap(),
+            "--- BEGIN DOCUMENT [wiki/rust] ---\n\
+             Rust is a systems language.\n\
+             --- END DOCUMENT [wiki/rust] ---\n\n\
+             --- BEGIN DOCUMENT [docs/tokio] ---\n\
+             Tokio is an async runtime.\n\
+             --- END DOCUMENT [docs/tokio] ---"
+        );
+
+        context.push_retrieved_documents(Vec::new());
+        assert_eq!(context.len(), 1);
+    }
+
+    #[test]
+    fn test_estimated_request_size() {
+        let settings = Settings::new();
+        let mut context = Context::new();
+        context.push_message(Role::User, "Describe this image".to_string());
+        let text_only = context.estimated_request_size(&settings);
+        assert_eq!(
+            text_only,
+            serde_json::to_string(&context.build(&settings))
+                .unwrap()
+                .len()
+        );
+
+        context.push_blob(Role::User, Blob::new("image/png", &[0u8; 3000]));
+        let with_blob = context.estimated_request_size(&settings);
+        assert!(with_blob >= text_only + 4000);
+    }
+
+    #[test]
+    fn test_safety_settings_from_map() {
+        let mut map = HashMap::new();
+        map.insert(
+            HarmCategory::HarmCategoryHarassment,
+            HarmBlockThreshold::BlockNone,
+        );
+        map.insert(
+            HarmCategory::HarmCategoryDangerousContent,
+            HarmBlockThreshold::BlockLowAndAbove,
+        );
+
+        let safety = SafetySetting::from_map(map.clone());
+        assert_eq!(safety.len(), 2);
+        assert_eq!(
+            safety[0].get_category(),
+            &HarmCategory::HarmCategoryDangerousContent
+        );
+        assert_eq!(
+            safety[0].get_threshold(),
+            &HarmBlockThreshold::BlockLowAndAbove
+        );
+        assert_eq!(
+            safety[1].get_category(),
+            &HarmCategory::HarmCategoryHarassment
+        );
+        assert_eq!(safety[1].get_threshold(), &HarmBlockThreshold::BlockNone);
+
+        let mut settings = Settings::new();
+        settings.set_safety_settings_map(map);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["safetySettings"],
+            serde_json::json!([
+                { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "threshold": "BLOCK_LOW_AND_ABOVE" },
+                { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE" }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_role_from_str() {
+        for role in [Role::Model, Role::User, Role::System] {
+            assert_eq!(role.to_string().parse::<Role>().unwrap(), role);
+            assert_eq!(
+                serde_json::to_value(&role).unwrap(),
+                Value::String(role.to_string())
+            );
+        }
+
+        assert_eq!("assistant".parse::<Role>().unwrap(), Role::Model);
+        assert_eq!("AI".parse::<Role>().unwrap(), Role::Model);
+        assert_eq!(" Human ".parse::<Role>().unwrap(), Role::User);
+        assert_eq!("USER".parse::<Role>().unwrap(), Role::User);
+        assert!("narrator".parse::<Role>().is_err());
+        assert!("".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_settings_json_round_trip() {
+        let mut settings = Settings::builder()
+            .temperature(0.5)
+            .max_output_tokens(512)
+            .seed(3)
+            .safety_setting(
+                HarmCategory::HarmCategoryDangerousContent,
+                HarmBlockThreshold::BlockLowAndAbove,
+            )
+            .system_instruction("You are a helpful assistant.")
+            .build();
+        settings.set_stream_stop_when(|text| text.contains("STOP"));
+
+        let json = settings.to_json().unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["generationConfig"]["temperature"], 0.5);
+        assert!(value.get("streamStopWhen").is_none());
+
+        let restored = Settings::from_json(&json).unwrap();
+        let request = |settings: &Settings| {
+            let mut context = Context::new();
+            context.push_message(Role::User, "Hi".to_string());
+            serde_json::to_value(context.build(settings)).unwrap()
+        };
+        assert_eq!(request(&restored), request(&settings));
+        assert_eq!(
+            request(&restored)["generationConfig"]["maxOutputTokens"],
+            512
+        );
+        assert_eq!(
+            request(&restored)["safetySettings"][0]["threshold"],
+            "BLOCK_LOW_AND_ABOVE"
+        );
+        assert_eq!(
+            request(&restored)["systemInstruction"]["parts"][0]["text"],
+            "You are a helpful assistant."
+        );
+        assert!(restored.get_stream_stop_when().is_none());
+
+        let empty = Settings::from_json("{}").unwrap();
+        assert_eq!(empty.get_stream_max_json_size(), 16384);
+        assert!(Settings::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_use_recommended_safety() {
+        let mut settings = Settings::new();
+        settings.set_all_safety_settings(HarmBlockThreshold::BlockNone);
+        settings.use_recommended_safety();
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        let safety_settings = request["safetySettings"].as_array().unwrap();
+        assert_eq!(safety_settings.len(), 5);
+        assert!(safety_settings
+            .iter()
+            .all(|setting| setting["threshold"] == "BLOCK_MEDIUM_AND_ABOVE"));
+        assert!(safety_settings
+            .iter()
+            .any(|setting| setting["category"] == "HARM_CATEGORY_CIVIC_INTEGRITY"));
+    }
+
+    #[test]
+    fn test_set_safety_setting() {
+        let mut settings = Settings::new();
+        settings.set_all_safety_settings(HarmBlockThreshold::BlockNone);
+        settings.set_safety_setting(
+            HarmCategory::HarmCategoryDangerousContent,
+            HarmBlockThreshold::BlockLowAndAbove,
+        );
+        settings.set_safety_setting(
+            HarmCategory::HarmCategoryCivicIntegrity,
+            HarmBlockThreshold::BlockMediumAndAbove,
+        );
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["safetySettings"],
+            serde_json::json!([
+                { "category": "HARM_CATEGORY_HATE_SPEECH", "threshold": "BLOCK_NONE" },
+                { "category": "HARM_CATEGORY_SEXUALLY_EXPLICIT", "threshold": "BLOCK_NONE" },
+                { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "threshold": "BLOCK_LOW_AND_ABOVE" },
+                { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE" },
+                { "category": "HARM_CATEGORY_CIVIC_INTEGRITY", "threshold": "BLOCK_MEDIUM_AND_ABOVE" }
+            ])
+        );
+
+        let mut settings = Settings::new();
+        settings.set_safety_setting(
+            HarmCategory::HarmCategoryHarassment,
+            HarmBlockThreshold::BlockOnlyHigh,
+        );
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["safetySettings"],
+            serde_json::json!([
+                { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_ONLY_HIGH" }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_response_modalities() {
+        let mut settings = Settings::new();
+        assert!(settings
+            .validate_response_modalities(&Models::Gemini15Pro)
+            .is_ok());
+
+        settings.set_response_modalities(vec![Modality::Text, Modality::Image]);
+        match settings.validate_response_modalities(&Models::Gemini15Pro) {
+            Err(GemError::InvalidConfig(message)) => assert!(message.contains("IMAGE")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(settings
+            .validate_response_modalities(&Models::Gemini2FlashExp)
+            .is_ok());
+
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["generationConfig"]["responseModalities"],
+            serde_json::json!(["TEXT", "IMAGE"])
+        );
+    }
+
+    #[test]
+    fn test_function_call_args_as() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Weather {
+            city: String,
+            unit: Option<String>,
+        }
+
+        let part: Part = serde_json::from_str(
+            r#"{ "functionCall": { "name": "get_weather", "args": { "city": "Paris", "days": 3 } } }"#,
+        )
+        .unwrap();
+
+        let call = match part.data {
+            PartData::FunctionCall { function_call } => function_call,
+            other => panic!("unexpected part: {:?}", other),
+        };
+        assert_eq!(call.name, "get_weather");
+        assert_eq!(
+            call.args_as::<Weather>().unwrap(),
+            Weather {
+                city: "Paris".to_string(),
+                unit: None,
+            }
+        );
+
+        let missing = FunctionCall {
+            name: "get_weather".to_string(),
+            args: serde_json::json!({ "unit": "celsius" }),
+        };
+        assert!(matches!(
+            missing.args_as::<Weather>(),
+            Err(GemError::ParsingError(_))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_grounding_supports() {
+        let json_data = r#"
+        {
+            "candidates": [
+                {
+                    "content": {
+                        "parts": [{ "text": "Spain won Euro 2024." }],
+                        "role": "model"
+                    },
+                    "groundingMetadata": {
+                        "groundingSupports": [
+                            {
+                                "segment": { "endIndex": 20, "text": "Spain won Euro 2024." },
+                                "groundingChunkIndices": [0, 2],
+                                "confidenceScores": [0.95, 0.7]
+                            }
+                        ]
+                    }
+                }
+            ]
+        }
+        "#;
+
+        let response: GenerateContentResponse = serde_json::from_str(json_data).unwrap();
+        let metadata = response.get_candidates()[0]
+            .get_grounding_metadata()
+            .unwrap();
+        let supports = metadata.get_grounding_supports();
+        assert_eq!(supports.len(), 1);
+
+        let segment = supports[0].get_segment().unwrap();
+        assert_eq!(segment.get_start_index(), 0);
+        assert_eq!(segment.get_end_index(), 20);
+        assert_eq!(segment.get_text(), Some("Spain won Euro 2024."));
+        assert_eq!(supports[0].get_grounding_chunk_indices(), &[0, 2]);
+        assert_eq!(supports[0].get_confidence_scores(), &[0.95, 0.7]);
+    }
+
+    #[test]
+    fn test_truncate_at() {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{ "candidates": [{ "content": { "parts": [{ "text": "Answer: 42\nEND\nExtra ### notes" }], "role": "model" } }] }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.truncate_at(&["###".to_string(), "\nEND".to_string()]),
+            "Answer: 42"
+        );
+        assert_eq!(
+            response.truncate_at(&["###".to_string()]),
+            "Answer: 42\nEND\nExtra "
+        );
+        assert_eq!(
+            response.truncate_at(&["STOP".to_string(), String::new()]),
+            "Answer: 42\nEND\nExtra ### notes"
+        );
+    }
+
+    #[test]
+    fn test_inline_data_size_limit() {
+        let mut context = Context::new();
+        context.push_blob(Role::User, Blob::new("image/png", &[0u8; 1024]));
+        assert!(context.validate_inline_data_size().is_ok());
+
+        context.push_blob(
+            Role::User,
+            Blob::new("video/mp4", &vec![0u8; MAX_INLINE_DATA_SIZE * 3 / 4]),
+        );
+        match context.validate_inline_data_size() {
+            Err(GemError::InlineDataTooLarge { size, limit }) => {
+                assert_eq!(size, context.inline_data_size());
+                assert_eq!(limit, MAX_INLINE_DATA_SIZE);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_candidate_token_counts() {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"
+            {
+                "candidates": [
+                    { "content": { "parts": [{ "text": "A long answer" }], "role": "model" }, "tokenCount": 42, "index": 0 },
+                    { "content": { "parts": [{ "text": "Short" }], "role": "model" }, "tokenCount": 7, "index": 1 },
+                    { "content": { "parts": [{ "text": "Unknown" }], "role": "model" }, "index": 2 }
+                ]
+            }
+            "#,
+        )
+        .unwrap();
+
+        let counts: Vec<Option<i32>> = response
+            .get_candidates()
+            .iter()
+            .map(|candidate| candidate.get_token_count())
+            .collect();
+        assert_eq!(counts, vec![Some(42), Some(7), None]);
+    }
+
+    #[test]
+    fn test_push_function_responses() {
+        let mut context = Context::new();
+        context.push_function_responses(vec![
+            (
+                "get_weather".to_string(),
+                serde_json::json!({ "temperature": 21 }),
+            ),
+            (
+                "get_time".to_string(),
+                serde_json::json!({ "time": "12:00" }),
+            ),
+        ]);
+
+        assert_eq!(context.len(), 1);
+        let content = serde_json::to_value(&context.get_contents()[0]).unwrap();
+        assert_eq!(
+            content,
+            serde_json::json!({
+                "role": "user",
+                "parts": [
+                    { "function_response": { "name": "get_weather", "response": { "temperature": 21 } } },
+                    { "function_response": { "name": "get_time", "response": { "time": "12:00" } } }
+                ]
+            })
+        );
+
+        context.push_function_responses(Vec::new());
+        assert_eq!(context.len(), 1);
+    }
+
+    #[test]
+    fn test_thinking_config_sentinels() {
+        let config = |thinking: ThinkingConfig| {
+            let mut settings = Settings::new();
+            settings.set_thinking_config(thinking);
+            serde_json::to_value(Context::new().build(&settings)).unwrap()["generationConfig"]
+                ["thinkingConfig"]
+                .clone()
+        };
+
+        assert_eq!(
+            config(ThinkingConfig::dynamic()),
+            serde_json::json!({ "thinkingBudget": -1 })
+        );
+        assert_eq!(
+            config(ThinkingConfig::off()),
+            serde_json::json!({ "thinkingBudget": 0 })
+        );
+        assert_eq!(
+            config(ThinkingConfig::budget(1024)),
+            serde_json::json!({ "thinkingBudget": 1024 })
+        );
+    }
+
+    #[test]
+    fn test_validate_thinking_budget() {
+        let mut settings = Settings::new();
+        let model = Models::Gemini25ProExp;
+
+        settings.set_thinking_config(ThinkingConfig::dynamic());
+        assert!(settings.validate_for_model(&model).is_ok());
+
+        settings.set_thinking_config(ThinkingConfig::budget(2048));
+        assert!(settings.validate_for_model(&model).is_ok());
+
+        settings.set_thinking_config(ThinkingConfig::off());
+        assert!(matches!(
+            settings.validate_for_model(&model),
+            Err(GemError::InvalidConfig(_))
+        ));
+
+        settings.set_thinking_config(ThinkingConfig::budget(100_000));
+        assert!(matches!(
+            settings.validate_for_model(&model),
+            Err(GemError::InvalidConfig(_))
+        ));
+        assert!(settings
+            .validate_for_model(&Models::Custom("gemini-next".to_string()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_function_calling() {
+        let mut settings = Settings::new();
+        settings.set_tools(vec![Tool::functions(vec![FunctionDeclaration::new(
+            "get_weather",
+            "Returns the current weather for a city",
+            Some(serde_json::json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+                "required": ["city"]
+            })),
+        )])]);
+
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["tools"][0]["functionDeclarations"][0]["name"],
+            "get_weather"
+        );
+        assert_eq!(
+            request["tools"][0]["functionDeclarations"][0]["parameters"]["required"][0],
+            "city"
+        );
+        assert!(Context::new().build(&Settings::new()).tools.is_none());
+
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"
+            {
+                "candidates": [{
+                    "content": {
+                        "parts": [
+                            { "text": "Let me check." },
+                            { "functionCall": { "name": "get_weather", "args": { "city": "Oslo" } } },
+                            { "inlineData": { "mimeType": "image/png", "data": "aGVsbG8=" } }
+                        ],
+                        "role": "model"
+                    }
+                }]
+            }
+            "#,
+        )
+        .unwrap();
+
+        let calls = response.get_candidates()[0].get_function_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(calls[0].args["city"], "Oslo");
+        assert_eq!(response.function_call_parts().len(), 1);
+
+        let parts = &response.get_candidates()[0].get_content().unwrap().parts;
+        assert!(matches!(&parts[0].data, PartData::Text { text } if text == "Let me check."));
+        assert!(
+            matches!(&parts[2].data, PartData::InlineData { inline_data } if inline_data.mime_type == "image/png")
+        );
+
+        let mut context = Context::new();
+        context.push_function_response("get_weather", serde_json::json!({ "temp": 4 }));
+        let content = serde_json::to_value(&context.get_contents()[0]).unwrap();
+        assert_eq!(
+            content["parts"][0]["function_response"]["name"],
+            "get_weather"
+        );
+    }
+
+    #[test]
+    fn test_model_version() {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{ "candidates": [], "modelVersion": "gemini-2.5-flash-preview-09-2025" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.get_model_version(),
+            Some("gemini-2.5-flash-preview-09-2025")
+        );
+
+        let response: GenerateContentResponse =
+            serde_json::from_str(r#"{ "candidates": [] }"#).unwrap();
+        assert!(response.get_model_version().is_none());
+    }
+
+    #[test]
+    fn test_response_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer" } }
         });
+
+        let mut settings = Settings::new();
+        settings.set_temperature(0.5);
+        settings.set_response_schema(schema.clone());
+        assert!(settings.validate_response_schema().is_ok());
+
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["generationConfig"]["responseMimeType"],
+            RESPONSE_MIME_TYPE_JSON
+        );
+        assert_eq!(request["generationConfig"]["responseSchema"], schema);
+
+        settings
+            .generation_config
+            .as_mut()
+            .unwrap()
+            .response_mime_type = Some("text/plain".to_string());
+        assert!(matches!(
+            settings.validate_response_schema(),
+            Err(GemError::InvalidConfig(_))
+        ));
+
+        let mut settings = Settings::new();
+        settings.set_advance_settings(
+            None,
+            Some(RESPONSE_MIME_TYPE_ENUM.to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        settings.set_response_schema(serde_json::json!({ "type": "string", "enum": ["a", "b"] }));
+        assert_eq!(
+            settings
+                .generation_config
+                .unwrap()
+                .response_mime_type
+                .as_deref(),
+            Some(RESPONSE_MIME_TYPE_ENUM)
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        #[derive(Deserialize)]
+        struct Person {
+            name: String,
+            age: u32,
+        }
+
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "parts": [{ "text": "{\"name\": \"Ada\", \"age\": 36}" }],
+                    "role": "model"
+                }
+            }]
+        }))
+        .unwrap();
+        let person: Person = response.parse_json().unwrap();
+        assert_eq!(person.name, "Ada");
+        assert_eq!(person.age, 36);
+
+        assert!(matches!(
+            response.parse_json::<Vec<u32>>(),
+            Err(GemError::ParsingError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shared_file_manager() {
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+
+        let mut manager = FileManager::with_api_key("test-key".to_string());
+        manager.base_url = server.url.clone();
+        let manager = Arc::new(manager);
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    manager
+                        .add_file_from_bytes("notes.txt", vec![i; 5], "text/plain")
+                        .await
+                })
+            })
+            .collect();
+        for handle in handles {
+            let file_data = handle.await.unwrap().unwrap();
+            assert_eq!(file_data.mime_type, "text/plain");
+        }
+
+        assert_eq!(manager.files.lock().await.len(), 4);
+        assert!(manager.check_file(&sha256::digest(vec![2u8; 5])).await);
+    }
+
+    #[test]
+    fn test_clear_generation_config_fields() {
+        let mut settings = Settings::new();
+        settings.set_temperature(0.2);
+        settings.set_max_output_tokens(256);
+        settings.set_top_p(0.9);
+        settings.set_top_k(40);
+
+        settings.clear_temperature();
+        settings.clear_max_output_tokens();
+
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        let config = request["generationConfig"].as_object().unwrap();
+        assert!(!config.contains_key("temperature"));
+        assert!(!config.contains_key("maxOutputTokens"));
+        assert_eq!(config["topK"], 40);
+
+        settings.clear_top_p();
+        settings.clear_top_k();
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert!(request["generationConfig"].as_object().unwrap().is_empty());
+
+        // Clearing a field that was never set is a no-op.
+        let mut settings = Settings::new();
+        settings.clear_temperature();
+        assert!(settings.generation_config.is_none());
+    }
+
+    #[test]
+    fn test_thought_parts() {
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "parts": [
+                        { "text": "The user wants a sum. ", "thought": true },
+                        { "text": "2 + 2 is 4.", "thought": true },
+                        { "text": "The answer " },
+                        { "text": "is 4." }
+                    ],
+                    "role": "model"
+                }
+            }]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            response.thoughts_text().as_deref(),
+            Some("The user wants a sum. 2 + 2 is 4.")
+        );
+        assert_eq!(
+            response.final_answer_text().as_deref(),
+            Some("The answer is 4.")
+        );
+
+        let parts = &response.get_candidates()[0].get_content().unwrap().parts;
+        assert!(parts[0].is_thought());
+        assert!(!parts[2].is_thought());
+
+        let part = serde_json::to_value(&parts[2]).unwrap();
+        assert!(part.get("thought").is_none());
+
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "parts": [{ "text": "Hi" }], "role": "model" } }]
+        }))
+        .unwrap();
+        assert!(response.thoughts_text().is_none());
+    }
+
+    #[test]
+    fn test_finish_reason_and_safety_ratings() {
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [
+                {
+                    "content": { "parts": [{ "text": "Once upon a" }], "role": "model" },
+                    "finishReason": "MAX_TOKENS"
+                },
+                {
+                    "finishReason": "SAFETY",
+                    "safetyRatings": [
+                        { "category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE" },
+                        {
+                            "category": "HARM_CATEGORY_DANGEROUS_CONTENT",
+                            "probability": "HIGH",
+                            "blocked": true
+                        }
+                    ]
+                }
+            ]
+        }))
+        .unwrap();
+
+        let candidates = response.get_candidates();
+        assert_eq!(
+            candidates[0].get_finish_reason(),
+            Some(FinishReason::MaxTokens)
+        );
+        assert!(candidates[0].get_safety_ratings().is_none());
+
+        assert_eq!(
+            candidates[1].get_finish_reason(),
+            Some(FinishReason::Safety)
+        );
+        let blocked: Vec<&SafetyRating> = candidates[1]
+            .get_safety_ratings()
+            .unwrap()
+            .iter()
+            .filter(|rating| rating.get_blocked() == Some(true))
+            .collect();
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(
+            blocked[0].get_category(),
+            Some("HARM_CATEGORY_DANGEROUS_CONTENT")
+        );
+        assert_eq!(blocked[0].get_probability(), Some("HIGH"));
+    }
+
+    fn text_response(text: &str) -> GenerateContentResponse {
+        serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" } }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_extract_single_code_block() {
+        let response = text_response(
+            "Here you go:\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n\nEnjoy!",
+        );
+        assert_eq!(
+            response.extract_code_blocks(),
+            vec![(
+                Some("rust".to_string()),
+                "fn main() {\n    println!(\"hi\");\n}".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_extract_multiple_code_blocks() {
+        let response = text_response(
+            "```python title=\"a.py\"\nprint(1)\n```\nand\n~~~js\nconsole.log(2)\n~~~\n",
+        );
+        assert_eq!(
+            response.extract_code_blocks(),
+            vec![
+                (Some("python".to_string()), "print(1)".to_string()),
+                (Some("js".to_string()), "console.log(2)".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_untagged_and_nested_code_blocks() {
+        let response = text_response("```\nplain\n```");
+        assert_eq!(
+            response.extract_code_blocks(),
+            vec![(None, "plain".to_string())]
+        );
+
+        let response = text_response(
+            "````markdown\nUse a fence:\n```sh\nls\n```\n````\nUnclosed:\n```toml\na = 1",
+        );
+        assert_eq!(
+            response.extract_code_blocks(),
+            vec![
+                (
+                    Some("markdown".to_string()),
+                    "Use a fence:\n```sh\nls\n```".to_string()
+                ),
+                (Some("toml".to_string()), "a = 1".to_string()),
+            ]
+        );
+
+        assert!(text_response("No code here, just `inline`.")
+            .extract_code_blocks()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_is_effectively_empty() {
+        let response = |parts: Value| -> GenerateContentResponse {
+            serde_json::from_value(serde_json::json!({
+                "candidates": [{ "content": { "parts": parts, "role": "model" } }]
+            }))
+            .unwrap()
+        };
+
+        assert!(response(serde_json::json!([{ "text": "  \n\t " }])).is_effectively_empty());
+        assert!(
+            response(serde_json::json!([{ "text": "" }, { "text": " " }])).is_effectively_empty()
+        );
+        assert!(!response(serde_json::json!([{ "text": " Hi " }])).is_effectively_empty());
+        assert!(!response(
+            serde_json::json!([{ "functionCall": { "name": "get_time", "args": {} } }])
+        )
+        .is_effectively_empty());
+
+        // A blocked first candidate doesn't hide the text of the next one.
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [
+                { "finishReason": "SAFETY", "index": 0 },
+                { "content": { "parts": [{ "text": "Hi" }], "role": "model" }, "index": 1 }
+            ]
+        }))
+        .unwrap();
+        assert!(!response.is_effectively_empty());
+    }
+
+    #[test]
+    fn test_was_spii_blocked() {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{
+                "candidates": [{
+                    "finishReason": "SPII",
+                    "index": 0,
+                    "safetyRatings": [
+                        {"category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE"}
+                    ]
+                }],
+                "usageMetadata": {"promptTokenCount": 12, "totalTokenCount": 12}
+            }"#,
+        )
+        .unwrap();
+        assert!(response.was_spii_blocked());
+        assert_eq!(
+            response.get_candidates()[0].get_finish_reason(),
+            Some(FinishReason::Spii)
+        );
+
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "parts": [{ "text": "Hi" }] }, "finishReason": "STOP" }]
+        }))
+        .unwrap();
+        assert!(!response.was_spii_blocked());
+    }
+
+    #[test]
+    fn test_penalties() {
+        let mut settings = Settings::new();
+        settings.set_temperature(0.7);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        let config = request["generationConfig"].as_object().unwrap();
+        assert!(!config.contains_key("presencePenalty"));
+        assert!(!config.contains_key("frequencyPenalty"));
+
+        let mut settings = Settings::new();
+        settings.set_presence_penalty(0.5);
+        settings.set_frequency_penalty(-0.25);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(request["generationConfig"]["presencePenalty"], 0.5);
+        assert_eq!(request["generationConfig"]["frequencyPenalty"], -0.25);
+    }
+
+    #[test]
+    fn test_finish_reasons() {
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [
+                { "index": 2, "finishReason": "SAFETY" },
+                {
+                    "index": 0,
+                    "content": { "parts": [{ "text": "Done." }], "role": "model" },
+                    "finishReason": "STOP"
+                },
+                {
+                    "index": 1,
+                    "content": { "parts": [{ "text": "Once upon" }], "role": "model" }
+                }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            response.finish_reasons(),
+            vec![Some(FinishReason::Stop), None, Some(FinishReason::Safety)]
+        );
+    }
+
+    #[test]
+    fn test_logprobs() {
+        let mut settings = Settings::new();
+        settings.set_temperature(0.9);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert!(request["generationConfig"]
+            .get("responseLogprobs")
+            .is_none());
+        assert!(request["generationConfig"].get("logprobs").is_none());
+
+        settings.set_response_logprobs(true);
+        settings.set_logprobs(2);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(request["generationConfig"]["responseLogprobs"], true);
+        assert_eq!(request["generationConfig"]["logprobs"], 2);
+
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{
+                "candidates": [{
+                    "content": {"role": "model", "parts": [{"text": "Yes"}]},
+                    "finishReason": "STOP",
+                    "avgLogprobs": -0.25,
+                    "logprobsResult": {
+                        "topCandidates": [{
+                            "candidates": [
+                                {"token": "Yes", "tokenId": 7, "logProbability": -0.25},
+                                {"token": "No", "tokenId": 8, "logProbability": -1.5}
+                            ]
+                        }],
+                        "chosenCandidates": [
+                            {"token": "Yes", "tokenId": 7, "logProbability": -0.25}
+                        ]
+                    }
+                }]
+            }"#,
+        )
+        .unwrap();
+        let candidate = &response.get_candidates()[0];
+        assert_eq!(candidate.get_avg_logprobs(), Some(-0.25));
+        let logprobs = candidate.get_logprobs().unwrap();
+        let top = logprobs.get_top_candidates()[0].get_candidates();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[1].get_token(), Some("No"));
+        assert_eq!(top[1].get_token_id(), Some(8));
+        assert_eq!(top[1].get_log_probability(), Some(-1.5));
+        assert_eq!(logprobs.get_chosen_candidates()[0].get_token(), Some("Yes"));
+
+        let merged = GenerateContentResponse::merge(vec![response.clone(), response]);
+        let logprobs = merged.get_candidates()[0].get_logprobs().unwrap();
+        assert_eq!(logprobs.get_chosen_candidates().len(), 2);
+    }
+
+    #[test]
+    fn test_best_candidate_by_logprob() {
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [
+                { "content": { "parts": [{ "text": "no data" }] }, "index": 0 },
+                { "content": { "parts": [{ "text": "unsure" }] }, "avgLogprobs": -1.2, "index": 1 },
+                { "content": { "parts": [{ "text": "confident" }] }, "avgLogprobs": -0.1, "index": 2 },
+                { "content": { "parts": [{ "text": "tied" }] }, "avgLogprobs": -0.1, "index": 3 }
+            ]
+        }))
+        .unwrap();
+        let best = response.best_candidate_by_logprob().unwrap();
+        assert_eq!(best.get_index(), Some(2));
+
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [
+                { "content": { "parts": [{ "text": "a" }] }, "index": 0 },
+                { "content": { "parts": [{ "text": "b" }] }, "index": 1 }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            response.best_candidate_by_logprob().unwrap().get_index(),
+            Some(0)
+        );
+
+        let response: GenerateContentResponse =
+            serde_json::from_value(serde_json::json!({ "candidates": [] })).unwrap();
+        assert!(response.best_candidate_by_logprob().is_none());
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut context = Context::new();
+        context.push_message(Role::User, "Tell me a joke".to_string());
+
+        let request_body = || {
+            let mut settings = Settings::new();
+            settings.set_temperature(0.9);
+            settings.set_seed(42);
+            serde_json::to_string(&context.build(&settings)).unwrap()
+        };
+        assert_eq!(request_body(), request_body());
+
+        let request: Value = serde_json::from_str(&request_body()).unwrap();
+        assert_eq!(request["generationConfig"]["seed"], 42);
+
+        let mut settings = Settings::new();
+        settings.set_temperature(0.9);
+        let request = serde_json::to_value(context.build(&settings)).unwrap();
+        assert!(request["generationConfig"].get("seed").is_none());
+    }
+
+    #[test]
+    fn test_request_priority() {
+        assert_eq!(
+            serde_json::to_string(&RequestPriority::Dedicated).unwrap(),
+            r#""dedicated""#
+        );
+        assert_eq!(RequestPriority::Shared.header_value(), "shared");
+        assert!(RequestPriority::is_supported_by(
+            "https://us-central1-aiplatform.googleapis.com/v1/projects/p/locations/us-central1/publishers/google/models/"
+        ));
+        assert!(!RequestPriority::is_supported_by(
+            crate::api::GENERATE_CONTENT
+        ));
+
+        // The priority is sent as a header, never in the request body.
+        let mut settings = Settings::new();
+        settings.set_request_priority(RequestPriority::Dedicated);
+        assert_eq!(
+            settings.get_request_priority(),
+            Some(RequestPriority::Dedicated)
+        );
+        let request = serde_json::to_string(&Context::new().build(&settings)).unwrap();
+        assert!(!request.contains("dedicated"));
+    }
+
+    #[tokio::test]
+    async fn test_file_poll_config() {
+        // Upload start, upload, then one poll while processing and one once active.
+        let responses = |url: &str| {
+            vec![
+                upload_response(url, "PROCESSING"),
+                upload_response(url, "PROCESSING"),
+                upload_response(url, "PROCESSING"),
+                upload_response(url, "ACTIVE"),
+            ]
+        };
+        let interval = std::time::Duration::from_millis(10);
+
+        let server = MockServer::start_with(responses).await;
+        let mut manager =
+            FileManager::with_api_key("test-key".to_string()).with_poll_config(1, interval);
+        manager.base_url = server.url.clone();
+        assert!(manager
+            .add_file_from_bytes("notes.txt", vec![1; 5], "text/plain")
+            .await
+            .is_ok());
+        assert_eq!(server.requests().len(), 4);
+
+        let server = MockServer::start_with(responses).await;
+        let mut manager =
+            FileManager::with_api_key("test-key".to_string()).with_poll_config(0, interval);
+        manager.base_url = server.url.clone();
+        match manager
+            .add_file_from_bytes("notes.txt", vec![1; 5], "text/plain")
+            .await
+        {
+            Err(GemError::FileError(message)) => assert_eq!(message, "File processing timeout"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_merge_chunks() {
+        let chunks: Vec<GenerateContentResponse> = vec![
+            serde_json::json!({
+                "candidates": [{
+                    "index": 0,
+                    "content": { "parts": [{ "text": "The quick " }], "role": "model" },
+                    "safetyRatings": [
+                        { "category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE" }
+                    ]
+                }],
+                "modelVersion": "gemini-2.0-flash-001"
+            }),
+            serde_json::json!({
+                "candidates": [
+                    { "index": 1, "content": { "parts": [{ "text": "A second" }], "role": "model" } },
+                    {
+                        "index": 0,
+                        "content": { "parts": [{ "text": "brown fox" }], "role": "model" },
+                        "safetyRatings": [
+                            { "category": "HARM_CATEGORY_HARASSMENT", "probability": "LOW" },
+                            { "category": "HARM_CATEGORY_HATE_SPEECH", "probability": "NEGLIGIBLE" }
+                        ]
+                    }
+                ],
+                "usageMetadata": { "promptTokenCount": 4, "totalTokenCount": 8 }
+            }),
+            serde_json::json!({ "candidates": [] }),
+            serde_json::json!({
+                "candidates": [
+                    {
+                        "index": 0,
+                        "content": { "parts": [{ "text": " jumps." }], "role": "model" },
+                        "finishReason": "STOP"
+                    },
+                    { "index": 1, "finishReason": "MAX_TOKENS" }
+                ],
+                "usageMetadata": { "promptTokenCount": 4, "totalTokenCount": 12 }
+            }),
+        ]
+        .into_iter()
+        .map(|chunk| serde_json::from_value(chunk).unwrap())
+        .collect();
+
+        let response = GenerateContentResponse::merge(chunks);
+        assert_eq!(
+            response.get_results(),
+            vec![
+                "The quick brown fox jumps.".to_string(),
+                "A second".to_string()
+            ]
+        );
+        assert_eq!(
+            response.finish_reasons(),
+            vec![Some(FinishReason::Stop), Some(FinishReason::MaxTokens)]
+        );
+        assert_eq!(
+            response.get_candidates()[0]
+                .get_content()
+                .unwrap()
+                .parts
+                .len(),
+            1
+        );
+        assert_eq!(
+            response
+                .get_usage_metadata()
+                .unwrap()
+                .get_total_token_count(),
+            Some(12)
+        );
+        assert_eq!(response.get_model_version(), Some("gemini-2.0-flash-001"));
+
+        let ratings = response.get_candidates()[0].get_safety_ratings().unwrap();
+        assert_eq!(ratings.len(), 2);
+        assert_eq!(ratings[0].get_probability(), Some("LOW"));
+
+        assert!(GenerateContentResponse::merge(Vec::new())
+            .get_candidates()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_headers() {
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+        let mut manager = FileManager::with_api_key("test-key".to_string());
+        manager.base_url = server.url.clone();
+        manager
+            .add_file_from_bytes("notes.txt", b"hello".to_vec(), "text/plain")
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        let start = &requests[0];
+        assert!(start.path.starts_with("/upload/v1beta/files"));
+        assert_eq!(start.header("X-Goog-Upload-Protocol"), Some("resumable"));
+        assert_eq!(start.header("X-Goog-Upload-Command"), Some("start"));
+        assert_eq!(
+            start.header("X-Goog-Upload-Header-Content-Length"),
+            Some("5")
+        );
+        assert_eq!(
+            start.header("X-Goog-Upload-Header-Content-Type"),
+            Some("text/plain")
+        );
+
+        let upload = &requests[1];
+        assert_eq!(upload.method, "PUT");
+        assert_eq!(upload.header("X-Goog-Upload-Offset"), Some("0"));
+        assert_eq!(
+            upload.header("X-Goog-Upload-Command"),
+            Some("upload, finalize")
+        );
+        assert_eq!(upload.body, "hello");
+    }
+
+    #[test]
+    fn test_harm_category_from_name() {
+        assert_eq!(
+            HarmCategory::from_name("HARM_CATEGORY_DANGEROUS_CONTENT"),
+            Some(HarmCategory::HarmCategoryDangerousContent)
+        );
+        assert_eq!(
+            HarmCategory::from_name("hate_speech"),
+            Some(HarmCategory::HarmCategoryHateSpeech)
+        );
+        assert_eq!(
+            HarmCategory::from_name("HARM_CATEGORY_CIVIC_INTEGRITY"),
+            Some(HarmCategory::HarmCategoryCivicIntegrity)
+        );
+        assert_eq!(HarmCategory::from_name("HARM_CATEGORY_MEDICAL"), None);
+        assert_eq!(HarmCategory::from_name(""), None);
+
+        let rating: SafetyRating = serde_json::from_value(serde_json::json!({
+            "category": "HARM_CATEGORY_HARASSMENT",
+            "probability": "LOW"
+        }))
+        .unwrap();
+        assert_eq!(
+            rating.harm_category(),
+            Some(HarmCategory::HarmCategoryHarassment)
+        );
+
+        let rating: SafetyRating =
+            serde_json::from_value(serde_json::json!({ "probability": "LOW" })).unwrap();
+        assert!(rating.harm_category().is_none());
+    }
+
+    #[test]
+    fn test_push_gcs_file() {
+        let mut context = Context::new();
+        context
+            .push_gcs_file(Role::User, "gs://media-bucket/videos/talk.mp4", "video/mp4")
+            .unwrap();
+
+        match &context.get_contents()[0].parts[0].data {
+            PartData::FileData { file_data } => {
+                assert_eq!(file_data.file_uri, "gs://media-bucket/videos/talk.mp4");
+                assert_eq!(file_data.mime_type, "video/mp4");
+            }
+            other => panic!("unexpected part: {:?}", other),
+        }
+
+        for uri in [
+            "https://storage.googleapis.com/media-bucket/talk.mp4",
+            "gs://media-bucket",
+            "gs:///talk.mp4",
+        ] {
+            assert!(matches!(
+                context.push_gcs_file(Role::User, uri, "video/mp4"),
+                Err(GemError::FileError(_))
+            ));
+        }
+        assert_eq!(context.len(), 1);
     }
 
-    pub fn push_contents(&mut self, contents: Vec<Content>) {
-        self.contents.extend(contents);
+    #[test]
+    fn test_google_search_grounding() {
+        let mut settings = Settings::new();
+        settings.enable_google_search();
+        settings.enable_google_search();
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["tools"],
+            serde_json::json!([{ "googleSearch": {} }])
+        );
+
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "parts": [{ "text": "Spain won Euro 2024." }],
+                    "role": "model"
+                },
+                "groundingMetadata": {
+                    "webSearchQueries": ["euro 2024 winner"],
+                    "groundingChunks": [
+                        { "web": { "uri": "https://example.com/a", "title": "uefa.com" } },
+                        { "web": { "uri": "https://example.com/b", "title": "bbc.com" } }
+                    ],
+                    "groundingSupports": [{
+                        "segment": { "endIndex": 20, "text": "Spain won Euro 2024." },
+                        "groundingChunkIndices": [1, 0],
+                        "confidenceScores": [0.9, 0.8]
+                    }]
+                }
+            }]
+        }))
+        .unwrap();
+
+        let metadata = response.get_candidates()[0]
+            .get_grounding_metadata()
+            .unwrap();
+        assert_eq!(metadata.get_web_search_queries(), ["euro 2024 winner"]);
+        assert_eq!(metadata.get_grounding_chunks().len(), 2);
+
+        let support = &metadata.get_grounding_supports()[0];
+        assert_eq!(support.get_segment().unwrap().get_end_index(), 20);
+        let sources = metadata.get_sources(support);
+        assert_eq!(sources[0].get_uri(), Some("https://example.com/b"));
+        assert_eq!(sources[1].get_title(), Some("uefa.com"));
     }
 
-    pub fn push_file(&mut self, role: Role, file_data: FileData) {
-        self.contents.push(Content {
-            role: Some(role),
-            parts: vec![Part {
-                data: PartData::FileData { file_data },
+    #[test]
+    fn test_debug_summary() {
+        let long_text = "a".repeat(500);
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "parts": [
+                        { "text": long_text },
+                        { "inlineData": { "mimeType": "image/png", "data": "A".repeat(4000) } }
+                    ],
+                    "role": "model"
+                },
+                "finishReason": "MAX_TOKENS",
+                "index": 0
             }],
-        });
-    }
+            "usageMetadata": {
+                "promptTokenCount": 12,
+                "candidatesTokenCount": 500,
+                "totalTokenCount": 512
+            },
+            "modelVersion": "gemini-2.0-flash-001"
+        }))
+        .unwrap();
 
-    pub fn push_blob(&mut self, role: Role, blob: Blob) {
-        self.contents.push(Content {
-            role: Some(role),
-            parts: vec![Part {
-                data: PartData::InlineData { inline_data: blob },
-            }],
-        });
+        let summary = response.debug_summary();
+        assert!(summary.contains("candidates: 1"));
+        assert!(summary.contains("model: gemini-2.0-flash-001"));
+        assert!(summary.contains("usage: prompt 12, candidates 500, total 512"));
+        assert!(summary.contains("finish: MaxTokens"));
+        assert!(summary.contains("(420 more chars)"));
+        assert!(summary.contains("inline data: image/png (4000 bytes base64)"));
+        assert!(!summary.contains(&"a".repeat(81)));
+        assert!(!summary.contains("AAAA"));
     }
 
-    pub fn push_message_with_file(&mut self, role: Role, content: &str, file_data: FileData) {
-        self.contents.push(Content {
-            role: Some(role),
-            parts: vec![
-                Part {
-                    data: PartData::Text {
-                        text: content.to_string(),
-                    },
-                },
-                Part {
-                    data: PartData::FileData { file_data },
-                },
+    #[test]
+    fn test_thinking_budget_and_thought_parts() {
+        let mut settings = Settings::new();
+        settings.set_thinking_budget(1024);
+        settings.set_include_thoughts(true);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["generationConfig"]["thinkingConfig"],
+            serde_json::json!({ "thinkingBudget": 1024, "includeThoughts": true })
+        );
+
+        let content: Content = serde_json::from_value(serde_json::json!({
+            "parts": [
+                { "text": "Let me think. ", "thought": true },
+                { "text": "It is 4." }
             ],
-        });
+            "role": "model"
+        }))
+        .unwrap();
+        assert_eq!(content.thought_parts().count(), 1);
+        assert_eq!(content.answer_parts().count(), 1);
+        assert_eq!(
+            content.get_thoughts_text().as_deref(),
+            Some("Let me think. ")
+        );
+        assert_eq!(content.get_answer_text().as_deref(), Some("It is 4."));
     }
 
-    pub fn push_message_with_blob(&mut self, role: Role, content: &str, blob: Blob) {
-        self.contents.push(Content {
-            role: Some(role),
-            parts: vec![
-                Part {
-                    data: PartData::Text {
-                        text: content.to_string(),
-                    },
-                },
-                Part {
-                    data: PartData::InlineData { inline_data: blob },
-                },
+    #[test]
+    fn test_system_instruction_parts() {
+        let mut settings = Settings::new();
+        settings.set_system_instruction("Be brief");
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["systemInstruction"],
+            serde_json::json!({ "parts": [{ "text": "Be brief" }] })
+        );
+
+        settings.set_system_instruction_parts(vec![
+            Part::text("Answer using this style guide:"),
+            Part::file_data(FileData {
+                mime_type: "application/pdf".to_string(),
+                file_uri: "https://example.com/files/guide".to_string(),
+            }),
+            Part::blob(Blob::new("text/plain", b"Use British spelling.")),
+        ]);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        let parts = request["systemInstruction"]["parts"].as_array().unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0]["text"], "Answer using this style guide:");
+        assert_eq!(
+            parts[1]["file_data"]["file_uri"],
+            "https://example.com/files/guide"
+        );
+        assert_eq!(parts[2]["inline_data"]["mime_type"], "text/plain");
+    }
+
+    #[test]
+    fn test_push_parts() {
+        let file = FileData {
+            mime_type: "application/pdf".to_string(),
+            file_uri: "https://example.com/files/a".to_string(),
+        };
+        let mut context = Context::new();
+        context.push_parts(
+            Some(Role::User),
+            vec![
+                Part::file_data(file.clone()),
+                Part::file_data(file),
+                Part::text("Compare these"),
             ],
+        );
+        context.push_parts(None, vec![Part::text("No role")]);
+        context.push_content(Content {
+            role: Some(Role::System),
+            parts: vec![Part::text("Be brief")],
         });
+
+        let request = serde_json::to_value(context.build(&Settings::new())).unwrap();
+        assert_eq!(request["contents"].as_array().unwrap().len(), 2);
+        assert_eq!(request["contents"][0]["role"], "user");
+        assert_eq!(request["contents"][0]["parts"].as_array().unwrap().len(), 3);
+        assert!(request["contents"][1]["role"].is_null());
+        assert_eq!(request["systemInstruction"]["parts"][0]["text"], "Be brief");
     }
 
-    pub fn build(&self, settings: &Settings) -> GenerateContentRequest {
-        GenerateContentRequest::new(
-            self,
-            settings.generation_config.clone(),
-            settings.safety_settings.clone(),
-            match &settings.system_instruction {
-                Some(instruction) => Some(NoRoleContent {
-                    parts: vec![Part {
-                        data: PartData::Text {
-                            text: instruction.clone(),
-                        },
-                    }],
-                }),
-                None => None,
-            },
-        )
+    #[test]
+    fn test_system_role_routed_to_system_instruction() {
+        let mut settings = Settings::new();
+        settings.set_system_instruction("Be brief");
+
+        let mut context = Context::new();
+        context.push_message(Role::System, "Answer in French".to_string());
+        context.push_message(Role::User, "Hello".to_string());
+        context.push_contents(vec![Content {
+            role: Some(Role::System),
+            parts: vec![Part::text("Never use emoji")],
+        }]);
+        assert_eq!(context.len(), 1);
+        assert_eq!(context.get_system_parts().len(), 2);
+
+        let request = serde_json::to_value(context.build(&settings)).unwrap();
+        assert_eq!(request["contents"].as_array().unwrap().len(), 1);
+        assert_eq!(request["contents"][0]["role"], "user");
+        assert_eq!(
+            request["systemInstruction"],
+            serde_json::json!({ "parts": [
+                { "text": "Be brief" },
+                { "text": "Answer in French" },
+                { "text": "Never use emoji" }
+            ] })
+        );
+
+        let restored: Context =
+            serde_json::from_str(&serde_json::to_string(&context).unwrap()).unwrap();
+        assert_eq!(restored.get_system_parts().len(), 2);
+        let legacy: Context = serde_json::from_str(r#"{"contents": []}"#).unwrap();
+        assert!(legacy.get_system_parts().is_empty());
     }
 
-    pub fn clear(&mut self) {
-        self.contents.clear();
+    #[test]
+    fn test_merge_orders_candidates_by_index() {
+        let chunk = |index: i32, text: &str| -> GenerateContentResponse {
+            serde_json::from_value(serde_json::json!({
+                "candidates": [{
+                    "index": index,
+                    "content": { "parts": [{ "text": text }], "role": "model" }
+                }]
+            }))
+            .unwrap()
+        };
+
+        let merged = GenerateContentResponse::merge(vec![
+            chunk(3, "three"),
+            chunk(0, "zero"),
+            chunk(3, "!"),
+            chunk(2, "two"),
+        ]);
+        let candidates = merged.get_candidates();
+        assert_eq!(candidates.len(), 4);
+        for (position, candidate) in candidates.iter().enumerate() {
+            assert_eq!(candidate.get_index(), Some(position as i32));
+        }
+        assert_eq!(merged.get_results(), vec!["zero", "two", "three!"]);
+        assert!(candidates[1].get_content().is_none());
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.contents.is_empty()
+    #[test]
+    fn test_rate_limit_info_from_headers() {
+        let mut headers = header::HeaderMap::new();
+        assert!(RateLimitInfo::from_headers(&headers).is_none());
+
+        headers.insert("x-ratelimit-limit", "60".parse().unwrap());
+        headers.insert("x-ratelimit-remaining", "12".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "1m30s".parse().unwrap());
+        let info = RateLimitInfo::from_headers(&headers).unwrap();
+        assert_eq!(info.get_limit(), Some(60));
+        assert_eq!(info.get_remaining(), Some(12));
+        assert_eq!(info.get_reset(), Some(std::time::Duration::from_secs(90)));
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert("x-ratelimit-remaining-requests", "3".parse().unwrap());
+        headers.insert("x-ratelimit-reset-requests", "2.5".parse().unwrap());
+        let info = RateLimitInfo::from_headers(&headers).unwrap();
+        assert_eq!(info.get_limit(), None);
+        assert_eq!(info.get_remaining(), Some(3));
+        assert_eq!(
+            info.get_reset(),
+            Some(std::time::Duration::from_millis(2500))
+        );
     }
 
-    pub fn len(&self) -> usize {
-        self.contents.len()
+    #[test]
+    fn test_get_all_text() {
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "parts": [
+                        { "text": "Let me compute it. " },
+                        { "functionCall": { "name": "sum", "args": { "a": 1, "b": 2 } } },
+                        { "text": "The sum is 3." }
+                    ],
+                    "role": "model"
+                }
+            }]
+        }))
+        .unwrap();
+
+        let content = response.get_candidates()[0].get_content().unwrap();
+        assert_eq!(content.get_text().as_deref(), Some("Let me compute it. "));
+        assert_eq!(content.get_all_text(), "Let me compute it. The sum is 3.");
+        assert_eq!(response.get_results(), vec!["Let me compute it. "]);
+        assert_eq!(
+            response.get_all_results(),
+            vec!["Let me compute it. The sum is 3."]
+        );
     }
 
-    pub fn get_contents(&self) -> &Vec<Content> {
-        &self.contents
+    #[test]
+    fn test_inline_image_output() {
+        let png = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "parts": [
+                        { "text": "Here is your cat:" },
+                        { "inlineData": { "mimeType": "image/png", "data": general_purpose::STANDARD.encode(png) } },
+                        { "inlineData": { "mimeType": "audio/wav", "data": "AAAA" } }
+                    ],
+                    "role": "model"
+                }
+            }]
+        }))
+        .unwrap();
+
+        let content = response.get_candidates()[0].get_content().unwrap();
+        let blobs = content.get_inline_data();
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs[0].get_mime_type(), "image/png");
+        assert_eq!(blobs[0].decode().unwrap(), png);
+        assert!(matches!(
+            Blob {
+                mime_type: "image/png".to_string(),
+                data: "not base64!".to_string(),
+            }
+            .decode(),
+            Err(GemError::FileError(_))
+        ));
+
+        let dir = std::env::temp_dir().join(format!("gem-rs-images-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = response.save_images(&dir).unwrap();
+        assert_eq!(paths, vec![dir.join("image_0.png")]);
+        assert_eq!(std::fs::read(&paths[0]).unwrap(), png);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    pub fn get_contents_mut(&mut self) -> &mut Vec<Content> {
-        &mut self.contents
+    #[test]
+    fn test_settings_builder() {
+        let settings = Settings::builder()
+            .temperature(0.7)
+            .max_output_tokens(1024)
+            .seed(7)
+            .system_instruction("Be brief")
+            .google_search()
+            .build();
+
+        let mut expected = Settings::new();
+        expected.set_temperature(0.7);
+        expected.set_max_output_tokens(1024);
+        expected.set_seed(7);
+        expected.set_system_instruction("Be brief");
+        expected.enable_google_search();
+
+        assert_eq!(
+            serde_json::to_value(Context::new().build(&settings)).unwrap(),
+            serde_json::to_value(Context::new().build(&expected)).unwrap()
+        );
+        let config = settings.generation_config.unwrap();
+        assert_eq!(config.temperature, Some(0.7));
+        assert_eq!(config.max_output_tokens, Some(1024));
     }
-}
 
-mod tests {
+    #[test]
+    fn test_content_breakdown() {
+        let mut context = Context::new();
+        context.push_message(Role::System, "Be brief".to_string());
+        context.push_message_with_file(
+            Role::User,
+            "Summarize this",
+            FileData {
+                mime_type: "application/pdf".to_string(),
+                file_uri: "https://example.com/files/report".to_string(),
+            },
+        );
+        context.push_message_with_blob(Role::User, "And this", Blob::new("image/png", b"abc"));
+        context.push_function_response("lookup", serde_json::json!({ "ok": true }));
 
-    use super::*;
+        assert_eq!(
+            context.content_breakdown(),
+            ContentBreakdown {
+                turns: 3,
+                text_parts: 3,
+                file_parts: 1,
+                blob_parts: 1,
+                function_call_parts: 0,
+                function_response_parts: 1,
+                text_length: "Be brief".len() + "Summarize this".len() + "And this".len(),
+                blob_size: 4,
+            }
+        );
+    }
 
     #[test]
-    fn test_deserialize_generate_content_response() {
-        let json_data = r#"
-        {
-            "candidates": [
-                {
-                    "content": {
-                        "parts": [
-                            {
-                                "text": "Sample text"
+    fn test_parse_json_array_root() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Item {
+            name: String,
+        }
+
+        let mut settings = Settings::new();
+        settings.set_response_schema(serde_json::json!({
+            "type": "array",
+            "items": { "type": "object", "properties": { "name": { "type": "string" } } }
+        }));
+        assert!(settings.validate_response_schema().is_ok());
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(
+            request["generationConfig"]["responseSchema"]["type"],
+            "array"
+        );
+
+        let response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "parts": [
+                        { "text": "The user wants a list.", "thought": true },
+                        { "text": "[{\"name\": \"apple\"}, " },
+                        { "text": "{\"name\": \"pear\"}]" }
+                    ],
+                    "role": "model"
+                }
+            }]
+        }))
+        .unwrap();
+        let items: Vec<Item> = response.parse_json().unwrap();
+        assert_eq!(
+            items,
+            vec![
+                Item {
+                    name: "apple".to_string()
+                },
+                Item {
+                    name: "pear".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_manager_api_key() {
+        let manager = FileManager::with_api_key("secret-key".to_string());
+        assert_eq!(manager.api_key, "secret-key");
+
+        let manager =
+            FileManager::from_env(|name| (name == "GEMINI_API_KEY").then(|| "env-key".to_string()))
+                .unwrap();
+        assert_eq!(manager.api_key, "env-key");
+
+        assert!(matches!(
+            FileManager::from_env(|_| None),
+            Err(GemError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_push_user_and_model_messages() {
+        let mut context = Context::new();
+        context.push_user_message("What is 2 + 2?");
+        context.push_model_message("4");
+
+        let contents = context.get_contents();
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[0].role, Some(Role::User));
+        assert_eq!(contents[1].role, Some(Role::Model));
+        assert_eq!(contents[1].get_text().as_deref(), Some("4"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_file_uses_name() {
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+
+        let mut manager = FileManager::with_api_key("test-key".to_string());
+        manager.base_url = server.url.clone();
+        manager
+            .add_file_from_bytes("notes.txt", b"hello".to_vec(), "text/plain")
+            .await
+            .unwrap();
+
+        // The uri of a cached file can go stale; only the name identifies it.
+        let hash = sha256::digest(b"hello".to_vec());
+        manager.files.lock().await.get_mut(&hash).unwrap().uri =
+            "http://127.0.0.1:1/stale".to_string();
+
+        manager.delete_file(&hash).await.unwrap();
+        assert!(!manager.check_file(&hash).await);
+
+        let requests = server.requests();
+        let delete = requests.last().unwrap();
+        assert_eq!(delete.method, "DELETE");
+        assert!(delete.path.starts_with("/v1beta/files/abc123?key=test-key"));
+    }
+
+    #[test]
+    fn test_function_declaration_schema_validation() {
+        let nested = FunctionDeclaration::new(
+            "create_order",
+            "Creates an order",
+            Some(serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "customer": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "address": {
+                                "type": "object",
+                                "properties": { "city": { "type": "string" } }
                             }
-                        ],
-                        "role": "model"
+                        },
+                        "required": ["name"]
                     },
-                    "finishReason": "STOP",
-                    "safetyRatings": [
-                        {
-                            "category": "violence",
-                            "probability": "low",
-                            "blocked": false
+                    "items": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "sku": { "type": "string" },
+                                "quantity": { "type": "integer", "minimum": 1 }
+                            }
                         }
-                    ],
-                    "tokenCount": 10,
-                    "index": 0
-                }
-            ],
-            "promptFeedback": {
-                "blockReason": "SAFETY",
-                "safetyRatings": [
-                    {
-                        "category": "violence",
-                        "probability": "low",
-                        "blocked": false
                     }
-                ]
-            },
-            "usageMetadata": {
-                "promptTokenCount": 5,
-                "cachedContentTokenCount": 3,
-                "candidatesTokenCount": 10,
-                "totalTokenCount": 18
+                }
+            })),
+        );
+        assert!(nested.validate().is_ok());
+
+        let mut settings = Settings::new();
+        settings.set_tools(vec![Tool::functions(vec![nested])]);
+        assert!(settings.validate_tools().is_ok());
+
+        let invalid = FunctionDeclaration::new(
+            "create_order",
+            "Creates an order",
+            Some(serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "items": {
+                        "type": "array",
+                        "items": { "oneOf": [{ "type": "string" }, { "type": "integer" }] }
+                    },
+                    "customer": { "$ref": "#/$defs/customer" }
+                }
+            })),
+        );
+        settings.set_tools(vec![Tool::functions(vec![invalid])]);
+        match settings.validate_for_model(&Models::Gemini2Flash) {
+            Err(GemError::InvalidConfig(message)) => {
+                assert!(message.contains("create_order"));
+                assert!(message.contains("`$ref` at parameters.properties.customer"));
             }
+            other => panic!("unexpected result: {:?}", other),
         }
-        "#;
+    }
 
-        let response: GenerateContentResponse = serde_json::from_str(json_data).unwrap();
+    #[test]
+    fn test_context_text_only() {
+        let mut context = Context::new();
+        context.push_message_with_blob(Role::User, "What is this?", Blob::new("image/png", b"png"));
+        context.push_blob(Role::User, Blob::new("image/png", b"more"));
+        context.push_model_message("A cat.");
+        context.push_function_response("lookup", serde_json::json!({ "ok": true }));
+        context.push_message(Role::System, "Be brief.".to_string());
+        context.push_blob(Role::System, Blob::new("image/png", b"logo"));
 
-        assert_eq!(response.candidates.len(), 1);
-        let candidate = &response.candidates[0];
-        assert_eq!(candidate.content.as_ref().unwrap().parts.len(), 1);
+        let text = context.text_only();
+        let breakdown = text.content_breakdown();
+        assert_eq!(breakdown.turns, 2);
+        assert_eq!(breakdown.text_parts, 3);
+        assert_eq!(breakdown.blob_parts, 0);
+        assert_eq!(breakdown.function_response_parts, 0);
+        assert_eq!(text.get_contents()[0].get_all_text(), "What is this?");
+        assert_eq!(text.get_contents()[1].get_all_text(), "A cat.");
+        assert_eq!(text.get_system_parts().len(), 1);
+
+        // The original context is left untouched.
+        assert_eq!(context.content_breakdown().blob_parts, 3);
+        assert_eq!(context.len(), 4);
+    }
+
+    #[test]
+    fn test_context_history_editing() {
+        let mut context = Context::new();
+        context.push_message(Role::System, "Be brief.".to_string());
+        for i in 0..5 {
+            context.push_user_message(&format!("question {}", i));
+            context.push_model_message(&format!("answer {}", i));
+        }
+        assert_eq!(context.len(), 10);
+
+        context.truncate_to_last(4);
+        assert_eq!(context.len(), 4);
+        assert_eq!(context.get_contents()[0].get_all_text(), "question 3");
+        assert_eq!(context.get_system_parts().len(), 1);
+
+        // Truncating to more turns than there are is a no-op.
+        context.truncate_to_last(10);
+        assert_eq!(context.len(), 4);
+
+        assert_eq!(context.pop().unwrap().get_all_text(), "answer 4");
+        assert_eq!(context.remove(0).unwrap().get_all_text(), "question 3");
+        assert!(context.remove(5).is_none());
+        assert_eq!(context.len(), 2);
+
+        // "Be brief." + "answer 3" + "question 4"
+        assert_eq!(context.total_text_len(), 9 + 8 + 10);
+
+        context.pop();
+        context.pop();
+        assert!(context.pop().is_none());
+    }
+
+    #[test]
+    fn test_google_search_dynamic_retrieval() {
+        let mut settings = Settings::new();
+        settings.enable_google_search_dynamic(0.25);
+        settings.enable_google_search_dynamic(0.5);
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
         assert_eq!(
-            candidate.content.as_ref().unwrap().role.as_ref().unwrap(),
-            &Role::Model
+            request["tools"],
+            serde_json::json!([{
+                "googleSearchRetrieval": {
+                    "dynamicRetrievalConfig": {
+                        "mode": "MODE_DYNAMIC",
+                        "dynamicThreshold": 0.5
+                    }
+                }
+            }])
+        );
+        assert!(settings.validate_tools().is_ok());
+
+        let settings = Settings::builder().google_search_dynamic(1.5).build();
+        assert!(matches!(
+            settings.validate_tools(),
+            Err(GemError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_generation_config() {
+        let model = Models::Gemini2Flash;
+        let invalid = |settings: Settings| {
+            matches!(
+                settings.validate_for_model(&model),
+                Err(GemError::InvalidConfig(_))
+            )
+        };
+
+        let valid = Settings::builder()
+            .temperature(2.0)
+            .top_p(0.0)
+            .presence_penalty(-2.0)
+            .frequency_penalty(1.5)
+            .max_output_tokens(8192)
+            .logprobs(20)
+            .build();
+        assert!(valid.validate_for_model(&model).is_ok());
+
+        assert!(invalid(Settings::builder().temperature(3.0).build()));
+        assert!(invalid(Settings::builder().temperature(f32::NAN).build()));
+        assert!(invalid(Settings::builder().top_p(1.5).build()));
+        assert!(invalid(Settings::builder().presence_penalty(-2.5).build()));
+        assert!(invalid(Settings::builder().frequency_penalty(2.5).build()));
+        assert!(invalid(Settings::builder().logprobs(21).build()));
+        assert!(invalid(Settings::builder().max_output_tokens(0).build()));
+        assert!(invalid(
+            Settings::builder().max_output_tokens(100_000).build()
+        ));
+
+        let mut settings = Settings::new();
+        settings.set_advance_settings(
+            Some((0..6).map(|i| i.to_string()).collect()),
+            None,
+            None,
+            None,
+            None,
+            None,
         );
+        assert!(invalid(settings));
+
+        // The output limit is only checked for models with known limits.
+        let settings = Settings::builder().max_output_tokens(100_000).build();
+        assert!(settings
+            .validate_for_model(&Models::Custom("gemini-next".to_string()))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_add_file_mime_type() {
+        let dir = std::env::temp_dir().join(format!("gem-rs-mime-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sniffed = dir.join("photo.bin");
+        std::fs::write(&sniffed, b"\x89PNG\r\n\x1a\nrest").unwrap();
+        let unknown = dir.join("data.bin");
+        std::fs::write(&unknown, [0u8, 159, 146, 150]).unwrap();
+
+        let upload_type = |server: &MockServer| {
+            server.requests()[0]
+                .header("X-Goog-Upload-Header-Content-Type")
+                .map(str::to_string)
+        };
+
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+        let mut manager = FileManager::with_api_key("test-key".to_string());
+        manager.base_url = server.url.clone();
+        manager.add_file(&sniffed).await.unwrap();
+        assert_eq!(upload_type(&server).as_deref(), Some("image/png"));
+
+        assert!(matches!(
+            manager.add_file(&unknown).await,
+            Err(GemError::FileError(_))
+        ));
+
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+        manager.base_url = server.url.clone();
+        manager
+            .add_file_with_mime_type(&unknown, Some("application/octet-stream"))
+            .await
+            .unwrap();
         assert_eq!(
-            candidate.finish_reason.as_ref().unwrap(),
-            &FinishReason::Stop
+            upload_type(&server).as_deref(),
+            Some("application/octet-stream")
         );
-        assert_eq!(candidate.safety_ratings.as_ref().unwrap().len(), 1);
-        assert_eq!(candidate.token_count.unwrap(), 10);
-        assert_eq!(candidate.index.unwrap(), 0);
 
-        let prompt_feedback = response.prompt_feedback.as_ref().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_identical_uploads() {
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+        let mut manager = FileManager::with_api_key("test-key".to_string());
+        manager.base_url = server.url.clone();
+        manager
+            .add_file_from_bytes("notes.txt", vec![7; 5], "text/plain")
+            .await
+            .unwrap();
+        let requests_per_upload = server.requests().len();
+
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+        let mut manager = FileManager::with_api_key("test-key".to_string());
+        manager.base_url = server.url.clone();
+        let manager = Arc::new(manager);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    manager
+                        .add_file_from_bytes("notes.txt", vec![7; 5], "text/plain")
+                        .await
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.await.unwrap().is_ok());
+        }
+
+        assert_eq!(server.requests().len(), requests_per_upload);
+        assert_eq!(manager.files.lock().await.len(), 1);
+        assert!(manager.uploads.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_upload_releases_lock() {
+        // The upload never completes, so the caller gives up on it.
+        let server = MockServer::start_with(|url| {
+            vec![MockResponse::new(200, "")
+                .header("X-Goog-Upload-URL", &format!("{}upload", url))
+                .hold_open()]
+        })
+        .await;
+        let mut manager = FileManager::with_api_key("test-key".to_string());
+        manager.base_url = server.url.clone();
+
+        let upload = manager.add_file_from_bytes("notes.txt", vec![7; 5], "text/plain");
+        let timed_out = tokio::time::timeout(std::time::Duration::from_millis(100), upload).await;
+        assert!(timed_out.is_err());
+        assert!(manager.uploads.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_progress() {
+        const KIB_256: usize = 256 * 1024;
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+        // Rounded up to 256 KiB, the smallest size the upload endpoint accepts.
+        let mut manager =
+            FileManager::with_api_key("test-key".to_string()).with_upload_chunk_size(1000);
+        manager.base_url = server.url.clone();
+
+        let bytes: Vec<u8> = (0..2 * KIB_256 + 10)
+            .map(|i| b'a' + (i / KIB_256) as u8)
+            .collect();
+        let mut reported = Vec::new();
+        manager
+            .add_file_from_bytes_with_progress(
+                "notes.txt",
+                bytes,
+                "text/plain",
+                |uploaded, total| reported.push((uploaded, total)),
+            )
+            .await
+            .unwrap();
+        let total = 2 * KIB_256 + 10;
         assert_eq!(
-            prompt_feedback.block_reason.as_ref().unwrap(),
-            &BlockReason::Safety
+            reported,
+            vec![(KIB_256, total), (2 * KIB_256, total), (total, total)]
         );
-        assert_eq!(prompt_feedback.safety_ratings.len(), 1);
 
-        let usage_metadata = response.usage_metadata.as_ref().unwrap();
-        assert_eq!(usage_metadata.prompt_token_count.unwrap(), 5);
-        assert_eq!(usage_metadata.cached_content_token_count.unwrap(), 3);
-        assert_eq!(usage_metadata.candidates_token_count.unwrap(), 10);
-        assert_eq!(usage_metadata.total_token_count.unwrap(), 18);
+        let puts: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .collect();
+        let chunks: Vec<_> = puts
+            .iter()
+            .map(|request| {
+                (
+                    request.header("X-Goog-Upload-Offset").unwrap().to_string(),
+                    request.header("X-Goog-Upload-Command").unwrap().to_string(),
+                    request.body.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                ("0".to_string(), "upload".to_string(), "a".repeat(KIB_256)),
+                (
+                    KIB_256.to_string(),
+                    "upload".to_string(),
+                    "b".repeat(KIB_256)
+                ),
+                (
+                    (2 * KIB_256).to_string(),
+                    "upload, finalize".to_string(),
+                    "c".repeat(10)
+                ),
+            ]
+        );
+
+        // Files that fit in one chunk are sent in a single request.
+        let server = MockServer::start_with(|url| vec![upload_response(url, "ACTIVE")]).await;
+        let mut manager =
+            FileManager::with_api_key("test-key".to_string()).with_upload_chunk_size(KIB_256);
+        manager.base_url = server.url.clone();
+        let mut reported = Vec::new();
+        manager
+            .add_file_from_bytes_with_progress(
+                "notes.txt",
+                vec![1; 5],
+                "text/plain",
+                |uploaded, total| reported.push((uploaded, total)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reported, vec![(5, 5)]);
+        let puts = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT");
+        assert_eq!(puts.count(), 1);
     }
 }
@@ -9,7 +9,7 @@ use std::path::Path;
 ///
 /// This function takes a file path and attempts to determine its MIME type
 /// by examining the file extension. It supports various file formats including
-/// documents, images, audio, and video files.
+/// documents, images, audio, video, and text or code files.
 ///
 /// # Arguments
 ///
@@ -38,32 +38,244 @@ use std::path::Path;
 ///
 /// # Notes
 ///
-/// - Currently, all document types are treated as PDF. This may change in future updates.
+/// - Extensions are matched case-insensitively.
+/// - Currently, all document types other than PDF are unsupported. This may change in future updates.
 /// - GIF files are currently not supported, as there's a TODO to implement GIF to video conversion.
-/// - All text types (including code files) are currently treated as plain text.
+/// - Source code without a dedicated MIME type is treated as plain text.
 pub fn get_mime_type(file_path: &Path) -> Option<String> {
-    match file_path.extension().and_then(|ext| ext.to_str()) {
+    let extension = file_path.extension()?.to_str()?.to_ascii_lowercase();
+    let mime_type = match extension.as_str() {
         //TODO: Convert all document types to PDF
-        Some("pdf") => Some("application/pdf".to_string()),
-
-        Some("png") => Some("image/png".to_string()),
-        Some("jpg") | Some("jpeg") => Some("image/jpeg".to_string()),
-
-        Some("mp3") => Some("audio/mpeg".to_string()),
-        Some("wav") => Some("audio/wav".to_string()),
-
-        Some("mp4") => Some("video/mp4".to_string()),
-        Some("mov") => Some("video/quicktime".to_string()),
-        Some("mpeg") => Some("video/mpeg".to_string()),
-        Some("mpg") => Some("video/mpeg".to_string()),
-        Some("avi") => Some("video/x-msvideo".to_string()),
-        Some("wmv") => Some("video/x-ms-wmv".to_string()),
-        Some("mpegps") => Some("video/mpeg".to_string()),
-        Some("flv") => Some("video/x-flv".to_string()),
-        // Some("gif") => Some("image/gif".to_string()), TODO: implement gif to video conversion
-
-        //TODO: Convert all text types (including codes) to plain text
-        Some("txt") => Some("text/plain".to_string()),
+        "pdf" => "application/pdf",
+
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "aiff" | "aif" => "audio/aiff",
+        "aac" => "audio/aac",
+        "ogg" | "oga" => "audio/ogg",
+        "flac" => "audio/flac",
+
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "mpeg" | "mpg" | "mpegps" => "video/mpeg",
+        "avi" => "video/x-msvideo",
+        "wmv" => "video/x-ms-wmv",
+        "flv" => "video/x-flv",
+        "webm" => "video/webm",
+        "3gp" | "3gpp" => "video/3gpp",
+        // "gif" => "image/gif", TODO: implement gif to video conversion
+        "txt" | "log" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "ts" => "text/x-typescript",
+        "py" => "text/x-python",
+        "json" => "application/json",
+        "xml" => "text/xml",
+        "rtf" => "text/rtf",
+        "rs" | "c" | "h" | "cpp" | "hpp" | "cc" | "java" | "kt" | "go" | "rb" | "php" | "swift"
+        | "cs" | "sh" | "sql" | "toml" | "yaml" | "yml" | "ini" => "text/plain",
+        _ => return None,
+    };
+    Some(mime_type.to_string())
+}
+
+/// Guesses the MIME type of a file from its first bytes, for files whose extension is
+/// missing or unknown.
+///
+/// Recognizes the binary formats supported by `get_mime_type` that have a signature, and
+/// falls back to `text/plain` when the first kilobyte is valid UTF-8 without NUL bytes.
+///
+/// # Examples
+///
+/// ```
+/// use gem_rs::utils::sniff_mime_type;
+///
+/// assert_eq!(sniff_mime_type(b"%PDF-1.7\n..."), Some("application/pdf"));
+/// assert_eq!(sniff_mime_type(b"\x89PNG\r\n\x1a\n..."), Some("image/png"));
+/// assert_eq!(sniff_mime_type(b"fn main() {}"), Some("text/plain"));
+/// assert_eq!(sniff_mime_type(&[0, 159, 146, 150]), None);
+/// assert_eq!(sniff_mime_type(b"\0\0\0\x18ftypisom"), Some("video/mp4"));
+/// assert_eq!(sniff_mime_type(b"\0\0\0\x18ftypcrx "), None);
+///
+/// // Only the first kilobyte is checked, even when it ends mid-character.
+/// let text = format!("{}é", "a".repeat(1023));
+/// assert_eq!(sniff_mime_type(text.as_bytes()), Some("text/plain"));
+/// ```
+pub fn sniff_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    let riff_format = bytes.get(8..12).filter(|_| bytes.starts_with(b"RIFF"));
+    let ftyp_brand = bytes
+        .get(8..12)
+        .filter(|_| bytes.get(4..8) == Some(b"ftyp"));
+
+    let mime_type = if bytes.starts_with(b"%PDF-") {
+        "application/pdf"
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if riff_format == Some(b"WEBP") {
+        "image/webp"
+    } else if riff_format == Some(b"WAVE") {
+        "audio/wav"
+    } else if riff_format == Some(b"AVI ") {
+        "video/x-msvideo"
+    } else if bytes.starts_with(b"FORM") && bytes.get(8..12) == Some(b"AIFF") {
+        "audio/aiff"
+    } else if bytes.starts_with(b"fLaC") {
+        "audio/flac"
+    } else if bytes.starts_with(b"OggS") {
+        "audio/ogg"
+    } else if bytes.starts_with(b"ID3") || bytes.starts_with(&[0xFF, 0xFB]) {
+        "audio/mpeg"
+    } else if bytes.starts_with(&[0xFF, 0xF1]) || bytes.starts_with(&[0xFF, 0xF9]) {
+        "audio/aac"
+    } else if bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        "video/webm"
+    } else if let Some(brand) = ftyp_brand {
+        match brand {
+            b"heic" | b"heix" => "image/heic",
+            b"mif1" | b"msf1" => "image/heif",
+            b"qt  " => "video/quicktime",
+            b"3gp4" | b"3gp5" | b"3gp6" => "video/3gpp",
+            b"isom" | b"iso2" | b"iso4" | b"iso5" | b"iso6" | b"mp41" | b"mp42" | b"avc1"
+            | b"dash" | b"M4V " => "video/mp4",
+            _ => return None,
+        }
+    } else if is_text(&bytes[..bytes.len().min(TEXT_SNIFF_LEN)]) {
+        "text/plain"
+    } else {
+        return None;
+    };
+    Some(mime_type)
+}
+
+/// Number of leading bytes `sniff_mime_type` checks when looking for text.
+const TEXT_SNIFF_LEN: usize = 1024;
+
+/// Returns `true` if `prefix` is UTF-8 without NUL bytes, allowing a character cut off at its end.
+fn is_text(prefix: &[u8]) -> bool {
+    if prefix.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(prefix) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Returns the file extension to use for a MIME type, the inverse of `get_mime_type`.
+///
+/// # Examples
+///
+/// ```
+/// use gem_rs::utils::get_extension;
+///
+/// assert_eq!(get_extension("image/png"), Some("png"));
+/// assert_eq!(get_extension("application/x-unknown"), None);
+/// ```
+pub fn get_extension(mime_type: &str) -> Option<&'static str> {
+    match mime_type {
+        "application/pdf" => Some("pdf"),
+
+        "image/png" => Some("png"),
+        "image/jpeg" => Some("jpg"),
+        "image/webp" => Some("webp"),
+        "image/heic" => Some("heic"),
+        "image/heif" => Some("heif"),
+
+        "audio/mpeg" => Some("mp3"),
+        "audio/wav" => Some("wav"),
+        "audio/aiff" => Some("aiff"),
+        "audio/aac" => Some("aac"),
+        "audio/ogg" => Some("ogg"),
+        "audio/flac" => Some("flac"),
+
+        "video/mp4" => Some("mp4"),
+        "video/quicktime" => Some("mov"),
+        "video/mpeg" => Some("mpeg"),
+        "video/webm" => Some("webm"),
+
+        "text/plain" => Some("txt"),
+        "text/markdown" => Some("md"),
+        "text/csv" => Some("csv"),
+        "text/html" => Some("html"),
+        "application/json" => Some("json"),
         _ => None,
     }
 }
+
+/// Extracts the fenced code blocks of a markdown text as `(language, code)` pairs.
+///
+/// Fences are three or more backticks or tildes. A block is closed by a fence of the same
+/// character that is at least as long as the opening one, so a block opened with four
+/// backticks can contain a three backtick fence. The language is the first word of the
+/// info string, if any. A block left open runs to the end of the text.
+///
+/// # Examples
+///
+/// ```
+/// use gem_rs::utils::extract_code_blocks;
+///
+/// let text = "Run this:\n```sh\ncargo test\n```\nThen this:\n```\nls\n```";
+/// assert_eq!(
+///     extract_code_blocks(text),
+///     vec![
+///         (Some("sh".to_string()), "cargo test".to_string()),
+///         (None, "ls".to_string()),
+///     ]
+/// );
+/// ```
+pub fn extract_code_blocks(text: &str) -> Vec<(Option<String>, String)> {
+    let mut blocks = Vec::new();
+    // The fence character and length of the open block, its language and its lines.
+    let mut open: Option<(char, usize, Option<String>, Vec<&str>)> = None;
+
+    for line in text.lines() {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let trimmed = line.trim_start_matches(' ');
+        let fence_char = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let fence_len = fence_char.map_or(0, |c| trimmed.chars().take_while(|x| *x == c).count());
+        let is_fence = indent <= 3 && fence_len >= 3;
+
+        match &mut open {
+            Some((open_char, open_len, language, lines)) => {
+                let rest = &trimmed[fence_len..];
+                if is_fence
+                    && fence_char == Some(*open_char)
+                    && fence_len >= *open_len
+                    && rest.trim().is_empty()
+                {
+                    blocks.push((language.take(), lines.join("\n")));
+                    open = None;
+                } else {
+                    lines.push(line);
+                }
+            }
+            None if is_fence => {
+                let fence_char = fence_char.unwrap();
+                let info = trimmed[fence_len..].trim();
+                // Backtick fences can't have backticks in their info string.
+                if fence_char == '`' && info.contains('`') {
+                    continue;
+                }
+                let language = info.split_whitespace().next().map(str::to_string);
+                open = Some((fence_char, fence_len, language, Vec::new()));
+            }
+            None => {}
+        }
+    }
+
+    if let Some((_, _, language, lines)) = open {
+        blocks.push((language, lines.join("\n")));
+    }
+    blocks
+}